@@ -0,0 +1,37 @@
+use calculator::{Environment, Expr, Number, Value};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const FORMULA: &str = "price * qty > 100 && region == 2 ? price * qty * 0.9 : sqrt(price) + qty ^ 2";
+
+// 每次都重新解析字符串后计算
+fn bench_eval(c: &mut Criterion) {
+    let mut env = Environment::new();
+    c.bench_function("eval", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i += 1;
+            env.set("price", Value::Num(Number::Int(i % 50))).unwrap();
+            env.set("qty", Value::Num(Number::Int(3))).unwrap();
+            env.set("region", Value::Num(Number::Int(2))).unwrap();
+            black_box(env.execute(black_box(FORMULA)).unwrap())
+        })
+    });
+}
+
+// 编译一次，每次绑定不同的变量值计算
+fn bench_compiled(c: &mut Criterion) {
+    let program = Expr::new(FORMULA).compile().unwrap();
+    assert_eq!(program.vars(), ["price", "qty", "region"]);
+    let mut stack = vec![];
+    c.bench_function("compiled", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i += 1;
+            let vars = [Value::Num(Number::Int(i % 50)), Value::Num(Number::Int(3)), Value::Num(Number::Int(2))];
+            black_box(program.eval(black_box(&vars), &mut stack).unwrap())
+        })
+    });
+}
+
+criterion_group!(benches, bench_eval, bench_compiled);
+criterion_main!(benches);
@@ -0,0 +1,543 @@
+use crate::{function, unit, Environment, ExprError, Number, Result, Unit, Value};
+use std::fmt::Display;
+
+// 左结合
+pub(crate) const ASSOC_LEFT: i32 = 0;
+// 右结合
+pub(crate) const ASSOC_RIGHT: i32 = 1;
+
+// 一元运算符
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Plus,   // 正号
+    Neg,    // 负号
+    Not,    // 逻辑非
+    BitNot, // 按位取反
+}
+
+// 二元运算符
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,    // 加
+    Sub,    // 减
+    Mul,    // 乘
+    Div,    // 除
+    Mod,    // 取余
+    Pow,    // 幂
+    Eq,     // 等于
+    Ne,     // 不等于
+    Lt,     // 小于
+    Le,     // 小于等于
+    Gt,     // 大于
+    Ge,     // 大于等于
+    And,    // 逻辑与
+    Or,     // 逻辑或
+    BitAnd, // 按位与
+    BitOr,  // 按位或
+    BitXor, // 按位异或
+    Shl,    // 左移
+    Shr,    // 右移
+}
+
+// 条件表达式 c ? a : b 的优先级最低，并且是右结合的
+pub(crate) const TERNARY_PRECEDENCE: i32 = 1;
+
+// 一元运算符的优先级介于乘除和幂运算之间，-2^2 = -(2^2)，-2*3 = (-2)*3
+pub(crate) const UNARY_PRECEDENCE: i32 = 12;
+
+impl BinOp {
+    // 获取运算符的优先级
+    pub(crate) fn precedence(&self) -> i32 {
+        match self {
+            BinOp::Or => 2,
+            BinOp::And => 3,
+            BinOp::Eq | BinOp::Ne => 4,
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 5,
+            // 位运算的优先级高于比较运算，a & 0xff == 0 = (a & 0xff) == 0
+            BinOp::BitOr => 6,
+            BinOp::BitXor => 7,
+            BinOp::BitAnd => 8,
+            BinOp::Shl | BinOp::Shr => 9,
+            BinOp::Add | BinOp::Sub => 10,
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 11,
+            BinOp::Pow => 13,
+        }
+    }
+
+    // 获取运算符的结合性
+    pub(crate) fn assoc(&self) -> i32 {
+        match self {
+            BinOp::Pow => ASSOC_RIGHT,
+            _ => ASSOC_LEFT,
+        }
+    }
+
+    // 根据当前运算符进行计算
+    // 1. 算术运算和大小比较只能用于数字
+    // 2. 相等比较的两边必须是同一种类型
+    // 3. 逻辑运算只能用于布尔值
+    // 4. 有一边带单位时按照单位计算
+    // 5. 位运算只能用于整数
+    pub fn computer(&self, l: Value, r: Value) -> Result<Value> {
+        if self.is_arithmetic() && (matches!(l, Value::Quantity(..)) || matches!(r, Value::Quantity(..))) {
+            return unit::computer(*self, l, r);
+        }
+        let int = |v: Value| v.as_number()?.as_int();
+        let value = match self {
+            BinOp::Add => l.as_number()?.checked_add(r.as_number()?)?.into(),
+            BinOp::Sub => l.as_number()?.checked_sub(r.as_number()?)?.into(),
+            BinOp::Mul => l.as_number()?.checked_mul(r.as_number()?)?.into(),
+            BinOp::Div => l.as_number()?.checked_div(r.as_number()?)?.into(),
+            BinOp::Mod => l.as_number()?.checked_rem(r.as_number()?)?.into(),
+            BinOp::Pow => l.as_number()?.checked_pow(r.as_number()?)?.into(),
+            BinOp::Eq | BinOp::Ne => {
+                let equal = match l {
+                    Value::Num(l) => l == r.as_number()?,
+                    Value::Bool(l) => l == r.as_bool()?,
+                    Value::Quantity(..) => unreachable!(),
+                };
+                Value::Bool(equal == (*self == BinOp::Eq))
+            }
+            BinOp::Lt => Value::Bool(l.as_number()?.as_f64() < r.as_number()?.as_f64()),
+            BinOp::Le => Value::Bool(l.as_number()?.as_f64() <= r.as_number()?.as_f64()),
+            BinOp::Gt => Value::Bool(l.as_number()?.as_f64() > r.as_number()?.as_f64()),
+            BinOp::Ge => Value::Bool(l.as_number()?.as_f64() >= r.as_number()?.as_f64()),
+            BinOp::And => Value::Bool(l.as_bool()? && r.as_bool()?),
+            BinOp::Or => Value::Bool(l.as_bool()? || r.as_bool()?),
+            BinOp::BitAnd => Number::Int(int(l)? & int(r)?).into(),
+            BinOp::BitOr => Number::Int(int(l)? | int(r)?).into(),
+            BinOp::BitXor => Number::Int(int(l)? ^ int(r)?).into(),
+            // 移位的位数必须在 0 到 63 之间，左移丢失了有效位时溢出
+            BinOp::Shl | BinOp::Shr => {
+                let (n, shift) = (int(l)?, int(r)?);
+                if !(0..64).contains(&shift) {
+                    return Err(ExprError::Overflow);
+                }
+                if *self == BinOp::Shr {
+                    return Ok(Number::Int(n >> shift).into());
+                }
+                let shifted = n << shift;
+                if shifted >> shift != n {
+                    return Err(ExprError::Overflow);
+                }
+                Number::Int(shifted).into()
+            }
+        };
+        Ok(value)
+    }
+
+    // 算术运算和比较运算，可以用于带单位的数字
+    fn is_arithmetic(&self) -> bool {
+        !matches!(
+            self,
+            BinOp::And | BinOp::Or | BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Shl | BinOp::Shr
+        )
+    }
+}
+
+impl UnaryOp {
+    pub fn computer(&self, operand: Value) -> Result<Value> {
+        match (self, operand) {
+            (UnaryOp::Plus, Value::Quantity(..)) => Ok(operand),
+            (UnaryOp::Neg, Value::Quantity(n, unit)) => Ok(Value::Quantity(n.checked_neg()?, unit)),
+            (UnaryOp::Plus, _) => Ok(operand.as_number()?.into()),
+            (UnaryOp::Neg, _) => Ok(operand.as_number()?.checked_neg()?.into()),
+            (UnaryOp::Not, _) => Ok((!operand.as_bool()?).into()),
+            (UnaryOp::BitNot, _) => Ok(Number::Int(!operand.as_number()?.as_int()?).into()),
+        }
+    }
+}
+
+impl Display for UnaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            UnaryOp::Plus => "+",
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+            UnaryOp::BitNot => "~",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            // 整数模式下才能解析为按位异或
+            BinOp::BitXor => "^",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+// 函数调用的最大层数
+const MAX_CALL_DEPTH: usize = 256;
+
+// 表达式的语法树
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ast {
+    Num(Number),
+    Bool(bool),
+    Var(String),
+    UnaryOp(UnaryOp, Box<Ast>),
+    BinOp(BinOp, Box<Ast>, Box<Ast>),
+    Call(String, Vec<Ast>),
+    // 条件表达式 c ? a : b
+    Cond(Box<Ast>, Box<Ast>, Box<Ast>),
+    // 带单位的数字 3 km
+    Quantity(Number, Unit),
+    // 单位换算 x in mm
+    Convert(Box<Ast>, Unit),
+}
+
+impl Ast {
+    // 在指定的环境中计算表达式的值
+    pub fn eval(&self, env: &Environment) -> Result<Value> {
+        self.eval_in(env, &[], 0)
+    }
+
+    // 计算表达式的值，locals 是当前函数调用的参数，depth 是函数调用的层数
+    fn eval_in(&self, env: &Environment, locals: &[(&str, Value)], depth: usize) -> Result<Value> {
+        match self {
+            // 整数模式下字面量和运算结果都限制在指定的位宽内
+            Ast::Num(n) => match env.int_mode() {
+                Some(mode) => mode.literal(*n),
+                None => Ok(Value::Num(*n)),
+            },
+            Ast::Bool(b) => Ok(Value::Bool(*b)),
+            Ast::Quantity(n, unit) => unit::quantity(*n, *unit),
+            Ast::Convert(operand, unit) => unit::convert(operand.eval_in(env, locals, depth)?, *unit),
+            // 函数的参数优先于全局变量
+            Ast::Var(name) => locals
+                .iter()
+                .find(|(local, _)| local == name)
+                .map(|(_, v)| *v)
+                .or_else(|| env.get(name))
+                .ok_or_else(|| ExprError::UnknownVariable(name.clone())),
+            Ast::UnaryOp(op, operand) => {
+                let operand = operand.eval_in(env, locals, depth)?;
+                match env.int_mode() {
+                    Some(mode) => mode.unary(*op, operand),
+                    None => op.computer(operand),
+                }
+            }
+            Ast::BinOp(op, lhs, rhs) => Ast::eval_binary(*op, lhs, rhs, env, locals, depth),
+            Ast::Cond(cond, then, otherwise) => {
+                if cond.eval_in(env, locals, depth)?.as_bool()? {
+                    then.eval_in(env, locals, depth)
+                } else {
+                    otherwise.eval_in(env, locals, depth)
+                }
+            }
+            Ast::Call(name, args) => Ast::eval_call(name, args, env, locals, depth),
+        }
+    }
+
+    // 二元运算和函数调用单独放在一个函数中，递归调用时每一层占用的栈空间更少
+    fn eval_binary(op: BinOp, lhs: &Ast, rhs: &Ast, env: &Environment, locals: &[(&str, Value)], depth: usize) -> Result<Value> {
+        // 逻辑运算短路求值，左边已经能确定结果时不计算右边
+        if matches!(op, BinOp::And | BinOp::Or) {
+            let l = lhs.eval_in(env, locals, depth)?.as_bool()?;
+            if l == (op == BinOp::Or) {
+                return Ok(Value::Bool(l));
+            }
+            return Ok(Value::Bool(rhs.eval_in(env, locals, depth)?.as_bool()?));
+        }
+        let (l, r) = (lhs.eval_in(env, locals, depth)?, rhs.eval_in(env, locals, depth)?);
+        match env.int_mode() {
+            Some(mode) => mode.computer(op, l, r),
+            None => op.computer(l, r),
+        }
+    }
+
+    fn eval_call(name: &str, args: &[Ast], env: &Environment, locals: &[(&str, Value)], depth: usize) -> Result<Value> {
+        let args = args
+            .iter()
+            .map(|arg| arg.eval_in(env, locals, depth))
+            .collect::<Result<Vec<_>>>()?;
+
+        // 先查找内置函数，再查找用户定义的函数
+        if let Some(builtin) = function::builtin(name) {
+            let args = args.iter().map(Value::as_number).collect::<Result<Vec<_>>>()?;
+            return builtin.call(&args).map(Value::Num);
+        }
+        let Some(func) = env.function(name) else {
+            return Err(ExprError::UnknownFunction(name.to_string()));
+        };
+        function::check_arity(name, func.params.len(), args.len())?;
+        if depth >= MAX_CALL_DEPTH {
+            return Err(ExprError::RecursionLimit);
+        }
+
+        // 函数体中只能看到自己的参数和全局变量
+        let locals: Vec<(&str, Value)> = func.params.iter().map(String::as_str).zip(args).collect();
+        func.body.eval_in(env, &locals, depth + 1)
+    }
+
+    // 常量对应的值
+    fn constant(&self) -> Option<Value> {
+        match self {
+            Ast::Num(n) => Some(Value::Num(*n)),
+            Ast::Bool(b) => Some(Value::Bool(*b)),
+            Ast::Quantity(n, unit) => unit::quantity(*n, *unit).ok(),
+            _ => None,
+        }
+    }
+
+    // 常量折叠，计算出所有只包含常量的子表达式
+    // 计算出错的子表达式保持原样，求值时再报告错误
+    pub fn simplify(&self) -> Ast {
+        match self {
+            Ast::Num(_) | Ast::Bool(_) | Ast::Var(_) => self.clone(),
+            Ast::Quantity(..) => self.constant().map_or_else(|| self.clone(), Ast::from),
+            Ast::Convert(operand, unit) => {
+                let operand = operand.simplify();
+                if let Some(value) = operand.constant() {
+                    if let Ok(value) = unit::convert(value, *unit) {
+                        return value.into();
+                    }
+                }
+                Ast::Convert(Box::new(operand), *unit)
+            }
+            Ast::UnaryOp(op, operand) => {
+                let operand = operand.simplify();
+                if let Some(value) = operand.constant() {
+                    if let Ok(value) = op.computer(value) {
+                        return value.into();
+                    }
+                }
+                match (op, operand) {
+                    (UnaryOp::Plus, operand) => operand,
+                    (op, operand) => Ast::UnaryOp(*op, Box::new(operand)),
+                }
+            }
+            Ast::BinOp(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.simplify(), rhs.simplify());
+                if let (Some(l), Some(r)) = (lhs.constant(), rhs.constant()) {
+                    if let Ok(value) = op.computer(l, r) {
+                        return value.into();
+                    }
+                }
+                Ast::BinOp(*op, Box::new(lhs), Box::new(rhs))
+            }
+            // 条件是常量时只保留一个分支
+            Ast::Cond(cond, then, otherwise) => match cond.simplify() {
+                Ast::Bool(true) => then.simplify(),
+                Ast::Bool(false) => otherwise.simplify(),
+                cond => Ast::Cond(Box::new(cond), Box::new(then.simplify()), Box::new(otherwise.simplify())),
+            },
+            // 参数都是常量的内置函数调用也可以计算出来
+            Ast::Call(name, args) => {
+                let args: Vec<Ast> = args.iter().map(Ast::simplify).collect();
+                let consts: Option<Vec<Number>> = args
+                    .iter()
+                    .map(|arg| match arg {
+                        Ast::Num(n) => Some(*n),
+                        _ => None,
+                    })
+                    .collect();
+                if let (Some(builtin), Some(consts)) = (function::builtin(name), consts) {
+                    if let Ok(n) = builtin.call(&consts) {
+                        return Ast::Num(n);
+                    }
+                }
+                Ast::Call(name.clone(), args)
+            }
+        }
+    }
+
+    // 作为运算符的操作数时的优先级，负数和一元运算一样
+    // 带单位的数字作为幂运算的底数时需要括号，(2 m) ^ 2 和 2 m^2 不同
+    fn precedence(&self) -> i32 {
+        match self {
+            Ast::BinOp(op, _, _) => op.precedence(),
+            Ast::UnaryOp(..) | Ast::Quantity(..) => UNARY_PRECEDENCE,
+            Ast::Num(n) if n.as_f64().is_sign_negative() => UNARY_PRECEDENCE,
+            Ast::Cond(..) => TERNARY_PRECEDENCE,
+            Ast::Convert(..) => TERNARY_PRECEDENCE - 1,
+            _ => i32::MAX,
+        }
+    }
+
+    // 输出操作数，优先级比运算符低时需要加上括号
+    fn fmt_operand(&self, f: &mut std::fmt::Formatter<'_>, paren: bool) -> std::fmt::Result {
+        if paren {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+impl From<Value> for Ast {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Num(n) => Ast::Num(n),
+            Value::Bool(b) => Ast::Bool(b),
+            Value::Quantity(n, unit) => Ast::Quantity(n, unit),
+        }
+    }
+}
+
+// 输出表达式，只添加必要的括号，输出的结果重新解析后得到同样的语法树
+impl Display for Ast {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // 浮点数使用 Debug 格式输出，保留小数点，避免重新解析后变成整数
+            Ast::Num(Number::Int(n)) => write!(f, "{}", n),
+            Ast::Num(Number::Float(n)) => write!(f, "{:?}", n),
+            Ast::Quantity(n, unit) => write!(f, "{} {}", Ast::Num(*n), unit),
+            // 换算的部分是条件表达式或者换算时需要括号
+            Ast::Convert(operand, unit) => {
+                operand.fmt_operand(f, operand.precedence() <= TERNARY_PRECEDENCE)?;
+                write!(f, " in {}", unit)
+            }
+            Ast::Bool(b) => write!(f, "{}", b),
+            Ast::Var(name) => write!(f, "{}", name),
+            Ast::UnaryOp(op, operand) => {
+                write!(f, "{}", op)?;
+                operand.fmt_operand(f, operand.precedence() < UNARY_PRECEDENCE)
+            }
+            Ast::BinOp(op, lhs, rhs) => {
+                // 左结合的运算符，右边优先级相同时需要括号，例如 1 - (2 - 3)
+                // 右结合的运算符，左边优先级相同时需要括号，例如 (2 ^ 3) ^ 2
+                let prec = op.precedence();
+                let (lhs_paren, rhs_paren) = match op.assoc() {
+                    ASSOC_LEFT => (lhs.precedence() < prec, rhs.precedence() <= prec),
+                    _ => (lhs.precedence() <= prec, rhs.precedence() < prec),
+                };
+                lhs.fmt_operand(f, lhs_paren)?;
+                write!(f, " {} ", op)?;
+                // 一元运算可以直接出现在右边，例如 2 * -3、2 ^ -1
+                rhs.fmt_operand(f, rhs_paren && rhs.precedence() != UNARY_PRECEDENCE)
+            }
+            // 条件部分是条件表达式时需要括号，两个分支不需要
+            Ast::Cond(cond, then, otherwise) => {
+                cond.fmt_operand(f, cond.precedence() <= TERNARY_PRECEDENCE)?;
+                write!(f, " ? {} : {}", then, otherwise)
+            }
+            Ast::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn parse(input: &str) -> Ast {
+        Expr::new(input).parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let num = |n| Box::new(Ast::Num(Number::Int(n)));
+        assert_eq!(
+            parse("-x ^ 2 * f(1, y)"),
+            Ast::BinOp(
+                BinOp::Mul,
+                Box::new(Ast::UnaryOp(
+                    UnaryOp::Neg,
+                    Box::new(Ast::BinOp(BinOp::Pow, Box::new(Ast::Var("x".into())), num(2)))
+                )),
+                Box::new(Ast::Call("f".into(), vec![Ast::Num(Number::Int(1)), Ast::Var("y".into())]))
+            )
+        );
+        assert_eq!(parse("g()"), Ast::Call("g".into(), vec![]));
+        assert_eq!(parse("x").eval(&Environment::new()), Err(ExprError::UnknownVariable("x".into())));
+        assert_eq!(parse("f(1)").eval(&Environment::new()), Err(ExprError::UnknownFunction("f".into())));
+    }
+
+    #[test]
+    fn test_pretty_print() {
+        for (input, output) in [
+            ("((1 + 2)) * 3", "(1 + 2) * 3"),
+            ("1 + (2 * 3)", "1 + 2 * 3"),
+            ("(1 + 2) + 3", "1 + 2 + 3"),
+            ("1 + (2 + 3)", "1 + (2 + 3)"),
+            ("1 - (2 - 3)", "1 - (2 - 3)"),
+            ("(2 ^ 3) ^ 2", "(2 ^ 3) ^ 2"),
+            ("2 ^ (3 ^ 2)", "2 ^ 3 ^ 2"),
+            ("-(2 ^ 2)", "-2 ^ 2"),
+            ("(-2) ^ 2", "(-2) ^ 2"),
+            ("-(1 + x)", "-(1 + x)"),
+            ("(-2) * 3", "-2 * 3"),
+            ("2 * (-3)", "2 * -3"),
+            ("2 ^ (-1)", "2 ^ -1"),
+            ("--x", "--x"),
+            ("f((1 + 2), x*y)", "f(1 + 2, x * y)"),
+            ("2.0 + 1e3 % .5", "2.0 + 1000.0 % 0.5"),
+            ("a + 1 > b * 2 == (c <= d)", "a + 1 > b * 2 == c <= d"),
+            ("(a || b) && !(c && d)", "(a || b) && !(c && d)"),
+            ("a || (b && c)", "a || b && c"),
+            ("!(a == b)", "!(a == b)"),
+            ("a ? b : (c ? d : e)", "a ? b : c ? d : e"),
+            ("(a ? b : c) ? d : e", "(a ? b : c) ? d : e"),
+            ("a ? (b ? c : d) : e", "a ? b ? c : d : e"),
+            ("1 + (a ? 2 : 3)", "1 + (a ? 2 : 3)"),
+            ("f(a ? 1 : 2, true)", "f(a ? 1 : 2, true)"),
+            ("(2 m) ^ 2 + 3 m^2", "(2 m) ^ 2 + 3 m^2"),
+            ("x * (9.81 m/s^2)", "x * 9.81 m/s^2"),
+            ("(a + 1 km) in m", "a + 1 km in m"),
+            ("(a ? 1 s : 2 s) in ms", "(a ? 1 s : 2 s) in ms"),
+            ("(x in km) * 2", "(x in km) * 2"),
+            ("(a & b) | c << 2", "a & b | c << 2"),
+            ("a & (b | c) == 0", "a & (b | c) == 0"),
+            ("~(a | b) + ~-1", "~(a | b) + ~-1"),
+        ] {
+            let ast = parse(input);
+            assert_eq!(ast.to_string(), output);
+            assert_eq!(parse(output), ast, "{} does not round-trip", output);
+        }
+    }
+
+    #[test]
+    fn test_simplify() {
+        for (input, output) in [
+            ("1 + 2 * 3", "7"),
+            ("x * (2 + 3) - -1", "x * 5 - -1"),
+            ("f(2 ^ 10, +x)", "f(1024, x)"),
+            ("(1 - 3) ^ 2 + x", "4 + x"),
+            ("x ^ (1 - 3)", "x ^ -2"),
+            ("(0 - 2) ^ x", "(-2) ^ x"),
+            ("x + 1 / 0", "x + 1 / 0"),
+            ("7 / 2 + x", "3.5 + x"),
+            ("1 < 2 && x", "true && x"),
+            ("2 > 3 ? x : y + 1 * 2", "y + 2"),
+            ("!(1 == 1) || x", "false || x"),
+            ("x + 2 * 3 km in m", "x + 6 km in m"),
+            ("1 h + 30 min in min", "90.0 min"),
+        ] {
+            let simplified = parse(input).simplify();
+            assert_eq!(simplified.to_string(), output);
+            assert_eq!(parse(output).simplify(), simplified, "{} does not round-trip", output);
+        }
+    }
+}
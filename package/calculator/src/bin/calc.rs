@@ -0,0 +1,95 @@
+use calculator::{Environment, Expr, IntMode, Overflow, Radix, Width};
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+fn main() -> rustyline::Result<()> {
+    println!("Commands:\n\
+              EXPR            evaluate an expression, e.g. 3 * (4 + 5)\n\
+              NAME = EXPR     assign a variable, e.g. x = 3 * 4\n\
+              NAME(ARGS) = EXPR\n\
+                              define a function, e.g. f(x) = x ^ 2 + 1\n\
+              EXPR in UNIT    convert to a unit, e.g. 3 km + 200 m in mm\n\
+              ans             the last result\n\
+              :diff VAR EXPR  differentiate, e.g. :diff x x ^ 2 * sin(x)\n\
+              :mode float|u8|u16|u32|u64 [wrap]\n\
+                              switch to integer mode, where ^ is xor and ** is power\n\
+              :radix 2|8|10|16\n\
+                              print integers in the given radix\n\
+              :vars           list variables and functions\n\
+              :clear          clear variables and functions\n\
+              :history        list input history\n\
+              Type Control-D (on Unix) or Control-Z (on Windows) to exit.");
+
+    let mut editor = DefaultEditor::new()?;
+    let mut env = Environment::new();
+    loop {
+        let line = match editor.readline("calc> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        match line {
+            ":vars" => {
+                for (name, value) in env.vars() {
+                    println!("{} = {}", name, env.format(value));
+                }
+                for (name, params, body) in env.functions() {
+                    println!("{}({}) = {}", name, params.join(", "), body);
+                }
+            }
+            ":clear" => env.clear(),
+            ":history" => {
+                for (i, entry) in editor.history().iter().enumerate() {
+                    println!("{:>4}  {}", i + 1, entry);
+                }
+            }
+            _ if line.starts_with(":mode ") => {
+                let mut args = line[":mode ".len()..].split_whitespace();
+                let width = match args.next() {
+                    Some("float") => None,
+                    Some("u8") => Some(Width::U8),
+                    Some("u16") => Some(Width::U16),
+                    Some("u32") => Some(Width::U32),
+                    Some("u64") => Some(Width::U64),
+                    _ => {
+                        println!("Usage: :mode float|u8|u16|u32|u64 [wrap]");
+                        continue;
+                    }
+                };
+                let overflow = match args.next() {
+                    Some("wrap") => Overflow::Wrap,
+                    _ => Overflow::Error,
+                };
+                env.set_int_mode(width.map(|width| IntMode { width, overflow }));
+            }
+            _ if line.starts_with(":radix ") => match line[":radix ".len()..].trim() {
+                "2" => env.set_radix(Radix::Bin),
+                "8" => env.set_radix(Radix::Oct),
+                "10" => env.set_radix(Radix::Dec),
+                "16" => env.set_radix(Radix::Hex),
+                _ => println!("Usage: :radix 2|8|10|16"),
+            },
+            _ if line.starts_with(":diff ") => {
+                let Some((var, expr)) = line[":diff ".len()..].trim().split_once(' ') else {
+                    println!("Usage: :diff VAR EXPR");
+                    continue;
+                };
+                match Expr::new(expr).parse().and_then(|ast| ast.derive(var)) {
+                    Ok(ast) => println!("{}", ast),
+                    Err(e) => println!("{}", e.diagnostic(expr)),
+                }
+            }
+            _ => match env.execute(line) {
+                Ok(Some(value)) => println!("{}", env.format(value)),
+                Ok(None) => {}
+                Err(e) => println!("{}", e.diagnostic(line)),
+            },
+        }
+    }
+}
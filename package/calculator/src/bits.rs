@@ -0,0 +1,273 @@
+use crate::{BinOp, ExprError, Number, Result, UnaryOp, Value};
+
+// 整数模式的位宽
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl Width {
+    pub fn bits(&self) -> u32 {
+        match self {
+            Width::U8 => 8,
+            Width::U16 => 16,
+            Width::U32 => 32,
+            Width::U64 => 64,
+        }
+    }
+
+    fn mask(&self) -> u128 {
+        (1 << self.bits()) - 1
+    }
+}
+
+// 运算结果超出位宽时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    // 只保留低位，和硬件寄存器的行为一致
+    Wrap,
+    // 返回溢出错误
+    Error,
+}
+
+// 整数模式，所有的值都是指定位宽的无符号整数
+// 除法是整除，^ 是按位异或，幂运算使用 **
+// 值仍然保存为 i64，u64 超出 i64 范围的值保存为相同的二进制位
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntMode {
+    pub width: Width,
+    pub overflow: Overflow,
+}
+
+impl IntMode {
+    // 转换为当前位宽的无符号整数，负数按照补码处理
+    fn operand(&self, value: Value) -> Result<u128> {
+        let n = value.as_number()?.as_int()? as u64 as u128;
+        self.check(n, n > self.width.mask())
+    }
+
+    // 超出位宽时回绕或者返回错误
+    fn check(&self, n: u128, overflow: bool) -> Result<u128> {
+        if overflow && self.overflow == Overflow::Error {
+            return Err(ExprError::Overflow);
+        }
+        Ok(n & self.width.mask())
+    }
+
+    fn value(n: u128) -> Value {
+        Value::Num(Number::Int(n as u64 as i64))
+    }
+
+    // 转换为当前位宽的整数值
+    pub fn fit(&self, value: Value) -> Result<Value> {
+        self.operand(value).map(Self::value)
+    }
+
+    // 表达式中的数字
+    pub(crate) fn literal(&self, n: Number) -> Result<Value> {
+        self.fit(Value::Num(n))
+    }
+
+    pub(crate) fn unary(&self, op: UnaryOp, operand: Value) -> Result<Value> {
+        match op {
+            UnaryOp::Not => op.computer(operand),
+            UnaryOp::Plus => self.fit(operand),
+            // 无符号整数取负数，只有 0 不会溢出
+            UnaryOp::Neg => {
+                let n = self.operand(operand)?;
+                self.check(n.wrapping_neg(), n != 0).map(Self::value)
+            }
+            UnaryOp::BitNot => Ok(Self::value(!self.operand(operand)? & self.width.mask())),
+        }
+    }
+
+    // 整数模式下的二元运算，逻辑运算和布尔值的比较与普通模式相同
+    pub(crate) fn computer(&self, op: BinOp, l: Value, r: Value) -> Result<Value> {
+        if matches!(op, BinOp::And | BinOp::Or) || matches!(l, Value::Bool(_)) || matches!(r, Value::Bool(_)) {
+            return op.computer(l, r);
+        }
+
+        // 操作数都小于 2^64，加法和乘法的结果不会超出 u128
+        let (a, b) = (self.operand(l)?, self.operand(r)?);
+        let (mask, bits) = (self.width.mask(), u128::from(self.width.bits()));
+        let (n, overflow) = match op {
+            BinOp::Add => (a + b, a + b > mask),
+            BinOp::Sub => (a.wrapping_sub(b), a < b),
+            BinOp::Mul => (a * b, a * b > mask),
+            BinOp::Div | BinOp::Mod if b == 0 => return Err(ExprError::DivisionByZero),
+            BinOp::Div => (a / b, false),
+            BinOp::Mod => (a % b, false),
+            BinOp::Pow => self.pow(a, b),
+            BinOp::Shl if b >= bits => (0, a != 0),
+            BinOp::Shl => (a << b, a << b > mask),
+            BinOp::Shr if b >= bits => (0, false),
+            BinOp::Shr => (a >> b, false),
+            BinOp::BitAnd => (a & b, false),
+            BinOp::BitOr => (a | b, false),
+            BinOp::BitXor => (a ^ b, false),
+            BinOp::Eq => return Ok(Value::Bool(a == b)),
+            BinOp::Ne => return Ok(Value::Bool(a != b)),
+            BinOp::Lt => return Ok(Value::Bool(a < b)),
+            BinOp::Le => return Ok(Value::Bool(a <= b)),
+            BinOp::Gt => return Ok(Value::Bool(a > b)),
+            BinOp::Ge => return Ok(Value::Bool(a >= b)),
+            BinOp::And | BinOp::Or => unreachable!(),
+        };
+        self.check(n, overflow).map(Self::value)
+    }
+
+    // 快速幂，每一步都只保留低位，同时记录真实的结果是否超出位宽
+    fn pow(&self, mut base: u128, mut exp: u128) -> (u128, bool) {
+        let mask = self.width.mask();
+        let (mut n, mut overflow) = (1, false);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                n *= base;
+                overflow |= n > mask;
+                n &= mask;
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base *= base;
+                overflow |= base > mask;
+                base &= mask;
+            }
+        }
+        (n, overflow)
+    }
+}
+
+// 输出整数使用的进制
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Radix {
+    Bin,
+    Oct,
+    #[default]
+    Dec,
+    Hex,
+}
+
+impl Radix {
+    // 输出整数，二进制、八进制、十六进制带有和字面量相同的前缀，例如 -0xff
+    pub(crate) fn format(&self, negative: bool, n: u64) -> String {
+        let sign = if negative { "-" } else { "" };
+        match self {
+            Radix::Bin => format!("{}0b{:b}", sign, n),
+            Radix::Oct => format!("{}0o{:o}", sign, n),
+            Radix::Dec => format!("{}{}", sign, n),
+            Radix::Hex => format!("{}0x{:x}", sign, n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn eval(env: &mut Environment, input: &str) -> String {
+        match env.execute(input) {
+            Ok(value) => env.format(value.unwrap()),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_literals_and_operators() {
+        let mut env = Environment::new();
+        for (input, output) in [
+            ("0xff + 0b1010 + 0o17", "280"),
+            ("0xFFFF_FFFF", "4294967295"),
+            ("0xffffffffffffffff", "18446744073709552000"),
+            ("0x", "Invalid number '0x' at position 0"),
+            ("0b102", "Invalid number '0b102' at position 0"),
+            ("0xf0 & 0x3c | 0x01", "49"),
+            ("~0", "-1"),
+            ("1 << 10 >> 2", "256"),
+            ("1 << 64", "Arithmetic overflow"),
+            ("2 ** 10", "1024"),
+            ("2 ^ 10", "1024"),
+            ("0xff & 0x0f == 0x0f", "true"),
+            ("1 + 2 << 3", "24"),
+            ("sqrt(16) | 1", "5"),
+            ("1.5 & 1", "Expected an integer, found a number"),
+        ] {
+            assert_eq!(eval(&mut env, input), output, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_int_mode() {
+        let mut env = Environment::new();
+        env.set_int_mode(Some(IntMode {
+            width: Width::U8,
+            overflow: Overflow::Wrap,
+        }));
+        for (input, output) in [
+            ("0xf0 ^ 0xff", "15"),
+            ("2 ** 10", "0"),
+            ("3 ** 5", "243"),
+            ("200 + 100", "44"),
+            ("0 - 1", "255"),
+            ("-1", "255"),
+            ("~0x0f", "240"),
+            ("7 / 2", "3"),
+            ("0x81 << 1", "2"),
+            ("0xff > 1", "true"),
+            ("0x1ff", "255"),
+        ] {
+            assert_eq!(eval(&mut env, input), output, "{}", input);
+        }
+
+        env.set_int_mode(Some(IntMode {
+            width: Width::U16,
+            overflow: Overflow::Error,
+        }));
+        for (input, output) in [
+            ("0xffff + 1", "Arithmetic overflow"),
+            ("0 - 1", "Arithmetic overflow"),
+            ("0x8000 << 1", "Arithmetic overflow"),
+            ("2 ** 16", "Arithmetic overflow"),
+            ("2 ** 15", "32768"),
+            ("0x10000", "Arithmetic overflow"),
+            ("1 / 0", "Division by zero"),
+            ("1 / 0 > 0 || true", "Division by zero"),
+            ("x = 0xabcd & 0xff00", "43776"),
+            ("x >> 8 == 0xab", "true"),
+            ("0.5", "Expected an integer, found a number"),
+        ] {
+            assert_eq!(eval(&mut env, input), output, "{}", input);
+        }
+
+        env.set_int_mode(Some(IntMode {
+            width: Width::U64,
+            overflow: Overflow::Wrap,
+        }));
+        assert_eq!(eval(&mut env, "0xffffffffffffffff + 2"), "1");
+        assert_eq!(eval(&mut env, "0 - 1"), "18446744073709551615");
+        assert_eq!(eval(&mut env, "0x8000000000000000 > 1"), "true");
+    }
+
+    #[test]
+    fn test_radix() {
+        let mut env = Environment::new();
+        env.set_radix(Radix::Hex);
+        assert_eq!(eval(&mut env, "255"), "0xff");
+        assert_eq!(eval(&mut env, "-255"), "-0xff");
+        assert_eq!(eval(&mut env, "1.5"), "1.5");
+        env.set_radix(Radix::Bin);
+        assert_eq!(eval(&mut env, "0o17"), "0b1111");
+
+        // 整数模式下按照位宽输出无符号整数
+        env.set_int_mode(Some(IntMode {
+            width: Width::U32,
+            overflow: Overflow::Wrap,
+        }));
+        env.set_radix(Radix::Hex);
+        assert_eq!(eval(&mut env, "-1"), "0xffffffff");
+        env.set_radix(Radix::Oct);
+        assert_eq!(eval(&mut env, "8 ** 3"), "0o1000");
+    }
+}
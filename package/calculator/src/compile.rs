@@ -0,0 +1,356 @@
+use crate::{
+    env::CONSTANTS,
+    function::{self, Builtin},
+    unit, Ast, BinOp, ExprError, IntMode, Number, Result, UnaryOp, Unit, Value,
+};
+
+// 内置函数最多的参数个数，调用时参数放在栈上的数组中，不需要分配内存
+const MAX_ARITY: usize = 2;
+
+// 栈式虚拟机的指令
+#[derive(Debug, Clone, Copy)]
+enum Instr {
+    // 常量入栈
+    Const(Value),
+    // 变量入栈，参数是变量在变量列表中的位置
+    Load(usize),
+    // 一元运算，弹出一个值，结果入栈
+    Unary(UnaryOp),
+    // 二元运算，弹出两个值，结果入栈
+    Binary(BinOp),
+    // 调用内置函数，弹出所有参数，结果入栈
+    Call(&'static Builtin),
+    // 检查栈顶是不是布尔值
+    AssertBool,
+    // 弹出栈顶的布尔值，为 false 时跳转
+    JumpIfFalse(usize),
+    // 无条件跳转
+    Jump(usize),
+    // 把栈顶的值换算到指定的单位
+    Convert(Unit),
+}
+
+// 编译后的表达式，可以绑定不同的变量值重复计算
+#[derive(Debug)]
+pub struct Program {
+    code: Vec<Instr>,
+    // 表达式中用到的变量，按照第一次出现的顺序
+    vars: Vec<String>,
+    // 计算时需要的栈的最大深度
+    max_stack: usize,
+    // 整数模式，和 Environment 一样限制运算结果的位宽
+    int_mode: Option<IntMode>,
+}
+
+impl Program {
+    // 把语法树编译为指令序列
+    // 内置常量编译为常量，内置函数的参数个数在编译时检查，不支持用户定义的函数
+    // 整数模式下字面量在编译时转换为指定位宽的值
+    pub fn compile(ast: &Ast, int_mode: Option<IntMode>) -> Result<Program> {
+        let mut compiler = Compiler {
+            int_mode,
+            ..Compiler::default()
+        };
+        compiler.compile(ast)?;
+        Ok(Program {
+            code: compiler.code,
+            vars: compiler.vars,
+            max_stack: compiler.max_depth,
+            int_mode,
+        })
+    }
+
+    // 变量列表，计算时按照这个顺序传入变量的值
+    pub fn vars(&self) -> &[String] {
+        &self.vars
+    }
+
+    // 使用给定的变量值计算表达式，stack 用于保存中间结果，可以在多次计算之间复用
+    // 复用 stack 时计算过程不会分配内存
+    pub fn eval(&self, vars: &[Value], stack: &mut Vec<Value>) -> Result<Value> {
+        if vars.len() < self.vars.len() {
+            return Err(ExprError::UnknownVariable(self.vars[vars.len()].clone()));
+        }
+        stack.clear();
+        stack.reserve(self.max_stack);
+
+        let mut pc = 0;
+        while let Some(instr) = self.code.get(pc) {
+            pc += 1;
+            match *instr {
+                Instr::Const(value) => stack.push(value),
+                Instr::Load(slot) => stack.push(vars[slot]),
+                Instr::Unary(op) => {
+                    let operand = stack.pop().unwrap();
+                    stack.push(match self.int_mode {
+                        Some(mode) => mode.unary(op, operand)?,
+                        None => op.computer(operand)?,
+                    });
+                }
+                Instr::Binary(op) => {
+                    let r = stack.pop().unwrap();
+                    let l = stack.pop().unwrap();
+                    stack.push(match self.int_mode {
+                        Some(mode) => mode.computer(op, l, r)?,
+                        None => op.computer(l, r)?,
+                    });
+                }
+                Instr::Call(builtin) => {
+                    let mut args = [Number::Int(0); MAX_ARITY];
+                    let base = stack.len() - builtin.arity;
+                    for (arg, value) in args.iter_mut().zip(stack.drain(base..)) {
+                        *arg = value.as_number()?;
+                    }
+                    stack.push(Value::Num(builtin.call(&args[..builtin.arity])?));
+                }
+                Instr::AssertBool => {
+                    stack.last().unwrap().as_bool()?;
+                }
+                Instr::JumpIfFalse(target) => {
+                    if !stack.pop().unwrap().as_bool()? {
+                        pc = target;
+                    }
+                }
+                Instr::Jump(target) => pc = target,
+                Instr::Convert(unit) => {
+                    let value = stack.pop().unwrap();
+                    stack.push(unit::convert(value, unit)?);
+                }
+            }
+        }
+        Ok(stack.pop().unwrap())
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    code: Vec<Instr>,
+    vars: Vec<String>,
+    // 当前栈的深度和最大深度
+    depth: usize,
+    max_depth: usize,
+    int_mode: Option<IntMode>,
+}
+
+impl Compiler {
+    // 生成一条指令，记录栈深度的变化
+    fn emit(&mut self, instr: Instr) -> usize {
+        match instr {
+            Instr::Const(_) | Instr::Load(_) => self.depth += 1,
+            Instr::Binary(_) | Instr::JumpIfFalse(_) => self.depth -= 1,
+            Instr::Call(builtin) => self.depth = self.depth + 1 - builtin.arity,
+            Instr::Unary(_) | Instr::AssertBool | Instr::Jump(_) | Instr::Convert(_) => {}
+        }
+        self.max_depth = self.max_depth.max(self.depth);
+        self.code.push(instr);
+        self.code.len() - 1
+    }
+
+    // 回填跳转指令的目标为当前位置
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len();
+        match &mut self.code[at] {
+            Instr::JumpIfFalse(t) | Instr::Jump(t) => *t = target,
+            _ => unreachable!(),
+        }
+    }
+
+    // 条件表达式 c ? a : b，两个分支只有一个会执行，结束时栈深度只增加 1
+    fn compile_cond(
+        &mut self,
+        cond: &Ast,
+        then: impl FnOnce(&mut Self) -> Result<()>,
+        otherwise: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        self.compile(cond)?;
+        let jump_if_false = self.emit(Instr::JumpIfFalse(0));
+        then(self)?;
+        let jump = self.emit(Instr::Jump(0));
+        self.patch(jump_if_false);
+        self.depth -= 1;
+        otherwise(self)?;
+        self.patch(jump);
+        Ok(())
+    }
+
+    fn compile(&mut self, ast: &Ast) -> Result<()> {
+        match ast {
+            Ast::Num(n) => {
+                let value = match self.int_mode {
+                    Some(mode) => mode.literal(*n)?,
+                    None => Value::Num(*n),
+                };
+                self.emit(Instr::Const(value));
+            }
+            Ast::Bool(b) => {
+                self.emit(Instr::Const(Value::Bool(*b)));
+            }
+            Ast::Quantity(n, unit) => {
+                self.emit(Instr::Const(unit::quantity(*n, *unit)?));
+            }
+            Ast::Convert(operand, unit) => {
+                self.compile(operand)?;
+                self.emit(Instr::Convert(*unit));
+            }
+            Ast::Var(name) => {
+                if let Some((_, n)) = CONSTANTS.iter().find(|(c, _)| c == name) {
+                    self.emit(Instr::Const(Value::Num(Number::Float(*n))));
+                    return Ok(());
+                }
+                let slot = match self.vars.iter().position(|v| v == name) {
+                    Some(slot) => slot,
+                    None => {
+                        self.vars.push(name.clone());
+                        self.vars.len() - 1
+                    }
+                };
+                self.emit(Instr::Load(slot));
+            }
+            Ast::UnaryOp(op, operand) => {
+                self.compile(operand)?;
+                self.emit(Instr::Unary(*op));
+            }
+            // 逻辑运算短路求值
+            // a && b 等价于 a ? b : false，a || b 等价于 a ? true : b，并且 b 必须是布尔值
+            Ast::BinOp(BinOp::And, lhs, rhs) => {
+                let rhs = |c: &mut Self| {
+                    c.compile(rhs)?;
+                    c.emit(Instr::AssertBool);
+                    Ok(())
+                };
+                let constant = |c: &mut Self| {
+                    c.emit(Instr::Const(Value::Bool(false)));
+                    Ok(())
+                };
+                self.compile_cond(lhs, rhs, constant)?;
+            }
+            Ast::BinOp(BinOp::Or, lhs, rhs) => {
+                let rhs = |c: &mut Self| {
+                    c.compile(rhs)?;
+                    c.emit(Instr::AssertBool);
+                    Ok(())
+                };
+                let constant = |c: &mut Self| {
+                    c.emit(Instr::Const(Value::Bool(true)));
+                    Ok(())
+                };
+                self.compile_cond(lhs, constant, rhs)?;
+            }
+            Ast::BinOp(op, lhs, rhs) => {
+                self.compile(lhs)?;
+                self.compile(rhs)?;
+                self.emit(Instr::Binary(*op));
+            }
+            Ast::Cond(cond, then, otherwise) => {
+                self.compile_cond(cond, |c| c.compile(then), |c| c.compile(otherwise))?;
+            }
+            Ast::Call(name, args) => {
+                let builtin = function::builtin(name).ok_or_else(|| ExprError::UnknownFunction(name.clone()))?;
+                function::check_arity(name, builtin.arity, args.len())?;
+                for arg in args {
+                    self.compile(arg)?;
+                }
+                self.emit(Instr::Call(builtin));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn run(input: &str, vars: &[(&str, Value)]) -> Result<Value> {
+        run_in(None, input, vars)
+    }
+
+    fn run_in(mode: Option<IntMode>, input: &str, vars: &[(&str, Value)]) -> Result<Value> {
+        let program = Expr::new(input).int_mode(mode).compile()?;
+        let values: Vec<Value> = program
+            .vars()
+            .iter()
+            .map(|name| vars.iter().find(|(n, _)| n == name).unwrap().1)
+            .collect();
+        program.eval(&values, &mut vec![])
+    }
+
+    #[test]
+    fn test_compile() {
+        let x = ("x", Value::Num(Number::Int(3)));
+        let y = ("y", Value::Num(Number::Float(0.5)));
+        let flag = ("flag", Value::Bool(true));
+        for input in [
+            "92 + 5 + 5 * 27 - (92 - 12) / 4 + 26",
+            "-x ^ 2 + x * y - 7 % x",
+            "sqrt(x * 12) + max(x, y) - min(x, 2)",
+            "x > 2 && y < 1 ? x * pi : y",
+            "flag || 1 / 0 == 0",
+            "!flag && 1 / 0 == 0",
+            "x < 0 ? -1 : x == 0 ? 0 : 1",
+            "(x > 1 ? y : x) + (flag ? 10 : 20)",
+            "x * 3 km + 200 m in mm",
+        ] {
+            let mut env = Environment::new();
+            for (name, value) in [x, y, flag] {
+                env.set(name, value).unwrap();
+            }
+            assert_eq!(run(input, &[x, y, flag]), env.execute(input).map(Option::unwrap), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_compile_int_mode() {
+        let x = ("x", Value::Num(Number::Int(200)));
+        for (width, overflow) in [(Width::U8, Overflow::Wrap), (Width::U16, Overflow::Error)] {
+            let mode = Some(IntMode { width, overflow });
+            let mut env = Environment::new();
+            env.set_int_mode(mode);
+            env.set(x.0, x.1).unwrap();
+            for input in [
+                "0 - 1",
+                "200 + 100",
+                "~0",
+                "-x",
+                "x * x ** 2",
+                "0x1ff & x",
+                "0x10000",
+                "x << 9 >> 4",
+                "0xf0 ^ 0xff",
+                "x > 100 ? x + 100 : 0 - x",
+                "1 / 0",
+            ] {
+                assert_eq!(run_in(mode, input, &[x]), env.execute(input).map(Option::unwrap), "{}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vars() {
+        let program = Expr::new("b * a + b * c").compile().unwrap();
+        assert_eq!(program.vars(), ["b", "a", "c"]);
+
+        // 同一个程序可以绑定不同的变量值重复计算
+        let mut stack = vec![];
+        for i in 0..10 {
+            let values = [Number::Int(i).into(), Number::Int(2).into(), Number::Int(3).into()];
+            assert_eq!(program.eval(&values, &mut stack), Ok(Number::Int(i * 5).into()));
+        }
+        assert!(stack.capacity() >= 3);
+        assert_eq!(program.eval(&[Number::Int(1).into()], &mut stack), Err(ExprError::UnknownVariable("a".into())));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let compile = |input| Expr::new(input).compile().unwrap_err();
+        assert_eq!(compile("f(x)"), ExprError::UnknownFunction("f".into()));
+        assert_eq!(compile("1 + sqrt(1, 2)"), ExprError::Arity { name: "sqrt".into(), expected: 1, found: 2 });
+
+        let mismatch = |expected, found| ExprError::TypeMismatch { expected, found };
+        let one = [Value::Num(Number::Int(1))];
+        assert_eq!(run("x && true", &[("x", one[0])]), Err(mismatch("boolean", "number")));
+        assert_eq!(run("true && x", &[("x", one[0])]), Err(mismatch("boolean", "number")));
+        assert_eq!(run("false || x", &[("x", one[0])]), Err(mismatch("boolean", "number")));
+        assert_eq!(run("x / 0", &[("x", one[0])]), Err(ExprError::DivisionByZero));
+    }
+}
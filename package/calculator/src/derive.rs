@@ -0,0 +1,210 @@
+use crate::{function, Ast, BinOp, ExprError, Number, Result, UnaryOp};
+
+impl Ast {
+    // 对变量 var 求导，得到导数的表达式
+    // 支持四则运算、幂运算和内置函数（链式法则），比较、逻辑运算和用户定义的函数不能求导
+    // 结果会化简掉 0 * x、1 * x、x + 0、x ^ 1 这样的项
+    pub fn derive(&self, var: &str) -> Result<Ast> {
+        self.simplify().derive_in(var)
+    }
+
+    fn derive_in(&self, var: &str) -> Result<Ast> {
+        let derivative = match self {
+            Ast::Num(_) | Ast::Quantity(..) => num(0),
+            Ast::Var(name) => num((name == var).into()),
+            Ast::UnaryOp(UnaryOp::Plus, u) => u.derive_in(var)?,
+            Ast::UnaryOp(UnaryOp::Neg, u) => neg(u.derive_in(var)?),
+            Ast::BinOp(op, u, v) => {
+                let (du, dv) = (u.derive_in(var)?, v.derive_in(var)?);
+                match op {
+                    BinOp::Add => binary(BinOp::Add, du, dv),
+                    BinOp::Sub => binary(BinOp::Sub, du, dv),
+                    // (uv)' = u'v + uv'
+                    BinOp::Mul => binary(
+                        BinOp::Add,
+                        binary(BinOp::Mul, du, (**v).clone()),
+                        binary(BinOp::Mul, (**u).clone(), dv),
+                    ),
+                    // (u/v)' = (u'v - uv') / v^2
+                    BinOp::Div => binary(
+                        BinOp::Div,
+                        binary(
+                            BinOp::Sub,
+                            binary(BinOp::Mul, du, (**v).clone()),
+                            binary(BinOp::Mul, (**u).clone(), dv),
+                        ),
+                        binary(BinOp::Pow, (**v).clone(), num(2)),
+                    ),
+                    // 除数是常数时 u % v = u - kv 的导数几乎处处等于 u'
+                    BinOp::Mod if is(&dv, 0) => du,
+                    BinOp::Pow => pow_rule(u, v, du, dv),
+                    _ => return Err(ExprError::NotDifferentiable(self.to_string())),
+                }
+            }
+            // 分段函数，每个分支分别求导
+            Ast::Cond(cond, then, otherwise) => {
+                Ast::Cond(cond.clone(), Box::new(then.derive_in(var)?), Box::new(otherwise.derive_in(var)?)).simplify()
+            }
+            Ast::Convert(u, unit) => Ast::Convert(Box::new(u.derive_in(var)?), *unit).simplify(),
+            Ast::Call(name, args) => {
+                let Some(builtin) = function::builtin(name) else {
+                    return Err(ExprError::NotDifferentiable(self.to_string()));
+                };
+                function::check_arity(name, builtin.arity, args.len())?;
+                call_rule(name, args, var)?
+            }
+            Ast::Bool(_) | Ast::UnaryOp(UnaryOp::Not | UnaryOp::BitNot, _) => {
+                return Err(ExprError::NotDifferentiable(self.to_string()));
+            }
+        };
+        Ok(derivative)
+    }
+}
+
+// 幂运算求导
+// 1. 指数是常数：(u^n)' = n * u^(n-1) * u'
+// 2. 底数是常数：(a^v)' = a^v * ln(a) * v'
+// 3. 一般情况：(u^v)' = u^v * (v' * ln(u) + v * u' / u)
+fn pow_rule(u: &Ast, v: &Ast, du: Ast, dv: Ast) -> Ast {
+    let power = binary(BinOp::Pow, u.clone(), v.clone());
+    let ln = |u: &Ast| Ast::Call("ln".into(), vec![u.clone()]).simplify();
+    if is(&dv, 0) {
+        let n = binary(BinOp::Sub, v.clone(), num(1));
+        let power = binary(BinOp::Pow, u.clone(), n);
+        return binary(BinOp::Mul, binary(BinOp::Mul, v.clone(), power), du);
+    }
+    if is(&du, 0) {
+        return binary(BinOp::Mul, binary(BinOp::Mul, power, ln(u)), dv);
+    }
+    let inner = binary(
+        BinOp::Add,
+        binary(BinOp::Mul, dv, ln(u)),
+        binary(BinOp::Div, binary(BinOp::Mul, v.clone(), du), u.clone()),
+    );
+    binary(BinOp::Mul, power, inner)
+}
+
+// 内置函数求导，f(u)' = f'(u) * u'
+fn call_rule(name: &str, args: &[Ast], var: &str) -> Result<Ast> {
+    let call = |name: &str, arg: &Ast| Ast::Call(name.into(), vec![arg.clone()]).simplify();
+    let u = &args[0];
+    let du = u.derive_in(var)?;
+    let outer = match name {
+        // sqrt(u)' = 1 / (2 * sqrt(u))
+        "sqrt" => binary(BinOp::Div, num(1), binary(BinOp::Mul, num(2), call("sqrt", u))),
+        "sin" => call("cos", u),
+        "cos" => neg(call("sin", u)),
+        "ln" => binary(BinOp::Div, num(1), u.clone()),
+        // log(u)' = 1 / (u * ln(10))
+        "log" => binary(BinOp::Div, num(1), binary(BinOp::Mul, u.clone(), call("ln", &num(10)))),
+        // abs(u)' = u / abs(u)，在 0 处没有定义
+        "abs" => binary(BinOp::Div, u.clone(), call("abs", u)),
+        // 取较小或较大的参数对应的导数
+        "min" | "max" => {
+            let op = if name == "min" { BinOp::Lt } else { BinOp::Gt };
+            let v = &args[1];
+            let cond = Ast::BinOp(op, Box::new(u.clone()), Box::new(v.clone()));
+            return Ok(Ast::Cond(Box::new(cond), Box::new(du), Box::new(v.derive_in(var)?)).simplify());
+        }
+        // 取整函数是分段常数，导数几乎处处为 0
+        _ => num(0),
+    };
+    Ok(binary(BinOp::Mul, outer, du))
+}
+
+fn num(n: i64) -> Ast {
+    Ast::Num(Number::Int(n))
+}
+
+// 是不是指定的常数
+fn is(ast: &Ast, n: i64) -> bool {
+    matches!(ast, Ast::Num(x) if *x == Number::Int(n))
+}
+
+fn neg(u: Ast) -> Ast {
+    match u {
+        Ast::UnaryOp(UnaryOp::Neg, u) => *u,
+        u => Ast::UnaryOp(UnaryOp::Neg, Box::new(u)).simplify(),
+    }
+}
+
+// 构造二元运算，同时化简 0 和 1 参与的运算，并计算常量
+fn binary(op: BinOp, u: Ast, v: Ast) -> Ast {
+    match op {
+        BinOp::Add if is(&u, 0) => v,
+        BinOp::Add | BinOp::Sub if is(&v, 0) => u,
+        BinOp::Sub if is(&u, 0) => neg(v),
+        BinOp::Mul if is(&u, 0) || is(&v, 0) => num(0),
+        BinOp::Mul if is(&u, 1) => v,
+        BinOp::Mul | BinOp::Div if is(&v, 1) => u,
+        BinOp::Div if is(&u, 0) => num(0),
+        BinOp::Pow if is(&v, 0) => num(1),
+        BinOp::Pow if is(&v, 1) => u,
+        // 乘以 -1 写成负号，例如 -1 * sin(x) = -sin(x)
+        BinOp::Mul if is(&u, -1) => neg(v),
+        _ => Ast::BinOp(op, Box::new(u), Box::new(v)).simplify(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn derive(input: &str) -> String {
+        let ast = Expr::new(input).parse().unwrap();
+        match ast.derive("x") {
+            Ok(ast) => ast.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_derive() {
+        for (input, output) in [
+            ("42", "0"),
+            ("y", "0"),
+            ("x", "1"),
+            ("3 * x + 2", "3"),
+            ("x ^ 2", "2 * x"),
+            ("x ^ 3 - 2 * x", "3 * x ^ 2 - 2"),
+            ("-x ^ -1", "x ^ -2"),
+            ("x * y", "y"),
+            ("x * sin(x)", "sin(x) + x * cos(x)"),
+            ("1 / x", "-1 / x ^ 2"),
+            ("(x + 1) / (x - 1)", "(x - 1 - (x + 1)) / (x - 1) ^ 2"),
+            ("sin(x ^ 2)", "cos(x ^ 2) * (2 * x)"),
+            ("cos(2 * x)", "-sin(2 * x) * 2"),
+            ("sqrt(x)", "1 / (2 * sqrt(x))"),
+            ("ln(x ^ 2 + 1)", "1 / (x ^ 2 + 1) * (2 * x)"),
+            ("2 ^ x", "2 ^ x * 0.6931471805599453"),
+            ("x ^ x", "x ^ x * (ln(x) + x / x)"),
+            ("max(x, 2 * x)", "x > 2 * x ? 1 : 2"),
+            ("x > 0 ? x ^ 2 : -x", "x > 0 ? 2 * x : -1"),
+            ("floor(x) + x % 3", "1"),
+            ("x < 1", "Cannot differentiate 'x < 1'"),
+            ("f(x)", "Cannot differentiate 'f(x)'"),
+            ("x % x", "Cannot differentiate 'x % x'"),
+        ] {
+            assert_eq!(derive(input), output, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_derive_eval() {
+        // 导数输出后可以重新解析和计算，和数值微分的结果比较
+        for input in ["x ^ 3 / (1 + x)", "sin(x) * cos(x ^ 2)", "sqrt(x) + log(x) - abs(x - 5)", "e ^ (2 * x) + x ^ x"] {
+            let derivative = Expr::new(input).parse().unwrap().derive("x").unwrap().to_string();
+            let mut env = Environment::new();
+            let mut eval = |x: f64, input: &str| {
+                env.set("x", Value::Num(Number::Float(x))).unwrap();
+                env.execute(input).unwrap().unwrap().as_number().unwrap().as_f64()
+            };
+            for x in [0.5, 1.0, 2.5] {
+                let h = 1e-6;
+                let numeric = (eval(x + h, input) - eval(x - h, input)) / (2.0 * h);
+                let symbolic = eval(x, &derivative);
+                assert!((numeric - symbolic).abs() < 1e-4, "{}: {} != {}", derivative, numeric, symbolic);
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{function, Ast, Expr, ExprError, IntMode, Number, Radix, Result, Value};
+use std::collections::BTreeMap;
+
+// 内置的常量
+pub(crate) const CONSTANTS: [(&str, f64); 2] = [("pi", std::f64::consts::PI), ("e", std::f64::consts::E)];
+
+// 上一次计算结果的变量名
+const ANS: &str = "ans";
+
+// 一条语句，表达式、赋值或者函数定义
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Expr(Ast),
+    Assign(String, Ast),
+    // 函数名、参数列表、函数体
+    Define(String, Vec<String>, Ast),
+}
+
+// 用户定义的函数
+#[derive(Debug)]
+pub(crate) struct Function {
+    pub params: Vec<String>,
+    pub body: Ast,
+}
+
+// 求值环境，保存用户定义的变量、函数和上一次计算的结果，以及整数模式和输出的进制
+#[derive(Debug, Default)]
+pub struct Environment {
+    vars: BTreeMap<String, Value>,
+    functions: BTreeMap<String, Function>,
+    ans: Option<Value>,
+    int_mode: Option<IntMode>,
+    radix: Radix,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 查找变量，依次查找用户定义的变量、上一次的结果、内置常量
+    pub fn get(&self, name: &str) -> Option<Value> {
+        if let Some(n) = self.vars.get(name) {
+            return Some(*n);
+        }
+        if name == ANS {
+            return self.ans;
+        }
+        CONSTANTS.iter().find(|(c, _)| *c == name).map(|(_, n)| Value::Num(Number::Float(*n)))
+    }
+
+    // 设置变量，内置常量、布尔值和 ans 不能被修改
+    pub fn set(&mut self, name: &str, value: Value) -> Result<()> {
+        if matches!(name, ANS | "true" | "false") || CONSTANTS.iter().any(|(c, _)| *c == name) {
+            return Err(ExprError::ReadOnly(name.to_string()));
+        }
+        self.vars.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    // 用户定义的变量，按照名字排序
+    pub fn vars(&self) -> impl Iterator<Item = (&str, Value)> {
+        self.vars.iter().map(|(name, n)| (name.as_str(), *n))
+    }
+
+    // 定义函数，同名的函数会被覆盖，内置函数不能被覆盖
+    pub fn define(&mut self, name: &str, params: Vec<String>, body: Ast) -> Result<()> {
+        if function::builtin(name).is_some() {
+            return Err(ExprError::ReadOnly(name.to_string()));
+        }
+        self.functions.insert(name.to_string(), Function { params, body });
+        Ok(())
+    }
+
+    // 查找用户定义的函数
+    pub(crate) fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.get(name)
+    }
+
+    // 用户定义的函数，按照名字排序，返回函数名、参数列表、函数体
+    pub fn functions(&self) -> impl Iterator<Item = (&str, &[String], &Ast)> {
+        self.functions
+            .iter()
+            .map(|(name, f)| (name.as_str(), f.params.as_slice(), &f.body))
+    }
+
+    // 清除用户定义的变量、函数和上一次的结果
+    pub fn clear(&mut self) {
+        self.vars.clear();
+        self.functions.clear();
+        self.ans = None;
+    }
+
+    // 切换整数模式，None 表示普通模式
+    pub fn set_int_mode(&mut self, mode: Option<IntMode>) {
+        self.int_mode = mode;
+    }
+
+    pub fn int_mode(&self) -> Option<IntMode> {
+        self.int_mode
+    }
+
+    // 设置输出整数使用的进制
+    pub fn set_radix(&mut self, radix: Radix) {
+        self.radix = radix;
+    }
+
+    pub fn radix(&self) -> Radix {
+        self.radix
+    }
+
+    // 按照当前的进制输出值，整数模式下按照位宽输出无符号整数
+    pub fn format(&self, value: Value) -> String {
+        match (value, self.int_mode) {
+            (Value::Num(Number::Int(n)), Some(mode)) => match mode.fit(value) {
+                Ok(Value::Num(Number::Int(bits))) => self.radix.format(false, bits as u64),
+                _ => n.to_string(),
+            },
+            (Value::Num(Number::Int(n)), None) => self.radix.format(n < 0, n.unsigned_abs()),
+            _ => value.to_string(),
+        }
+    }
+
+    // 执行一条语句，返回计算结果，并记录为 ans
+    // 赋值语句返回赋给变量的值，函数定义没有结果
+    pub fn execute(&mut self, input: &str) -> Result<Option<Value>> {
+        let value = match Expr::new(input).int_mode(self.int_mode).parse_statement()? {
+            Statement::Expr(ast) => ast.eval(self)?,
+            Statement::Assign(name, ast) => {
+                let value = ast.eval(self)?;
+                self.set(&name, value)?;
+                value
+            }
+            Statement::Define(name, params, body) => {
+                self.define(&name, params, body)?;
+                return Ok(None);
+            }
+        };
+        self.ans = Some(value);
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variables() {
+        let mut env = Environment::new();
+        assert_eq!(env.execute("x = 3 * 4"), Ok(Some(Value::Num(Number::Int(12)))));
+        assert_eq!(env.execute("y = x / 8"), Ok(Some(Value::Num(Number::Float(1.5)))));
+        assert_eq!(env.execute("x = x + 1"), Ok(Some(Value::Num(Number::Int(13)))));
+        assert_eq!(env.execute("x * y"), Ok(Some(Value::Num(Number::Float(19.5)))));
+        assert_eq!(env.vars().collect::<Vec<_>>(), vec![("x", Value::Num(Number::Int(13))), ("y", Value::Num(Number::Float(1.5)))]);
+
+        assert_eq!(env.execute("z + 1"), Err(ExprError::UnknownVariable("z".into())));
+        assert_eq!(env.execute("z = w"), Err(ExprError::UnknownVariable("w".into())));
+        assert_eq!(env.get("z"), None);
+
+        env.clear();
+        assert_eq!(env.vars().count(), 0);
+        assert_eq!(env.execute("x"), Err(ExprError::UnknownVariable("x".into())));
+    }
+
+    #[test]
+    fn test_ans() {
+        let mut env = Environment::new();
+        assert_eq!(env.execute("ans"), Err(ExprError::UnknownVariable("ans".into())));
+        env.execute("2 + 3").unwrap();
+        assert_eq!(env.execute("ans * 2"), Ok(Some(Value::Num(Number::Int(10)))));
+        assert_eq!(env.execute("ans * 2"), Ok(Some(Value::Num(Number::Int(20)))));
+
+        // 出错时 ans 保持不变
+        assert_eq!(env.execute("ans / 0"), Err(ExprError::DivisionByZero));
+        assert_eq!(env.execute("ans"), Ok(Some(Value::Num(Number::Int(20)))));
+        assert_eq!(env.execute("ans = 1"), Err(ExprError::ReadOnly("ans".into())));
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut env = Environment::new();
+        assert_eq!(env.execute("pi"), Ok(Some(Value::Num(Number::Float(std::f64::consts::PI)))));
+        assert_eq!(env.execute("e ^ 2"), Ok(Some(Value::Num(Number::Float(std::f64::consts::E.powi(2))))));
+        assert_eq!(env.execute("pi = 3"), Err(ExprError::ReadOnly("pi".into())));
+        assert_eq!(env.execute("true = 3"), Err(ExprError::ReadOnly("true".into())));
+        assert_eq!(Expr::new("2 * pi").eval(), Ok(Value::Num(Number::Float(std::f64::consts::TAU))));
+    }
+
+    #[test]
+    fn test_assign_errors() {
+        let mut env = Environment::new();
+        let error = |input| Expr::new(input).parse_statement().unwrap_err().to_string();
+        assert_eq!(error("x = "), "Expected a number or '(', found end of input at position 4");
+        assert_eq!(error("1 = 2"), "Unexpected '=' at position 2");
+        assert_eq!(error("x = y = 2"), "Unexpected '=' at position 6");
+        assert!(env.execute("(x) = 2").is_err());
+    }
+}
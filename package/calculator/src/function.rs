@@ -0,0 +1,189 @@
+use crate::{ExprError, Number, Result};
+
+// 内置函数
+#[derive(Debug)]
+pub(crate) struct Builtin {
+    pub name: &'static str,
+    // 参数个数
+    pub arity: usize,
+    pub func: fn(&[Number]) -> Result<Number>,
+}
+
+pub(crate) const BUILTINS: [Builtin; 11] = [
+    Builtin { name: "sqrt", arity: 1, func: sqrt },
+    Builtin { name: "sin", arity: 1, func: |args| Ok(Number::Float(args[0].as_f64().sin())) },
+    Builtin { name: "cos", arity: 1, func: |args| Ok(Number::Float(args[0].as_f64().cos())) },
+    Builtin { name: "ln", arity: 1, func: ln },
+    Builtin { name: "log", arity: 1, func: log },
+    Builtin { name: "abs", arity: 1, func: abs },
+    Builtin { name: "min", arity: 2, func: min },
+    Builtin { name: "max", arity: 2, func: max },
+    Builtin { name: "floor", arity: 1, func: |args| Ok(round_with(args[0], f64::floor)) },
+    Builtin { name: "ceil", arity: 1, func: |args| Ok(round_with(args[0], f64::ceil)) },
+    Builtin { name: "round", arity: 1, func: |args| Ok(round_with(args[0], f64::round)) },
+];
+
+// 查找内置函数
+pub(crate) fn builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|b| b.name == name)
+}
+
+impl Builtin {
+    // 检查参数个数，然后调用
+    pub fn call(&self, args: &[Number]) -> Result<Number> {
+        check_arity(self.name, self.arity, args.len())?;
+        (self.func)(args)
+    }
+}
+
+// 检查函数调用的参数个数
+pub(crate) fn check_arity(name: &str, expected: usize, found: usize) -> Result<()> {
+    if expected != found {
+        return Err(ExprError::Arity {
+            name: name.to_string(),
+            expected,
+            found,
+        });
+    }
+    Ok(())
+}
+
+fn sqrt(args: &[Number]) -> Result<Number> {
+    match args[0].as_f64() {
+        x if x < 0.0 => Err(ExprError::Domain("sqrt".into())),
+        x => Ok(Number::Float(x.sqrt())),
+    }
+}
+
+fn ln(args: &[Number]) -> Result<Number> {
+    match args[0].as_f64() {
+        x if x <= 0.0 => Err(ExprError::Domain("ln".into())),
+        x => Ok(Number::Float(x.ln())),
+    }
+}
+
+// 以 10 为底的对数
+fn log(args: &[Number]) -> Result<Number> {
+    match args[0].as_f64() {
+        x if x <= 0.0 => Err(ExprError::Domain("log".into())),
+        x => Ok(Number::Float(x.log10())),
+    }
+}
+
+fn abs(args: &[Number]) -> Result<Number> {
+    match args[0] {
+        Number::Int(n) => n.checked_abs().map(Number::Int).ok_or(ExprError::Overflow),
+        Number::Float(n) => Ok(Number::Float(n.abs())),
+    }
+}
+
+// 返回较小的参数，保持参数原来的类型
+fn min(args: &[Number]) -> Result<Number> {
+    Ok(if args[1].as_f64() < args[0].as_f64() { args[1] } else { args[0] })
+}
+
+fn max(args: &[Number]) -> Result<Number> {
+    Ok(if args[1].as_f64() > args[0].as_f64() { args[1] } else { args[0] })
+}
+
+// 取整，结果在整数范围内时返回整数
+fn round_with(n: Number, round: fn(f64) -> f64) -> Number {
+    match n {
+        Number::Int(_) => n,
+        Number::Float(f) => {
+            let rounded = round(f);
+            if rounded >= i64::MIN as f64 && rounded < i64::MAX as f64 {
+                Number::Int(rounded as i64)
+            } else {
+                Number::Float(rounded)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn eval(input: &str) -> Result<Number> {
+        Expr::new(input).eval()?.as_number()
+    }
+
+    #[test]
+    fn test_builtins() {
+        assert_eq!(eval("sqrt(16) + abs(-3)"), Ok(Number::Float(7.0)));
+        assert_eq!(eval("sin(0) + cos(0)"), Ok(Number::Float(1.0)));
+        assert_eq!(eval("ln(e) + log(1000)"), Ok(Number::Float(4.0)));
+        assert_eq!(eval("min(3, 2.5) + max(-1, 4)"), Ok(Number::Float(6.5)));
+        assert_eq!(eval("floor(2.7) + ceil(2.2) + round(-2.5)"), Ok(Number::Int(2)));
+        assert!(matches!(eval("floor(7 / 2)"), Ok(Number::Int(3))));
+        assert!(matches!(eval("round(1e300)"), Ok(Number::Float(_))));
+        assert!(matches!(eval("max(2, 2 + 1)"), Ok(Number::Int(3))));
+
+        assert_eq!(eval("sqrt(-1)"), Err(ExprError::Domain("sqrt".into())));
+        assert_eq!(eval("ln(0)"), Err(ExprError::Domain("ln".into())));
+        assert_eq!(eval("abs(-9223372036854775807 - 1)"), Err(ExprError::Overflow));
+        assert_eq!(eval("foo(1)"), Err(ExprError::UnknownFunction("foo".into())));
+    }
+
+    #[test]
+    fn test_arity() {
+        let arity = |name: &str, expected, found| ExprError::Arity {
+            name: name.into(),
+            expected,
+            found,
+        };
+        assert_eq!(eval("sqrt()"), Err(arity("sqrt", 1, 0)));
+        assert_eq!(eval("max(1, 2, 3)"), Err(arity("max", 2, 3)));
+        assert_eq!(eval("min(1)").unwrap_err().to_string(), "'min' expects 2 arguments, found 1");
+
+        let mut env = Environment::new();
+        env.execute("f(x, y) = x * y").unwrap();
+        assert_eq!(env.execute("f(2)"), Err(arity("f", 2, 1)));
+    }
+
+    #[test]
+    fn test_user_functions() {
+        let mut env = Environment::new();
+        assert_eq!(env.execute("f(x) = x^2 + 1"), Ok(None));
+        assert_eq!(env.execute("f(3)"), Ok(Some(Number::Int(10).into())));
+        assert_eq!(env.execute("g(x, y) = sqrt(f(x) + y)"), Ok(None));
+        assert_eq!(env.execute("g(2, 4)"), Ok(Some(Number::Float(3.0).into())));
+
+        // 参数会覆盖同名的变量，函数体中可以使用调用时的全局变量
+        env.execute("x = 100").unwrap();
+        env.execute("k = 2").unwrap();
+        env.execute("h(x) = k * x").unwrap();
+        assert_eq!(env.execute("h(5)"), Ok(Some(Number::Int(10).into())));
+        env.execute("k = 3").unwrap();
+        assert_eq!(env.execute("h(x)"), Ok(Some(Number::Int(300).into())));
+
+        // 重新定义会覆盖之前的定义，不能覆盖内置函数
+        env.execute("f(x) = x").unwrap();
+        assert_eq!(env.execute("f(3)"), Ok(Some(Number::Int(3).into())));
+        assert_eq!(env.execute("sqrt(x) = x"), Err(ExprError::ReadOnly("sqrt".into())));
+        let funcs: Vec<String> = env.functions().map(|(name, params, body)| format!("{}({}) = {}", name, params.join(", "), body)).collect();
+        assert_eq!(funcs, ["f(x) = x", "g(x, y) = sqrt(f(x) + y)", "h(x) = k * x"]);
+
+        // 没有终止条件的递归
+        env.execute("r(x) = r(x) + 1").unwrap();
+        assert_eq!(env.execute("r(1)"), Err(ExprError::RecursionLimit));
+    }
+
+    #[test]
+    fn test_definition_parse() {
+        let parse = |input| Expr::new(input).parse_statement();
+        let body = Expr::new("x + y").parse().unwrap();
+        assert_eq!(parse("f(x, y) = x + y"), Ok(Statement::Define("f".into(), vec!["x".into(), "y".into()], body)));
+        assert_eq!(parse("f(x, x) = x").unwrap_err().to_string(), "Duplicate parameter 'x' at position 5");
+        assert!(matches!(parse("f(x, 1) = x"), Err(ExprError::Parse { .. })));
+        assert!(matches!(parse("f(1, x)"), Ok(Statement::Expr(_))));
+    }
+
+    #[test]
+    fn test_simplify_calls() {
+        let simplify = |input| Expr::new(input).parse().unwrap().simplify().to_string();
+        assert_eq!(simplify("x * sqrt(2 + 2)"), "x * 2.0");
+        assert_eq!(simplify("f(1 + 1) + sqrt(-1)"), "f(2) + sqrt(-1)");
+    }
+}
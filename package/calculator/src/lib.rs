@@ -1,12 +1,77 @@
-use std::{fmt::Display, iter::Peekable, str::Chars};
+mod ast;
+mod bits;
+mod compile;
+mod derive;
+mod env;
+mod function;
+mod number;
+mod unit;
+mod value;
+
+use ast::{ASSOC_LEFT, TERNARY_PRECEDENCE};
+use std::{fmt::Display, iter::Peekable, str::CharIndices};
+
+pub use ast::{Ast, BinOp, UnaryOp};
+pub use bits::{IntMode, Overflow, Radix, Width};
+pub use compile::Program;
+pub use env::{Environment, Statement};
+pub use number::Number;
+pub use unit::Unit;
+pub use value::Value;
 
 // 自定义 Result 类型
 type Result<T> = std::result::Result<T, ExprError>;
 
+// 源码中的位置，字节偏移的范围 [start, end)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 // 自定义错误类型
 #[derive(Debug, PartialEq)]
 pub enum ExprError {
-    Parse(String),
+    // 解析错误，以及出错的位置
+    Parse { message: String, span: Span },
+    // 除数或者取余的除数为 0
+    DivisionByZero,
+    // 整数运算溢出，或者浮点数运算结果为无穷大
+    Overflow,
+    // 0 的负数次幂，或者负数的小数次幂
+    InvalidExponent,
+    // 未定义的变量
+    UnknownVariable(String),
+    // 未定义的函数
+    UnknownFunction(String),
+    // 内置常量、内置函数等不能修改的名字
+    ReadOnly(String),
+    // 函数调用的参数个数不对
+    Arity { name: String, expected: usize, found: usize },
+    // 参数超出了函数的定义域
+    Domain(String),
+    // 函数调用的层数太多
+    RecursionLimit,
+    // 数字和布尔值不能混用
+    TypeMismatch { expected: &'static str, found: &'static str },
+    // 量纲不同的单位不能相加、比较或者换算
+    IncompatibleUnits(String, String),
+    // 不能求导的表达式，例如比较运算和用户定义的函数
+    NotDifferentiable(String),
+}
+
+impl ExprError {
+    fn parse(message: impl Into<String>, span: Span) -> Self {
+        Self::Parse {
+            message: message.into(),
+            span,
+        }
+    }
+
+    // 结合源码显示错误，解析错误会在出错的位置下面标出 ^
+    pub fn diagnostic<'a>(&'a self, source: &'a str) -> Diagnostic<'a> {
+        Diagnostic { error: self, source }
+    }
 }
 
 impl std::error::Error for ExprError {}
@@ -14,29 +79,86 @@ impl std::error::Error for ExprError {}
 impl Display for ExprError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Parse(s) => write!(f, "{}", s),
+            Self::Parse { message, span } => write!(f, "{} at position {}", message, span.start),
+            Self::DivisionByZero => write!(f, "Division by zero"),
+            Self::Overflow => write!(f, "Arithmetic overflow"),
+            Self::InvalidExponent => write!(f, "Invalid exponent"),
+            Self::UnknownVariable(name) => write!(f, "Unknown variable '{}'", name),
+            Self::UnknownFunction(name) => write!(f, "Unknown function '{}'", name),
+            Self::ReadOnly(name) => write!(f, "Cannot redefine '{}'", name),
+            Self::Arity { name, expected, found } => {
+                let plural = if *expected == 1 { "" } else { "s" };
+                write!(f, "'{}' expects {} argument{}, found {}", name, expected, plural, found)
+            }
+            Self::Domain(name) => write!(f, "Argument out of the domain of '{}'", name),
+            Self::RecursionLimit => write!(f, "Too many nested function calls"),
+            Self::TypeMismatch { expected, found } => {
+                let article = |word: &str| if word.starts_with(['a', 'e', 'i', 'o', 'u']) { "an" } else { "a" };
+                write!(f, "Expected {} {}, found {} {}", article(expected), expected, article(found), found)
+            }
+            Self::IncompatibleUnits(from, to) => write!(f, "Incompatible units '{}' and '{}'", from, to),
+            Self::NotDifferentiable(expr) => write!(f, "Cannot differentiate '{}'", expr),
         }
     }
 }
 
-// Token 表示，数字、运算符号、括号
+// 带源码的错误信息，例如：
+// 2 $ 3
+//   ^ Unexpected character '$'
+pub struct Diagnostic<'a> {
+    error: &'a ExprError,
+    source: &'a str,
+}
+
+impl Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ExprError::Parse { message, span } = self.error else {
+            return write!(f, "{}", self.error);
+        };
+
+        // 按照字符计算列，范围为空时（输入结束）也标出一个 ^
+        let column = self.source[..span.start].chars().count();
+        let width = self.source[span.start..span.end].chars().count().max(1);
+        writeln!(f, "{}", self.source)?;
+        write!(f, "{}{} {}", " ".repeat(column), "^".repeat(width), message)
+    }
+}
+
+// Token 表示，数字、标识符、运算符号、括号、逗号等
+// 标识符的名字可以根据位置从源码中获取
 #[derive(Debug, Clone, Copy)]
 enum Token {
-    Number(i32),
+    Number(Number),
+    Ident,
     Plus,           // 加
     Minus,          // 减
     Multiply,       // 乘
     Divide,         // 除
+    Modulo,         // 取余
     Power,          // 幂
     LeftParen,      // 左括号
     RightParen,     // 右括号
+    Comma,          // 逗号
+    Assign,         // 赋值
+    Equal,          // 等于
+    NotEqual,       // 不等于
+    Less,           // 小于
+    LessEqual,      // 小于等于
+    Greater,        // 大于
+    GreaterEqual,   // 大于等于
+    And,            // 逻辑与
+    Or,             // 逻辑或
+    Not,            // 逻辑非
+    Question,       // 问号
+    Colon,          // 冒号
+    BitAnd,         // 按位与
+    BitOr,          // 按位或
+    BitXor,         // 按位异或
+    BitNot,         // 按位取反
+    ShiftLeft,      // 左移
+    ShiftRight,     // 右移
 }
 
-// 左结合
-const ASSOC_LEFT: i32 = 0;
-// 右结合
-const ASSOC_RIGHT: i32 = 1;
-
 impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -44,202 +166,586 @@ impl Display for Token {
             "{}",
             match self {
                 Token::Number(n) => n.to_string(),
+                Token::Ident => "identifier".to_string(),
                 Token::Plus => "+".to_string(),
                 Token::Minus => "-".to_string(),
                 Token::Multiply => "*".to_string(),
                 Token::Divide => "/".to_string(),
+                Token::Modulo => "%".to_string(),
                 Token::Power => "^".to_string(),
                 Token::LeftParen => "(".to_string(),
                 Token::RightParen => ")".to_string(),
+                Token::Comma => ",".to_string(),
+                Token::Assign => "=".to_string(),
+                Token::Equal => "==".to_string(),
+                Token::NotEqual => "!=".to_string(),
+                Token::Less => "<".to_string(),
+                Token::LessEqual => "<=".to_string(),
+                Token::Greater => ">".to_string(),
+                Token::GreaterEqual => ">=".to_string(),
+                Token::And => "&&".to_string(),
+                Token::Or => "||".to_string(),
+                Token::Not => "!".to_string(),
+                Token::Question => "?".to_string(),
+                Token::Colon => ":".to_string(),
+                Token::BitAnd => "&".to_string(),
+                Token::BitOr => "|".to_string(),
+                Token::BitXor => "^".to_string(),
+                Token::BitNot => "~".to_string(),
+                Token::ShiftLeft => "<<".to_string(),
+                Token::ShiftRight => ">>".to_string(),
             }
         )
     }
 }
 
 impl Token {
-    // 判断是不是运算符号
-    fn is_operator(&self) -> bool {
-        matches!(self, Token::Plus | Token::Minus | Token::Multiply | Token::Divide | Token::Power)
-    }
-
-    // 获取运算符的优先级
-    fn precedence(&self) -> i32 {
+    // 获取对应的二元运算符
+    fn bin_op(&self) -> Option<BinOp> {
         match self {
-            Token::Plus | Token::Minus => 1,
-            Token::Multiply | Token::Divide => 2,
-            Token::Power => 3,
-            _ => 0,
-        }
-    }
-
-    // 获取运算符的结合性
-    fn assoc(&self) -> i32 {
-        match self {
-            Token::Power => ASSOC_RIGHT,
-            _ => ASSOC_LEFT,
-        }
-    }
-
-    // 根据当前运算符进行计算
-    fn computer(&self, l: i32, r: i32) -> Option<i32> {
-        match self {
-            Token::Plus => Some(l + r),
-            Token::Minus => Some(l - r),
-            Token::Multiply => Some(l * r),
-            Token::Divide => Some(l / r),
-            Token::Power => Some(l.pow(r as u32)),
+            Token::Plus => Some(BinOp::Add),
+            Token::Minus => Some(BinOp::Sub),
+            Token::Multiply => Some(BinOp::Mul),
+            Token::Divide => Some(BinOp::Div),
+            Token::Modulo => Some(BinOp::Mod),
+            Token::Power => Some(BinOp::Pow),
+            Token::Equal => Some(BinOp::Eq),
+            Token::NotEqual => Some(BinOp::Ne),
+            Token::Less => Some(BinOp::Lt),
+            Token::LessEqual => Some(BinOp::Le),
+            Token::Greater => Some(BinOp::Gt),
+            Token::GreaterEqual => Some(BinOp::Ge),
+            Token::And => Some(BinOp::And),
+            Token::Or => Some(BinOp::Or),
+            Token::BitAnd => Some(BinOp::BitAnd),
+            Token::BitOr => Some(BinOp::BitOr),
+            Token::BitXor => Some(BinOp::BitXor),
+            Token::ShiftLeft => Some(BinOp::Shl),
+            Token::ShiftRight => Some(BinOp::Shr),
             _ => None,
         }
     }
 }
 
 struct Tokenizer<'a> {
-    tokens: Peekable<Chars<'a>>,
+    input: &'a str,
+    tokens: Peekable<CharIndices<'a>>,
+    // 整数模式下 ^ 是按位异或
+    int_mode: bool,
 }
 
 impl<'a> Tokenizer<'a> {
-    fn new(input: &'a str) -> Self {
+    fn new(input: &'a str, int_mode: bool) -> Self {
         Self {
-            tokens: input.chars().peekable(),
+            input,
+            tokens: input.char_indices().peekable(),
+            int_mode,
         }
     }
 
+    // 当前扫描到的位置
+    fn position(&mut self) -> usize {
+        self.tokens.peek().map_or(self.input.len(), |(i, _)| *i)
+    }
+
     // 消除空白字符
     fn consume_whitespace(&mut self) {
-        while let Some(&c) = self.tokens.peek() {
-            if c.is_whitespace() {
-                self.tokens.next();
-            } else {
-                break;
-            }
+        while self.tokens.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    // 扫描连续的数字
+    fn scan_digits(&mut self) {
+        while self.tokens.next_if(|(_, c)| c.is_ascii_digit()).is_some() {}
+    }
+
+    // 扫描十六进制、二进制、八进制的整数，例如 0xff、0b1010_0101、0o17，数字之间可以用 _ 分隔
+    // 超出 i64 范围的数字，整数模式下保存为相同的二进制位，否则使用浮点数表示
+    fn scan_radix(&mut self, start: usize, radix: u32) -> Result<Token> {
+        while self.tokens.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_').is_some() {}
+        let span = Span {
+            start,
+            end: self.position(),
+        };
+        let num = &self.input[span.start..span.end];
+        let digits = num[2..].replace('_', "");
+        match u64::from_str_radix(&digits, radix) {
+            Ok(n) if i64::try_from(n).is_ok() || self.int_mode => Ok(Token::Number(Number::Int(n as i64))),
+            Ok(n) => Ok(Token::Number(Number::Float(n as f64))),
+            Err(_) => Err(ExprError::parse(format!("Invalid number '{}'", num), span)),
         }
     }
 
-    // 扫描数字
-    fn scan_number(&mut self) -> Option<Token> {
-        let mut num = String::new();
-        while let Some(&c) = self.tokens.peek() {
-            if c.is_numeric() {
-                num.push(c);
-                self.tokens.next();
-            } else {
-                break;
+    // 扫描数字，包含小数点或者科学计数法的是浮点数，例如 3.5、.5、1e3、2.5E-3
+    fn scan_number(&mut self) -> Result<Token> {
+        let start = self.position();
+        let mut lookahead = self.tokens.clone();
+        if lookahead.next_if(|(_, c)| *c == '0').is_some() {
+            let radix = match lookahead.next().map(|(_, c)| c.to_ascii_lowercase()) {
+                Some('x') => Some(16),
+                Some('b') => Some(2),
+                Some('o') => Some(8),
+                _ => None,
+            };
+            if let Some(radix) = radix {
+                self.tokens = lookahead;
+                return self.scan_radix(start, radix);
             }
         }
 
-        match num.parse() {
-            Ok(n) => Some(Token::Number(n)),
-            Err(_) => None,
+        let mut float = false;
+        self.scan_digits();
+
+        if self.tokens.next_if(|(_, c)| *c == '.').is_some() {
+            float = true;
+            self.scan_digits();
+        }
+
+        // 只有 e 后面跟着数字时才是指数部分
+        let mut lookahead = self.tokens.clone();
+        if lookahead.next_if(|(_, c)| matches!(c, 'e' | 'E')).is_some() {
+            lookahead.next_if(|(_, c)| matches!(c, '+' | '-'));
+            if lookahead.peek().is_some_and(|(_, c)| c.is_ascii_digit()) {
+                float = true;
+                self.tokens = lookahead;
+                self.scan_digits();
+            }
         }
+
+        let span = Span {
+            start,
+            end: self.position(),
+        };
+        let num = &self.input[span.start..span.end];
+        let number = match num.parse() {
+            Ok(n) if !float => Some(Number::Int(n)),
+            // 超出整数范围的数字使用浮点数表示
+            _ => num.parse().ok().map(Number::Float),
+        };
+        number
+            .map(Token::Number)
+            .ok_or_else(|| ExprError::parse(format!("Invalid number '{}'", num), span))
+    }
+
+    // 扫描标识符，字母或者下划线开头，后面可以是字母、数字、下划线
+    fn scan_ident(&mut self) -> Result<Token> {
+        while self.tokens.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_').is_some() {}
+        Ok(Token::Ident)
+    }
+
+    // 下一个字符是 c 时，作为双字符运算符的一部分
+    fn follows(&mut self, c: char) -> bool {
+        self.tokens.next_if(|(_, next)| *next == c).is_some()
     }
 
     // 扫描运算符号
-    fn scan_operator(&mut self) -> Option<Token> {
-        match self.tokens.next() {
-            Some('+') => Some(Token::Plus),
-            Some('-') => Some(Token::Minus),
-            Some('*') => Some(Token::Multiply),
-            Some('/') => Some(Token::Divide),
-            Some('^') => Some(Token::Power),
-            Some('(') => Some(Token::LeftParen),
-            Some(')') => Some(Token::RightParen),
-            _ => None,
+    fn scan_operator(&mut self) -> Result<Token> {
+        let (start, c) = self.tokens.next().unwrap();
+        match c {
+            '=' if self.follows('=') => Ok(Token::Equal),
+            '!' if self.follows('=') => Ok(Token::NotEqual),
+            '<' if self.follows('=') => Ok(Token::LessEqual),
+            '>' if self.follows('=') => Ok(Token::GreaterEqual),
+            '&' if self.follows('&') => Ok(Token::And),
+            '|' if self.follows('|') => Ok(Token::Or),
+            '<' if self.follows('<') => Ok(Token::ShiftLeft),
+            '>' if self.follows('>') => Ok(Token::ShiftRight),
+            '*' if self.follows('*') => Ok(Token::Power),
+            '^' if self.int_mode => Ok(Token::BitXor),
+            '&' => Ok(Token::BitAnd),
+            '|' => Ok(Token::BitOr),
+            '~' => Ok(Token::BitNot),
+            '!' => Ok(Token::Not),
+            '<' => Ok(Token::Less),
+            '>' => Ok(Token::Greater),
+            '?' => Ok(Token::Question),
+            ':' => Ok(Token::Colon),
+            '+' => Ok(Token::Plus),
+            '-' => Ok(Token::Minus),
+            '*' => Ok(Token::Multiply),
+            '/' => Ok(Token::Divide),
+            '%' => Ok(Token::Modulo),
+            '^' => Ok(Token::Power),
+            '(' => Ok(Token::LeftParen),
+            ')' => Ok(Token::RightParen),
+            ',' => Ok(Token::Comma),
+            '=' => Ok(Token::Assign),
+            _ => {
+                let span = Span {
+                    start,
+                    end: start + c.len_utf8(),
+                };
+                Err(ExprError::parse(format!("Unexpected character '{}'", c), span))
+            }
         }
     }
 }
 
 
 // 实现 Iterator 接口，使 Tokenizer 可以通过 for 循环遍历
+// 每个 Token 都带有它在源码中的位置，遇到无法识别的字符时返回错误
 impl<'a> Iterator for Tokenizer<'a> {
-    type Item = Token;
+    type Item = Result<(Token, Span)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // 消除前面的空格
         self.consume_whitespace();
         // 解析当前位置的 Token 类型
-        match self.tokens.peek() {
-            Some(&c) if c.is_numeric() => self.scan_number(),
+        let start = self.position();
+        let token = match self.tokens.peek() {
+            Some((_, c)) if c.is_ascii_digit() || *c == '.' => self.scan_number(),
+            Some((_, c)) if c.is_ascii_alphabetic() || *c == '_' => self.scan_ident(),
             Some(_) => self.scan_operator(),
-            None => None,
-        }
+            None => return None,
+        };
+        let span = Span {
+            start,
+            end: self.position(),
+        };
+        Some(token.map(|token| (token, span)))
     }
 }
 
 pub struct Expr<'a> {
-    iter: Peekable<Tokenizer<'a>>,
+    input: &'a str,
+    tokens: Vec<(Token, Span)>,
+    pos: usize,
+    int_mode: Option<IntMode>,
 }
 
 impl<'a> Expr<'a> {
     pub fn new(input: &'a str) -> Self {
         Self {
-            iter: Tokenizer::new(input).peekable(),
+            input,
+            tokens: vec![],
+            pos: 0,
+            int_mode: None,
         }
     }
 
-    // 计算表达式，获取结果
-    pub fn eval(&mut self) -> Result<i32> {
-        let result = self.compute_expr(1)?;
+    // 按照整数模式解析，^ 表示按位异或，幂运算使用 **
+    // 编译得到的程序也按照指定的位宽和溢出处理方式计算
+    pub fn int_mode(mut self, int_mode: Option<IntMode>) -> Self {
+        self.int_mode = int_mode;
+        self
+    }
 
-        if self.iter.peek().is_some() {
-            return Err(ExprError::Parse("Unexpect end of expr".into()));
-        }
-        Ok(result)
+    // 计算表达式，获取结果，表达式中只能使用内置的常量和函数
+    pub fn eval(&mut self) -> Result<Value> {
+        self.parse()?.eval(&Environment::new())
+    }
+
+    // 解析表达式，得到语法树
+    pub fn parse(&mut self) -> Result<Ast> {
+        self.tokenize()?;
+        let ast = self.compute_expr(1)?;
+        self.expect_end()?;
+        Ok(ast)
     }
 
-    fn compute_atom(&mut self) -> Result<i32> {
-        match self.iter.peek() {
-            // 如果是数字的话，直接返回
-            Some(Token::Number(n)) => {
-                let val = *n;
-                self.iter.next();
-                Ok(val)
+    // 解析并编译表达式，得到可以重复计算的程序
+    pub fn compile(&mut self) -> Result<Program> {
+        Program::compile(&self.parse()?, self.int_mode)
+    }
+
+    // 解析一条语句
+    // 1. 变量名后面跟着等号的是赋值语句，例如 x = 3 * 4
+    // 2. 函数名和参数列表后面跟着等号的是函数定义，例如 f(x, y) = x ^ 2 + y
+    // 3. 其他的是表达式
+    pub fn parse_statement(&mut self) -> Result<Statement> {
+        self.tokenize()?;
+        let statement = match self.tokens.as_slice() {
+            [(Token::Ident, span), (Token::Assign, _), ..] => {
+                let name = self.text(*span).to_string();
+                self.pos = 2;
+                Statement::Assign(name, self.compute_expr(1)?)
             }
+            [(Token::Ident, span), (Token::LeftParen, _), ..] if self.is_definition() => {
+                let name = self.text(*span).to_string();
+                self.pos = 2;
+                let params = self.compute_params()?;
+                self.next();
+                Statement::Define(name, params, self.compute_expr(1)?)
+            }
+            _ => Statement::Expr(self.compute_expr(1)?),
+        };
+        self.expect_end()?;
+        Ok(statement)
+    }
 
-            // 如果是左括号的话，递归计算括号内的值
-            Some(Token::LeftParen) => {
-                self.iter.next();
-                let result = self.compute_expr(1)?;
-                if let Some(Token::RightParen) = self.iter.next() {
-                    Ok(result)
-                } else {
-                    Err(ExprError::Parse("Unexpected character".into()))
+    // 右括号后面跟着等号的是函数定义
+    fn is_definition(&self) -> bool {
+        let close = self.tokens.iter().position(|(token, _)| matches!(token, Token::RightParen));
+        close.is_some_and(|i| matches!(self.tokens.get(i + 1), Some((Token::Assign, _))))
+    }
+
+    // 解析函数定义的参数列表，参数名不能重复
+    fn compute_params(&mut self) -> Result<Vec<String>> {
+        let mut params: Vec<String> = vec![];
+        if matches!(self.peek(), Some((Token::RightParen, _))) {
+            self.next();
+            return Ok(params);
+        }
+        loop {
+            let Some((Token::Ident, span)) = self.peek() else {
+                return Err(ExprError::parse(
+                    format!("Expected a parameter name, found {}", self.describe()),
+                    self.span(),
+                ));
+            };
+            let name = self.text(span).to_string();
+            if params.contains(&name) {
+                return Err(ExprError::parse(format!("Duplicate parameter '{}'", name), span));
+            }
+            params.push(name);
+            self.next();
+
+            match self.peek() {
+                Some((Token::Comma, _)) => {
+                    self.next();
+                }
+                _ => {
+                    self.expect(Token::RightParen, ")")?;
+                    return Ok(params);
                 }
             }
+        }
+    }
 
-            _ => Err(ExprError::Parse("Expecting a number or left parenthesis".into()))
+    // Token 在源码中的文本
+    fn text(&self, span: Span) -> &'a str {
+        &self.input[span.start..span.end]
+    }
+
+    fn tokenize(&mut self) -> Result<()> {
+        self.tokens = Tokenizer::new(self.input, self.int_mode.is_some()).collect::<Result<_>>()?;
+        self.pos = 0;
+        Ok(())
+    }
+
+    // 所有的 Token 都已经解析完
+    fn expect_end(&self) -> Result<()> {
+        match self.peek() {
+            Some((_, span)) => Err(ExprError::parse(format!("Unexpected {}", self.describe()), span)),
+            None => Ok(()),
         }
     }
 
-    fn compute_expr(&mut self, min_precedence: i32) -> Result<i32> {
-        let mut atom_lhs = self.compute_atom()?;
+    fn peek(&self) -> Option<(Token, Span)> {
+        self.tokens.get(self.pos).copied()
+    }
 
-        loop {
-            if let Some(&token) = self.iter.peek() {
+    fn next(&mut self) -> Option<(Token, Span)> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    // 当前 Token 在源码中的文本，用于错误信息
+    fn describe(&self) -> String {
+        match self.peek() {
+            Some((_, span)) => format!("'{}'", &self.input[span.start..span.end]),
+            None => "end of input".into(),
+        }
+    }
+
+    // 下一个 Token 必须是指定的类型
+    fn expect(&mut self, expected: Token, symbol: &str) -> Result<()> {
+        match self.peek() {
+            Some((token, _)) if std::mem::discriminant(&token) == std::mem::discriminant(&expected) => {
+                self.next();
+                Ok(())
+            }
+            _ => Err(ExprError::parse(
+                format!("Expected '{}', found {}", symbol, self.describe()),
+                self.span(),
+            )),
+        }
+    }
 
-                // 1. Token 一定是运算符
-                // 2. Token 的优先级必须大于等于 min_precedence
-                if !token.is_operator() || token.precedence() < min_precedence {
-                    break;
+    // 当前 Token 的位置，输入结束时为末尾的空范围
+    fn span(&self) -> Span {
+        self.peek().map_or(
+            Span {
+                start: self.input.len(),
+                end: self.input.len(),
+            },
+            |(_, span)| span,
+        )
+    }
+
+    // 第 pos 个 Token 紧挨着前一个 Token，中间没有空格
+    fn joined(&self, pos: usize) -> bool {
+        match (pos.checked_sub(1).and_then(|i| self.tokens.get(i)), self.tokens.get(pos)) {
+            (Some((_, prev)), Some((_, span))) => prev.end == span.start,
+            _ => false,
+        }
+    }
+
+    // 第 pos 个 Token 是单位的名字
+    fn unit_at(&self, pos: usize) -> Option<Unit> {
+        match self.tokens.get(pos) {
+            Some((Token::Ident, span)) => Unit::named(self.text(*span)),
+            _ => None,
+        }
+    }
+
+    // 解析单位，例如 km、m/s^2、kg*m/s^2
+    // 复合单位中间不能有空格，2 m / s 表示 2 m 除以变量 s
+    fn compute_unit(&mut self) -> Result<Unit> {
+        let mut unit = self.compute_unit_factor()?;
+        while let Some((token @ (Token::Multiply | Token::Divide), _)) = self.peek() {
+            if !(self.joined(self.pos) && self.joined(self.pos + 1) && self.unit_at(self.pos + 1).is_some()) {
+                break;
+            }
+            self.next();
+            let factor = self.compute_unit_factor()?;
+            unit = match token {
+                Token::Multiply => unit.mul(factor)?,
+                _ => unit.mul(factor.pow(-1)?)?,
+            };
+        }
+        Ok(unit)
+    }
+
+    // 单位的名字，后面可以紧跟着整数指数，例如 s^2、s^-1
+    fn compute_unit_factor(&mut self) -> Result<Unit> {
+        let Some(unit) = self.unit_at(self.pos) else {
+            return Err(ExprError::parse(
+                format!("Expected a unit, found {}", self.describe()),
+                self.span(),
+            ));
+        };
+        self.next();
+        if !(matches!(self.peek(), Some((Token::Power, _))) && self.joined(self.pos)) {
+            return Ok(unit);
+        }
+
+        self.next();
+        let mut sign = 1;
+        if matches!(self.peek(), Some((Token::Minus, _))) && self.joined(self.pos) {
+            self.next();
+            sign = -1;
+        }
+        match self.peek() {
+            Some((Token::Number(Number::Int(exp)), _)) if self.joined(self.pos) => {
+                self.next();
+                unit.pow(sign * exp)
+            }
+            _ => Err(ExprError::parse(
+                format!("Expected an integer exponent, found {}", self.describe()),
+                self.span(),
+            )),
+        }
+    }
+
+    fn compute_atom(&mut self) -> Result<Ast> {
+        match self.peek() {
+            // 如果是数字的话，后面可以跟着单位，例如 3 km、9.81 m/s^2
+            Some((Token::Number(n), _)) => {
+                self.next();
+                if self.unit_at(self.pos).is_some() {
+                    return Ok(Ast::Quantity(n, self.compute_unit()?));
                 }
+                Ok(Ast::Num(n))
+            }
 
-                let mut next_precedence = token.precedence();
-                if token.assoc() == ASSOC_LEFT {
-                    next_precedence += 1;
+            // 如果是标识符的话，后面跟着左括号是函数调用，否则是变量
+            Some((Token::Ident, span)) => {
+                self.next();
+                let name = self.text(span).to_string();
+                match name.as_str() {
+                    "true" => return Ok(Ast::Bool(true)),
+                    "false" => return Ok(Ast::Bool(false)),
+                    _ => {}
+                }
+                if !matches!(self.peek(), Some((Token::LeftParen, _))) {
+                    return Ok(Ast::Var(name));
                 }
 
-                self.iter.next();
+                self.next();
+                let mut args = vec![];
+                if !matches!(self.peek(), Some((Token::RightParen, _))) {
+                    args.push(self.compute_expr(1)?);
+                    while let Some((Token::Comma, _)) = self.peek() {
+                        self.next();
+                        args.push(self.compute_expr(1)?);
+                    }
+                }
+                self.expect(Token::RightParen, ")")?;
+                Ok(Ast::Call(name, args))
+            }
 
-                // 递归计算右边的表达式
-                let atom_rhs = self.compute_expr(next_precedence)?;
+            // 如果是正负号、逻辑非或者按位取反的话，解析后面的表达式
+            // 一元运算的优先级比幂运算低，-2^2 = -(2^2)，但是可以出现在指数中，2^-1 = 2^(-1)
+            Some((sign @ (Token::Plus | Token::Minus | Token::Not | Token::BitNot), _)) => {
+                self.next();
+                let operand = self.compute_expr(BinOp::Pow.precedence())?;
+                let op = match sign {
+                    Token::Minus => UnaryOp::Neg,
+                    Token::Not => UnaryOp::Not,
+                    Token::BitNot => UnaryOp::BitNot,
+                    _ => UnaryOp::Plus,
+                };
+                Ok(Ast::UnaryOp(op, Box::new(operand)))
+            }
 
-                // 得到了两边的值，进行计算
-                match token.computer(atom_lhs, atom_rhs) {
-                    Some(val) => atom_lhs = val,
-                    None => return Err(ExprError::Parse("Unexpected expr".into())),
-                }
-            } else {
+            // 如果是左括号的话，递归解析括号内的表达式
+            Some((Token::LeftParen, _)) => {
+                self.next();
+                let ast = self.compute_expr(1)?;
+                self.expect(Token::RightParen, ")")?;
+                Ok(ast)
+            }
+
+            _ => Err(ExprError::parse(
+                format!("Expected a number or '(', found {}", self.describe()),
+                self.span(),
+            )),
+        }
+    }
+
+    // 使用优先级爬升法解析二元运算
+    fn compute_expr(&mut self, min_precedence: i32) -> Result<Ast> {
+        let mut atom_lhs = self.compute_atom()?;
+
+        while let Some((token, _)) = self.peek() {
+            // 1. Token 一定是运算符
+            // 2. Token 的优先级必须大于等于 min_precedence
+            let Some(op) = token.bin_op() else {
+                break;
+            };
+            if op.precedence() < min_precedence {
                 break;
             }
+
+            let mut next_precedence = op.precedence();
+            if op.assoc() == ASSOC_LEFT {
+                next_precedence += 1;
+            }
+
+            self.next();
+
+            // 递归解析右边的表达式
+            let atom_rhs = self.compute_expr(next_precedence)?;
+            atom_lhs = Ast::BinOp(op, Box::new(atom_lhs), Box::new(atom_rhs));
+        }
+
+        // 条件表达式的优先级最低，中间的分支可以是任意表达式，右边的分支右结合
+        // a ? b : c ? d : e = a ? b : (c ? d : e)
+        if min_precedence <= TERNARY_PRECEDENCE && matches!(self.peek(), Some((Token::Question, _))) {
+            self.next();
+            let then = self.compute_expr(TERNARY_PRECEDENCE)?;
+            self.expect(Token::Colon, ":")?;
+            let otherwise = self.compute_expr(TERNARY_PRECEDENCE)?;
+            atom_lhs = Ast::Cond(Box::new(atom_lhs), Box::new(then), Box::new(otherwise));
+        }
+
+        // 单位换算的优先级最低，3 km + 200 m in mm = (3 km + 200 m) in mm
+        while min_precedence <= TERNARY_PRECEDENCE
+            && matches!(self.peek(), Some((Token::Ident, span)) if self.text(span) == "in")
+        {
+            self.next();
+            atom_lhs = Ast::Convert(Box::new(atom_lhs), self.compute_unit()?);
         }
 
         Ok(atom_lhs)
@@ -254,6 +760,102 @@ mod tests {
     fn test_eval() {
         let input = "92 + 5 + 5 * 27 - (92 - 12) / 4 + 26";
         let mut expr = Expr::new(input);
-        assert_eq!(expr.eval(), Ok(238));
+        assert_eq!(expr.eval(), Ok(Number::Int(238).into()));
+    }
+
+    fn eval(input: &str) -> Number {
+        Expr::new(input).eval().unwrap().as_number().unwrap()
+    }
+
+    #[test]
+    fn test_unary() {
+        assert_eq!(eval("-3 + 2"), Number::Int(-1));
+        assert_eq!(eval("2 * -4"), Number::Int(-8));
+        assert_eq!(eval("--2 - +3"), Number::Int(-1));
+        assert_eq!(eval("-2 ^ 2"), Number::Int(-4));
+        assert_eq!(eval("(-2) ^ 2"), Number::Int(4));
+        assert_eq!(eval("2 ^ -1"), Number::Float(0.5));
+        assert_eq!(eval("2 ^ -1 ^ 2"), Number::Float(0.5));
+    }
+
+    #[test]
+    fn test_float() {
+        assert_eq!(eval("3.5 / 2"), Number::Float(1.75));
+        assert_eq!(eval("7 / 2"), Number::Float(3.5));
+        assert_eq!(eval(".5 + 1.25e1"), Number::Float(13.0));
+        assert_eq!(eval("2.5E-1 * 4"), Number::Float(1.0));
+        assert_eq!(eval("1e3").to_string(), "1000");
+        assert_eq!(eval("0.1 + 0.2").to_string(), "0.30000000000000004");
+    }
+
+    #[test]
+    fn test_modulo() {
+        assert_eq!(eval("10 % 3"), Number::Int(1));
+        assert_eq!(eval("-10 % 3"), Number::Int(-1));
+        assert_eq!(eval("5.5 % 2"), Number::Float(1.5));
+        assert_eq!(eval("2 + 10 % 4 * 3"), Number::Int(8));
+    }
+
+    #[test]
+    fn test_int_float_agree() {
+        // 同一个表达式用整数和浮点数计算，结果相等
+        for (int, float) in [
+            ("92 + 5 * 27 - (92 - 12) / 4", "92.0 + 5.0 * 27.0 - (92.0 - 12.0) / 4.0"),
+            ("7 / 2 - 10 % 4", "7.0 / 2.0 - 10.0 % 4.0"),
+            ("-3 ^ 3 + 2 ^ -2", "-3.0 ^ 3.0 + 2.0 ^ -2.0"),
+            ("2 ^ 61 + 2 ^ 61 - 1", "2.0 ^ 61.0 + 2.0 ^ 61.0 - 1.0"),
+        ] {
+            assert_eq!(eval(int), eval(float), "{} != {}", int, float);
+        }
+        assert!(matches!(eval("6 / 3"), Number::Int(2)));
+        assert!(matches!(eval("6.0 / 3"), Number::Float(_)));
+    }
+
+    #[test]
+    fn test_math_errors() {
+        let eval = |input| Expr::new(input).eval()?.as_number();
+        assert_eq!(eval("1 / 0"), Err(ExprError::DivisionByZero));
+        assert_eq!(eval("1.5 / (2 - 2.0)"), Err(ExprError::DivisionByZero));
+        assert_eq!(eval("10 % 0"), Err(ExprError::DivisionByZero));
+        assert_eq!(eval("9223372036854775807 + 1"), Err(ExprError::Overflow));
+        assert_eq!(eval("-9223372036854775807 - 2"), Err(ExprError::Overflow));
+        assert_eq!(eval("-(-9223372036854775807 - 1)"), Err(ExprError::Overflow));
+        assert_eq!(eval("(-9223372036854775807 - 1) / -1"), Err(ExprError::Overflow));
+        assert_eq!(eval("3037000500 * 3037000500"), Err(ExprError::Overflow));
+        assert_eq!(eval("2 ^ 63"), Err(ExprError::Overflow));
+        assert_eq!(eval("2 ^ 4294967296"), Err(ExprError::Overflow));
+        assert_eq!(eval("1e308 * 10"), Err(ExprError::Overflow));
+        assert_eq!(eval("0 ^ -1"), Err(ExprError::InvalidExponent));
+        assert_eq!(eval("(-8) ^ 0.5"), Err(ExprError::InvalidExponent));
+
+        assert_eq!(eval("2 ^ -1"), Ok(Number::Float(0.5)));
+        assert_eq!(eval("(-8) ^ 3"), Ok(Number::Int(-512)));
+        assert_eq!(eval("(-1) ^ 4294967297"), Ok(Number::Int(-1)));
+        assert_eq!(eval("(-9223372036854775807 - 1) % -1"), Ok(Number::Int(0)));
+        assert_eq!(eval("1 / 0").unwrap_err().to_string(), "Division by zero");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |input| Expr::new(input).eval().unwrap_err();
+        let span = |start, end| Span { start, end };
+        assert_eq!(error("2 $ 3"), ExprError::parse("Unexpected character '$'", span(2, 3)));
+        assert_eq!(error("1 + é"), ExprError::parse("Unexpected character 'é'", span(4, 6)));
+        assert_eq!(error("1 + ."), ExprError::parse("Invalid number '.'", span(4, 5)));
+        assert_eq!(error("(1 + 2"), ExprError::parse("Expected ')', found end of input", span(6, 6)));
+        assert_eq!(error("(1 + 2 3)"), ExprError::parse("Expected ')', found '3'", span(7, 8)));
+        assert_eq!(error("1 * )"), ExprError::parse("Expected a number or '(', found ')'", span(4, 5)));
+        assert_eq!(error("1 + 2)"), ExprError::parse("Unexpected ')'", span(5, 6)));
+        assert_eq!(error("2 $ 3").to_string(), "Unexpected character '$' at position 2");
+    }
+
+    #[test]
+    fn test_diagnostic() {
+        let render = |input| Expr::new(input).eval().unwrap_err().diagnostic(input).to_string();
+        assert_eq!(render("2 $ 3"), "2 $ 3\n  ^ Unexpected character '$'");
+        assert_eq!(render("(1 + 2"), "(1 + 2\n      ^ Expected ')', found end of input");
+        assert_eq!(render("12.5.3 + 1"), "12.5.3 + 1\n    ^^ Unexpected '.3'");
+        assert_eq!(render("1 + é + 2"), "1 + é + 2\n    ^ Unexpected character 'é'");
+        assert_eq!(render("1 / 0"), "Division by zero");
     }
 }
@@ -0,0 +1,156 @@
+use crate::{ExprError, Result};
+use std::fmt::Display;
+
+// 计算器中的数字，整数和浮点数
+// 整数运算的结果不能用整数表示时（除不尽、负指数），自动转换为浮点数，
+// 保证同一个表达式用整数和浮点数计算的结果一致
+// 所有运算都会检查溢出、除零等错误
+#[derive(Debug, Clone, Copy)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    // 转换为浮点数
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            Number::Int(n) => n as f64,
+            Number::Float(n) => n,
+        }
+    }
+
+    // 转换为整数，没有小数部分的浮点数也可以转换，用于位运算
+    pub fn as_int(&self) -> Result<i64> {
+        match *self {
+            Number::Int(n) => Ok(n),
+            Number::Float(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 => Ok(n as i64),
+            Number::Float(_) => Err(ExprError::TypeMismatch {
+                expected: "integer",
+                found: "number",
+            }),
+        }
+    }
+
+    pub fn checked_neg(self) -> Result<Number> {
+        match self {
+            Number::Int(n) => n.checked_neg().map(Number::Int).ok_or(ExprError::Overflow),
+            Number::Float(n) => Ok(Number::Float(-n)),
+        }
+    }
+
+    pub fn checked_add(self, rhs: Number) -> Result<Number> {
+        match (self, rhs) {
+            (Number::Int(l), Number::Int(r)) => l.checked_add(r).map(Number::Int).ok_or(ExprError::Overflow),
+            _ => self.float(rhs, |l, r| l + r),
+        }
+    }
+
+    pub fn checked_sub(self, rhs: Number) -> Result<Number> {
+        match (self, rhs) {
+            (Number::Int(l), Number::Int(r)) => l.checked_sub(r).map(Number::Int).ok_or(ExprError::Overflow),
+            _ => self.float(rhs, |l, r| l - r),
+        }
+    }
+
+    pub fn checked_mul(self, rhs: Number) -> Result<Number> {
+        match (self, rhs) {
+            (Number::Int(l), Number::Int(r)) => l.checked_mul(r).map(Number::Int).ok_or(ExprError::Overflow),
+            _ => self.float(rhs, |l, r| l * r),
+        }
+    }
+
+    // 整数能整除时结果仍然是整数
+    pub fn checked_div(self, rhs: Number) -> Result<Number> {
+        if rhs.is_zero() {
+            return Err(ExprError::DivisionByZero);
+        }
+        match (self, rhs) {
+            // i64::MIN % -1 会 panic，使用 wrapping_rem 判断能否整除，溢出由 checked_div 返回错误
+            (Number::Int(l), Number::Int(r)) if l.wrapping_rem(r) == 0 => {
+                l.checked_div(r).map(Number::Int).ok_or(ExprError::Overflow)
+            }
+            _ => self.float(rhs, |l, r| l / r),
+        }
+    }
+
+    // 取余，结果的符号和被除数相同
+    pub fn checked_rem(self, rhs: Number) -> Result<Number> {
+        if rhs.is_zero() {
+            return Err(ExprError::DivisionByZero);
+        }
+        match (self, rhs) {
+            // i64::MIN % -1 的结果是 0，但是 checked_rem 会认为溢出
+            (Number::Int(l), Number::Int(r)) => Ok(Number::Int(l.wrapping_rem(r))),
+            _ => self.float(rhs, |l, r| l % r),
+        }
+    }
+
+    // 幂运算
+    // 1. 0 的负数次幂没有意义
+    // 2. 负数的小数次幂不是实数
+    // 3. 整数的负数次幂是小数，按照浮点数计算
+    pub fn checked_pow(self, rhs: Number) -> Result<Number> {
+        if self.is_zero() && rhs.as_f64() < 0.0 {
+            return Err(ExprError::InvalidExponent);
+        }
+        if self.as_f64() < 0.0 && rhs.as_f64().fract() != 0.0 {
+            return Err(ExprError::InvalidExponent);
+        }
+        match (self, rhs) {
+            (Number::Int(l), Number::Int(r)) if r >= 0 => match (l, u32::try_from(r)) {
+                (_, Ok(r)) => l.checked_pow(r).map(Number::Int).ok_or(ExprError::Overflow),
+                // 指数超出 u32 范围时，只有 0、1、-1 不会溢出
+                (0 | 1, Err(_)) => Ok(Number::Int(l)),
+                (-1, Err(_)) => Ok(Number::Int(if r % 2 == 0 { 1 } else { -1 })),
+                _ => Err(ExprError::Overflow),
+            },
+            _ => self.float(rhs, f64::powf),
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.as_f64() == 0.0
+    }
+
+    // 转换为浮点数计算，有限的数计算出无穷大说明溢出了
+    fn float(self, rhs: Number, op: impl Fn(f64, f64) -> f64) -> Result<Number> {
+        let (l, r) = (self.as_f64(), rhs.as_f64());
+        let result = op(l, r);
+        if result.is_infinite() && l.is_finite() && r.is_finite() {
+            return Err(ExprError::Overflow);
+        }
+        Ok(Number::Float(result))
+    }
+}
+
+impl From<i64> for Number {
+    fn from(n: i64) -> Self {
+        Number::Int(n)
+    }
+}
+
+impl From<f64> for Number {
+    fn from(n: f64) -> Self {
+        Number::Float(n)
+    }
+}
+
+// 按照数值比较，整数和值相等的浮点数相等
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Number::Int(l), Number::Int(r)) => l == r,
+            _ => self.as_f64() == other.as_f64(),
+        }
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Number::Int(n) => write!(f, "{}", n),
+            Number::Float(n) => write!(f, "{}", n),
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{BinOp, ExprError, Number, Result, Value};
+use std::fmt::Display;
+
+// 量纲：长度、质量、时间的指数
+type Dims = [i32; 3];
+
+// 支持的单位，名字、量纲以及换算为国际单位制基本单位（m、kg、s）的比例（分子，分母）
+// 比例使用整数表示，换算时整数仍然可以保持精确
+const UNITS: &[(&str, Dims, (i64, i64))] = &[
+    // 长度
+    ("m", [1, 0, 0], (1, 1)),
+    ("km", [1, 0, 0], (1000, 1)),
+    ("cm", [1, 0, 0], (1, 100)),
+    ("mm", [1, 0, 0], (1, 1000)),
+    ("inch", [1, 0, 0], (254, 10000)),
+    ("ft", [1, 0, 0], (3048, 10000)),
+    ("mi", [1, 0, 0], (1609344, 1000)),
+    // 质量
+    ("kg", [0, 1, 0], (1, 1)),
+    ("g", [0, 1, 0], (1, 1000)),
+    ("t", [0, 1, 0], (1000, 1)),
+    ("lb", [0, 1, 0], (45359237, 100000000)),
+    // 时间
+    ("s", [0, 0, 1], (1, 1)),
+    ("ms", [0, 0, 1], (1, 1000)),
+    ("min", [0, 0, 1], (60, 1)),
+    ("h", [0, 0, 1], (3600, 1)),
+    // 导出单位
+    ("Hz", [0, 0, -1], (1, 1)),
+    ("N", [1, 1, -2], (1, 1)),
+    ("J", [2, 1, -2], (1, 1)),
+    ("W", [2, 1, -3], (1, 1)),
+];
+
+// 复合单位最多由几个单位组成
+const MAX_FACTORS: usize = 4;
+
+// 单位，由若干个单位的整数次幂相乘得到，例如 m/s^2 = m * s^-2
+// 使用固定大小的数组保存，这样带单位的值仍然可以 Copy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    // 单位在 UNITS 中的位置和指数，指数不为 0
+    factors: [(u8, i8); MAX_FACTORS],
+    len: usize,
+}
+
+impl Unit {
+    // 没有单位
+    const NONE: Unit = Unit {
+        factors: [(0, 0); MAX_FACTORS],
+        len: 0,
+    };
+
+    // 根据名字查找单位
+    pub(crate) fn named(name: &str) -> Option<Unit> {
+        let index = UNITS.iter().position(|(unit, _, _)| *unit == name)?;
+        let mut factors = Unit::NONE.factors;
+        factors[0] = (index as u8, 1);
+        Some(Unit { factors, len: 1 })
+    }
+
+    fn factors(&self) -> &[(u8, i8)] {
+        &self.factors[..self.len]
+    }
+
+    // 两个单位相乘，相同单位的指数相加，例如 m * m/s = m^2/s
+    pub(crate) fn mul(self, rhs: Unit) -> Result<Unit> {
+        let mut unit = self;
+        for &(index, exp) in rhs.factors() {
+            match unit.factors().iter().position(|(i, _)| *i == index) {
+                Some(pos) => {
+                    let exp = unit.factors[pos].1.checked_add(exp).ok_or(ExprError::Overflow)?;
+                    if exp == 0 {
+                        unit.factors.copy_within(pos + 1..unit.len, pos);
+                        unit.len -= 1;
+                        unit.factors[unit.len] = (0, 0);
+                    } else {
+                        unit.factors[pos].1 = exp;
+                    }
+                }
+                None if unit.len < MAX_FACTORS => {
+                    unit.factors[unit.len] = (index, exp);
+                    unit.len += 1;
+                }
+                None => return Err(ExprError::Overflow),
+            }
+        }
+        Ok(unit)
+    }
+
+    // 单位的整数次幂，例如 (m/s)^2 = m^2/s^2
+    pub(crate) fn pow(self, exp: i64) -> Result<Unit> {
+        if exp == 0 {
+            return Ok(Unit::NONE);
+        }
+        let mut unit = self;
+        for factor in &mut unit.factors[..unit.len] {
+            factor.1 = i64::from(factor.1)
+                .checked_mul(exp)
+                .and_then(|exp| i8::try_from(exp).ok())
+                .ok_or(ExprError::Overflow)?;
+        }
+        Ok(unit)
+    }
+
+    fn dims(&self) -> Dims {
+        let mut dims = [0; 3];
+        for &(index, exp) in self.factors() {
+            for (dim, unit_dim) in dims.iter_mut().zip(UNITS[index as usize].1) {
+                *dim += unit_dim * i32::from(exp);
+            }
+        }
+        dims
+    }
+
+    // 换算为基本单位的比例（分子，分母），已经约分
+    fn scale(&self) -> Result<(i128, i128)> {
+        let (mut num, mut den) = (1i128, 1i128);
+        for &(index, exp) in self.factors() {
+            let (n, d) = UNITS[index as usize].2;
+            let (n, d) = if exp > 0 { (n, d) } else { (d, n) };
+            for _ in 0..exp.unsigned_abs() {
+                num = num.checked_mul(i128::from(n)).ok_or(ExprError::Overflow)?;
+                den = den.checked_mul(i128::from(d)).ok_or(ExprError::Overflow)?;
+                let g = gcd(num, den);
+                num /= g;
+                den /= g;
+            }
+        }
+        Ok((num, den))
+    }
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// 输出单位，例如 kg*m/s^2，只有负指数时输出 1/s
+impl Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = |index: u8| UNITS[index as usize].0;
+        let mut first = true;
+        for &(index, exp) in self.factors().iter().filter(|(_, exp)| *exp > 0) {
+            if !first {
+                write!(f, "*")?;
+            }
+            first = false;
+            write!(f, "{}", name(index))?;
+            if exp != 1 {
+                write!(f, "^{}", exp)?;
+            }
+        }
+        if first {
+            write!(f, "1")?;
+        }
+        for &(index, exp) in self.factors().iter().filter(|(_, exp)| *exp < 0) {
+            write!(f, "/{}", name(index))?;
+            if exp != -1 {
+                write!(f, "^{}", -exp)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// 把数值从一个单位换算到另一个单位，两个单位的量纲必须相同
+fn rescale(n: Number, from: Unit, to: Unit) -> Result<Number> {
+    if from.dims() != to.dims() {
+        return Err(ExprError::IncompatibleUnits(from.to_string(), to.to_string()));
+    }
+    let ((from_num, from_den), (to_num, to_den)) = (from.scale()?, to.scale()?);
+    let num = from_num.checked_mul(to_den).ok_or(ExprError::Overflow)?;
+    let den = from_den.checked_mul(to_num).ok_or(ExprError::Overflow)?;
+    if num == den {
+        return Ok(n);
+    }
+    let g = gcd(num, den);
+    let int = |v: i128| i64::try_from(v / g).map(Number::Int).map_err(|_| ExprError::Overflow);
+    n.checked_mul(int(num)?)?.checked_div(int(den)?)
+}
+
+// 带单位的值，量纲为 0 时换算为普通的数字，例如 3 km / 1 m = 3000
+pub(crate) fn quantity(n: Number, unit: Unit) -> Result<Value> {
+    if unit.dims() == [0; 3] {
+        return Ok(Value::Num(rescale(n, unit, Unit::NONE)?));
+    }
+    Ok(Value::Quantity(n, unit))
+}
+
+// 数值和单位，数字看作没有单位
+fn split(value: Value) -> Result<(Number, Unit)> {
+    match value {
+        Value::Quantity(n, unit) => Ok((n, unit)),
+        _ => Ok((value.as_number()?, Unit::NONE)),
+    }
+}
+
+// 换算到指定的单位，例如 3 km in mm = 3000000 mm
+pub(crate) fn convert(value: Value, unit: Unit) -> Result<Value> {
+    let (n, from) = split(value)?;
+    Ok(Value::Quantity(rescale(n, from, unit)?, unit))
+}
+
+// 带单位的算术运算和比较
+// 加减、取余和比较时右边换算为左边的单位，乘除时单位相乘除，幂运算的指数必须是整数
+pub(crate) fn computer(op: BinOp, l: Value, r: Value) -> Result<Value> {
+    let (l, l_unit) = split(l)?;
+    match op {
+        BinOp::Mul => {
+            let (r, r_unit) = split(r)?;
+            quantity(l.checked_mul(r)?, l_unit.mul(r_unit)?)
+        }
+        BinOp::Div => {
+            let (r, r_unit) = split(r)?;
+            quantity(l.checked_div(r)?, l_unit.mul(r_unit.pow(-1)?)?)
+        }
+        BinOp::Pow => match r.as_number()? {
+            Number::Int(exp) => quantity(l.checked_pow(Number::Int(exp))?, l_unit.pow(exp)?),
+            Number::Float(_) => Err(ExprError::InvalidExponent),
+        },
+        _ => {
+            let (r, r_unit) = split(r)?;
+            let r = rescale(r, r_unit, l_unit)?;
+            match op.computer(Value::Num(l), Value::Num(r))? {
+                Value::Num(n) => quantity(n, l_unit),
+                value => Ok(value),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn eval(input: &str) -> Result<String> {
+        Environment::new().execute(input).map(|v| v.unwrap().to_string())
+    }
+
+    #[test]
+    fn test_units() {
+        for (input, output) in [
+            ("3 km + 200 m", "3.2 km"),
+            ("200 m + 3 km", "3200 m"),
+            ("3 km + 200 m in mm", "3200000 mm"),
+            ("5 kg * 9.81 m/s^2", "49.050000000000004 kg*m/s^2"),
+            ("5 kg * 9.81 m/s^2 in N", "49.050000000000004 N"),
+            ("90 km/h in m/s", "25 m/s"),
+            ("3 km / 1 m", "3000"),
+            ("(2 m) ^ 2", "4 m^2"),
+            ("2 m^2 * 3 m", "6 m^3"),
+            ("-(1 h) in min", "-60 min"),
+            ("1 / 4 s in Hz", "0.25 Hz"),
+            ("12 inch in cm", "30.48 cm"),
+            ("2 * 3 m - 50 cm", "5.5 m"),
+            ("1 km > 999 m", "true"),
+            ("60 s == 1 min", "true"),
+            ("2 min(3, 4)", "Unexpected '(' at position 5"),
+        ] {
+            let result = eval(input).unwrap_or_else(|e| e.to_string());
+            assert_eq!(result, output, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_unit_errors() {
+        let incompatible = |a: &str, b: &str| Err(ExprError::IncompatibleUnits(a.into(), b.into()));
+        assert_eq!(eval("3 km + 2 s"), incompatible("s", "km"));
+        assert_eq!(eval("3 km + 2"), incompatible("1", "km"));
+        assert_eq!(eval("3 kg in m"), incompatible("kg", "m"));
+        assert_eq!(eval("2 m < 3 s"), incompatible("s", "m"));
+        assert_eq!(eval("(4 m) ^ 0.5"), Err(ExprError::InvalidExponent));
+        assert_eq!(
+            eval("sqrt(4 m)"),
+            Err(ExprError::TypeMismatch {
+                expected: "number",
+                found: "quantity"
+            })
+        );
+        assert_eq!(
+            eval("3 m && true"),
+            Err(ExprError::TypeMismatch {
+                expected: "boolean",
+                found: "quantity"
+            })
+        );
+        assert!(matches!(eval("3 parsec"), Err(ExprError::Parse { .. })));
+        assert!(matches!(eval("3 km in"), Err(ExprError::Parse { .. })));
+    }
+
+    #[test]
+    fn test_unit_variables() {
+        let mut env = Environment::new();
+        env.execute("d = 42.195 km").unwrap();
+        env.execute("speed(t) = d / t in km/h").unwrap();
+        assert_eq!(env.execute("speed(2 h + 30 min)").unwrap().unwrap().to_string(), "16.878 km/h");
+        // 复合单位中间没有空格，有空格时是除以变量
+        env.execute("s = 2").unwrap();
+        assert_eq!(env.execute("10 m / s").unwrap().unwrap().to_string(), "5 m");
+        assert_eq!(env.execute("10 m/s").unwrap().unwrap().to_string(), "10 m/s");
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{ExprError, Number, Result, Unit};
+use std::fmt::Display;
+
+// 表达式的值，数字、带单位的数字或者布尔值
+// 不同的类型不会自动转换，类型不对时返回错误
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Num(Number),
+    Bool(bool),
+    // 数值和单位，例如 3 km
+    Quantity(Number, Unit),
+}
+
+impl Value {
+    // 类型的名字，用于错误信息
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Num(_) => "number",
+            Value::Bool(_) => "boolean",
+            Value::Quantity(..) => "quantity",
+        }
+    }
+
+    pub fn as_number(&self) -> Result<Number> {
+        match self {
+            Value::Num(n) => Ok(*n),
+            _ => Err(self.mismatch("number")),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(self.mismatch("boolean")),
+        }
+    }
+
+    fn mismatch(&self, expected: &'static str) -> ExprError {
+        ExprError::TypeMismatch {
+            expected,
+            found: self.type_name(),
+        }
+    }
+}
+
+impl From<Number> for Value {
+    fn from(n: Number) -> Self {
+        Value::Num(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Quantity(n, unit) => write!(f, "{} {}", n, unit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn eval(input: &str) -> Result<Value> {
+        let mut env = Environment::new();
+        env.execute("price = 40").unwrap();
+        env.execute("qty = 3").unwrap();
+        env.execute("region = 2").unwrap();
+        env.execute(input).map(Option::unwrap)
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(eval("1 + 2 == 3"), Ok(Value::Bool(true)));
+        assert_eq!(eval("2 == 2.0"), Ok(Value::Bool(true)));
+        assert_eq!(eval("7 / 2 != 3.5"), Ok(Value::Bool(false)));
+        assert_eq!(eval("-1 < 0 == 2 >= 3"), Ok(Value::Bool(false)));
+        assert_eq!(eval("true == (1 <= 1)"), Ok(Value::Bool(true)));
+        assert_eq!(eval("2 ^ 3 > 7"), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn test_logic() {
+        assert_eq!(eval("true || false && false"), Ok(Value::Bool(true)));
+        assert_eq!(eval("(true || false) && false"), Ok(Value::Bool(false)));
+        assert_eq!(eval("!true || !(1 > 2)"), Ok(Value::Bool(true)));
+        assert_eq!(eval("!!true"), Ok(Value::Bool(true)));
+
+        // 短路求值，右边不会计算
+        assert_eq!(eval("false && 1 / 0 == 1"), Ok(Value::Bool(false)));
+        assert_eq!(eval("true || undefined"), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn test_ternary() {
+        let rule = "price * qty > 100 && region == 2 ? 0.9 : 1.0";
+        assert_eq!(eval(rule), Ok(Number::Float(0.9).into()));
+        assert_eq!(eval("price * qty > 200 ? 1 : region == 2 ? 2 : 3"), Ok(Number::Int(2).into()));
+        assert_eq!(eval("(true ? false : true) ? 1 : 2"), Ok(Number::Int(2).into()));
+        assert_eq!(eval("1 + (qty > 2 ? 10 : 20) * 2"), Ok(Number::Int(21).into()));
+        assert_eq!(eval("true ? 1 : 1 / 0"), Ok(Number::Int(1).into()));
+
+        // 递归函数可以用条件表达式终止
+        let mut env = Environment::new();
+        env.execute("fact(n) = n <= 1 ? 1 : n * fact(n - 1)").unwrap();
+        assert_eq!(env.execute("fact(20)"), Ok(Some(Number::Int(2432902008176640000).into())));
+    }
+
+    #[test]
+    fn test_type_errors() {
+        let mismatch = |expected, found| ExprError::TypeMismatch { expected, found };
+        assert_eq!(eval("1 + true"), Err(mismatch("number", "boolean")));
+        assert_eq!(eval("1 && true"), Err(mismatch("boolean", "number")));
+        assert_eq!(eval("!1"), Err(mismatch("boolean", "number")));
+        assert_eq!(eval("-true"), Err(mismatch("number", "boolean")));
+        assert_eq!(eval("1 == true"), Err(mismatch("number", "boolean")));
+        assert_eq!(eval("1 < 2 < 3"), Err(mismatch("number", "boolean")));
+        assert_eq!(eval("qty ? 1 : 2"), Err(mismatch("boolean", "number")));
+        assert_eq!(eval("sqrt(true)"), Err(mismatch("number", "boolean")));
+        assert_eq!(eval("1 + true").unwrap_err().to_string(), "Expected a number, found a boolean");
+
+        // 布尔值可以保存在变量中，也可以作为用户函数的参数
+        let mut env = Environment::new();
+        env.execute("flag = 3 > 2").unwrap();
+        env.execute("pick(c, a, b) = c ? a : b").unwrap();
+        assert_eq!(env.execute("pick(flag, 1, 2)"), Ok(Some(Number::Int(1).into())));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |input| Expr::new(input).parse().unwrap_err().to_string();
+        assert_eq!(error("true ? 1"), "Expected ':', found end of input at position 8");
+        assert_eq!(error("1 # 2"), "Unexpected character '#' at position 2");
+        assert_eq!(error("1 = 2"), "Unexpected '=' at position 2");
+    }
+}
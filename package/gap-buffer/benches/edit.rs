@@ -0,0 +1,98 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use gap_buffer::{GapBuffer, Rope};
+
+// 文档的长度，大约相当于一个几 MB 的日志文件
+const LEN: usize = 1 << 20;
+
+fn document() -> impl Iterator<Item = char> {
+    "2024-01-01 12:00:00 INFO request handled in 3ms\n".chars().cycle().take(LEN)
+}
+
+// 伪随机的位置，两种数据结构使用相同的序列
+fn positions(count: usize) -> Vec<usize> {
+    let mut seed = 1u64;
+    (0..count)
+        .map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % LEN
+        })
+        .collect()
+}
+
+// 两种数据结构的编辑接口相同，用宏生成相同的测试代码
+macro_rules! bench_both {
+    ($group:expr, $name:expr, |$buf:ident| $body:expr) => {{
+        #[allow(unused_mut)]
+        let mut $buf: GapBuffer<char> = document().collect();
+        $group.bench_function(BenchmarkId::new($name, "GapBuffer"), |b| b.iter(|| $body));
+        #[allow(unused_mut)]
+        let mut $buf: Rope<char> = document().collect();
+        $group.bench_function(BenchmarkId::new($name, "Rope"), |b| b.iter(|| $body));
+    }};
+}
+
+// 在同一个位置连续输入再删除，间隙缓冲区最擅长的情况
+fn bench_typing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("typing");
+    bench_both!(group, "insert_delete_100", |buf| {
+        buf.set_position(LEN / 2).unwrap();
+        for c in "hello world ".chars().cycle().take(100) {
+            buf.insert(c);
+        }
+        for _ in 0..100 {
+            black_box(buf.delete_backward().unwrap());
+        }
+    });
+    group.finish();
+}
+
+// 在文档中的不同位置之间跳转并编辑，例如多光标编辑或者查找替换
+fn bench_jumping(c: &mut Criterion) {
+    let mut group = c.benchmark_group("jumping");
+    let positions = positions(100);
+    bench_both!(group, "edit_at_100_positions", |buf| {
+        for &pos in &positions {
+            buf.set_position(pos).unwrap();
+            buf.insert('x');
+            black_box(buf.delete_backward().unwrap());
+        }
+    });
+    // 替换成不同长度的内容再换回来，文档的长度不变
+    // 长度相同时间隙一直是空的，移动间隙不需要复制，不能代表一般的情况
+    bench_both!(group, "replace_range", |buf| {
+        for &pos in &positions {
+            let pos = pos.min(LEN - 4);
+            buf.replace_range(pos..pos + 4, "ERROR".chars()).unwrap();
+            buf.replace_range(pos..pos + 5, "INFO".chars()).unwrap();
+        }
+    });
+    group.finish();
+}
+
+// 按下标随机访问
+fn bench_index(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index");
+    let positions = positions(1000);
+    bench_both!(group, "get_1000", |buf| {
+        for &pos in &positions {
+            black_box(buf.get(pos));
+        }
+    });
+    group.finish();
+}
+
+// 保存快照后继续编辑，Rope 只需要复制被修改的路径
+fn bench_snapshot(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot");
+    bench_both!(group, "clone_and_edit", |buf| {
+        let snapshot = buf.clone();
+        buf.set_position(LEN / 2).unwrap();
+        buf.insert('x');
+        black_box(buf.delete_backward().unwrap());
+        snapshot
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_typing, bench_jumping, bench_index, bench_snapshot);
+criterion_main!(benches);
@@ -1,10 +1,74 @@
-use std::ops::Range;
+use std::fmt::{Debug, Display};
+use std::iter::Chain;
+use std::ops::{Index, IndexMut, Range};
+use std::slice;
+
+mod rope;
+mod text;
+mod undo;
+
+pub use rope::{Iter as RopeIter, Rope};
+pub use text::{Lines, TextBuffer};
+pub use undo::UndoBuffer;
+
+// 自定义 Result 类型
+pub type Result<T> = std::result::Result<T, Error>;
+
+// 自定义错误类型
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // 位置超出了缓冲区的长度
+    OutOfBounds { index: usize, len: usize },
+    // 范围的起点大于终点
+    InvalidRange { start: usize, end: usize },
+    // 光标在开头，前面没有可以删除的元素
+    AtStart,
+    // 文本的偏移量不在字符的边界上
+    NotCharBoundary(usize),
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfBounds { index, len } => write!(f, "index {} out of bounds {}", index, len),
+            Self::InvalidRange { start, end } => write!(f, "range start {} is greater than end {}", start, end),
+            Self::AtStart => write!(f, "nothing to delete before position 0"),
+            Self::NotCharBoundary(offset) => write!(f, "offset {} is not a char boundary", offset),
+        }
+    }
+}
+
+// 范围必须在 0..=len 之内，并且起点不大于终点
+pub(crate) fn check_range(range: &Range<usize>, len: usize) -> Result<()> {
+    if range.start > range.end {
+        return Err(Error::InvalidRange {
+            start: range.start,
+            end: range.end,
+        });
+    }
+    if range.end > len {
+        return Err(Error::OutOfBounds { index: range.end, len });
+    }
+    Ok(())
+}
 
 pub struct GapBuffer<T> {
     storage: Vec<T>,
     gap: Range<usize>,
 }
 
+impl<T> Default for GapBuffer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 按顺序遍历元素，先遍历间隙前面的部分，再遍历间隙后面的部分
+pub type Iter<'a, T> = Chain<slice::Iter<'a, T>, slice::Iter<'a, T>>;
+pub type IterMut<'a, T> = Chain<slice::IterMut<'a, T>, slice::IterMut<'a, T>>;
+
 impl<T> GapBuffer<T> {
     pub fn new() -> Self {
         Self {
@@ -13,6 +77,13 @@ impl<T> GapBuffer<T> {
         }
     }
 
+    // 预先分配空间，插入 capacity 个元素之前不需要重新分配
+    pub fn with_capacity(capacity: usize) -> Self {
+        let storage = Vec::with_capacity(capacity);
+        let gap = 0..storage.capacity();
+        Self { storage, gap }
+    }
+
     pub fn capacity(&self) -> usize {
         self.storage.capacity()
     }
@@ -21,16 +92,20 @@ impl<T> GapBuffer<T> {
         self.capacity() - self.gap.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn position(&self) -> usize {
         self.gap.start
     }
 
     unsafe fn space(&self, index: usize) -> *const T {
-        self.storage.as_ptr().offset(index as isize)
+        self.storage.as_ptr().add(index)
     }
 
     unsafe fn space_mut(&mut self, index: usize) -> *mut T {
-        self.storage.as_mut_ptr().offset(index as isize)
+        self.storage.as_mut_ptr().add(index)
     }
 
     fn index_to_raw(&self, index: usize) -> usize {
@@ -41,18 +116,58 @@ impl<T> GapBuffer<T> {
         }
     }
 
+    // 先检查下标，避免下标加上间隙长度后溢出，指向间隙中未初始化的内存
     pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len() {
+            return None;
+        }
+        unsafe { Some(&*self.space(self.index_to_raw(index))) }
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index >= self.len() {
+            return None;
+        }
         let raw = self.index_to_raw(index);
-        if raw < self.capacity() {
-            unsafe { Some(&*self.space(raw)) }
-        } else {
-            None
+        unsafe { Some(&mut *self.space_mut(raw)) }
+    }
+
+    // 间隙前后两段连续的元素
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        unsafe {
+            let before = slice::from_raw_parts(self.space(0), self.gap.start);
+            let after = slice::from_raw_parts(self.space(self.gap.end), self.capacity() - self.gap.end);
+            (before, after)
+        }
+    }
+
+    pub fn as_mut_slices(&mut self) -> (&mut [T], &mut [T]) {
+        let (start, end, capacity) = (self.gap.start, self.gap.end, self.capacity());
+        let ptr = self.storage.as_mut_ptr();
+        unsafe {
+            let before = slice::from_raw_parts_mut(ptr, start);
+            let after = slice::from_raw_parts_mut(ptr.add(end), capacity - end);
+            (before, after)
         }
     }
 
-    pub fn set_position(&mut self, pos: usize) {
+    pub fn iter(&self) -> Iter<'_, T> {
+        let (before, after) = self.as_slices();
+        before.iter().chain(after)
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        let (before, after) = self.as_mut_slices();
+        before.iter_mut().chain(after)
+    }
+
+    // 移动光标，间隙和光标之间的元素移动到间隙的另一边
+    pub fn set_position(&mut self, pos: usize) -> Result<()> {
         if pos > self.len() {
-            panic!("Index {} out of bounds {}", pos, self.len());
+            return Err(Error::OutOfBounds {
+                index: pos,
+                len: self.len(),
+            });
         }
 
         unsafe {
@@ -62,18 +177,15 @@ impl<T> GapBuffer<T> {
                 std::ptr::copy(self.space(gap.end), self.space_mut(gap.start), distance);
             } else if pos < gap.start {
                 let distance = gap.start - pos;
-                std::ptr::copy(
-                    self.space(pos),
-                    self.space_mut(gap.end - distance),
-                    distance,
-                );
+                std::ptr::copy(self.space(pos), self.space_mut(gap.end - distance), distance);
             }
             self.gap = pos..pos + gap.len();
         }
+        Ok(())
     }
 
     pub fn insert(&mut self, val: T) {
-        if self.gap.len() == 0 {
+        if self.gap.is_empty() {
             self.enlarge_gap()
         }
 
@@ -93,6 +205,7 @@ impl<T> GapBuffer<T> {
         }
     }
 
+    // 删除光标后面的元素
     pub fn remove(&mut self) -> Option<T> {
         if self.gap.end == self.capacity() {
             return None;
@@ -103,19 +216,108 @@ impl<T> GapBuffer<T> {
         Some(element)
     }
 
+    // 删除光标前面的元素，相当于退格键
+    pub fn delete_backward(&mut self) -> Result<T> {
+        if self.gap.start == 0 {
+            return Err(Error::AtStart);
+        }
+
+        self.gap.start -= 1;
+        Ok(unsafe { std::ptr::read(self.space(self.gap.start)) })
+    }
+
+    // 删除范围内的元素并返回，光标移动到范围的起点
+    pub fn drain(&mut self, range: Range<usize>) -> Result<Vec<T>> {
+        check_range(&range, self.len())?;
+        self.set_position(range.start)?;
+
+        let count = range.len();
+        let mut drained = Vec::with_capacity(count);
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.space(self.gap.end), drained.as_mut_ptr(), count);
+            drained.set_len(count);
+        }
+        self.gap.end += count;
+        Ok(drained)
+    }
+
+    // 删除范围内的元素，光标移动到范围的起点
+    pub fn delete_range(&mut self, range: Range<usize>) -> Result<()> {
+        check_range(&range, self.len())?;
+        self.set_position(range.start)?;
+
+        // 先把元素移到间隙中，析构函数 panic 时也不会重复释放
+        let count = range.len();
+        let start = self.gap.end;
+        self.gap.end += count;
+        unsafe {
+            std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(self.space_mut(start), count));
+        }
+        Ok(())
+    }
+
+    // 把范围内的元素替换为 iter 中的元素，光标移动到插入的元素后面
+    pub fn replace_range<I>(&mut self, range: Range<usize>, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.delete_range(range)?;
+        self.insert_iter(iter);
+        Ok(())
+    }
+
+    // 只保留前 len 个元素，len 不能大于当前的长度
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.len() {
+            return Err(Error::OutOfBounds {
+                index: len,
+                len: self.len(),
+            });
+        }
+        self.delete_range(len..self.len())
+    }
+
+    // 删除所有元素，保留已经分配的空间
+    pub fn clear(&mut self) {
+        let capacity = self.capacity();
+        let gap = std::mem::replace(&mut self.gap, 0..capacity);
+        let after = capacity - gap.end;
+        unsafe {
+            std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(self.space_mut(0), gap.start));
+            std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(self.space_mut(gap.end), after));
+        }
+    }
+
+    // 保证至少还可以插入 additional 个元素
+    pub fn reserve(&mut self, additional: usize) {
+        if self.gap.len() < additional {
+            let required = self.len().checked_add(additional).expect("capacity overflow");
+            self.resize(required.max(self.capacity() * 2));
+        }
+    }
+
+    // 释放间隙占用的空间
+    pub fn shrink_to_fit(&mut self) {
+        self.resize(self.len());
+    }
+
     fn enlarge_gap(&mut self) {
         let mut new_capacity = self.capacity() * 2;
         if new_capacity == 0 {
             new_capacity = 4;
         }
+        self.resize(new_capacity);
+    }
 
+    // 重新分配空间，光标位置不变，new_capacity 不能小于长度
+    fn resize(&mut self, new_capacity: usize) {
         let mut new = Vec::with_capacity(new_capacity);
         let after_gap = self.capacity() - self.gap.end;
         let new_gap = self.gap.start..new.capacity() - after_gap;
 
         unsafe {
             std::ptr::copy_nonoverlapping(self.space(0), new.as_mut_ptr(), self.gap.start);
-            let new_gap_end = new.as_mut_ptr().offset(new_gap.end as isize);
+            let new_gap_end = new.as_mut_ptr().add(new_gap.end);
             std::ptr::copy_nonoverlapping(self.space(self.gap.end), new_gap_end, after_gap);
         }
 
@@ -126,25 +328,315 @@ impl<T> GapBuffer<T> {
 
 impl<T> Drop for GapBuffer<T> {
     fn drop(&mut self) {
-        unsafe {
-            for i in 0..self.gap.start {
-                std::ptr::drop_in_place(self.space_mut(i));
-            }
-            for i in self.gap.end..self.capacity() {
-                std::ptr::drop_in_place(self.space_mut(i));
-            }
-        }
+        self.clear();
+    }
+}
+
+impl<T> Index<usize> for GapBuffer<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        let len = self.len();
+        self.get(index)
+            .unwrap_or_else(|| panic!("{}", Error::OutOfBounds { index, len }))
+    }
+}
+
+impl<T> IndexMut<usize> for GapBuffer<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let len = self.len();
+        self.get_mut(index)
+            .unwrap_or_else(|| panic!("{}", Error::OutOfBounds { index, len }))
+    }
+}
+
+// 克隆时保留光标的位置，间隙只保留插入需要的空间
+impl<T: Clone> Clone for GapBuffer<T> {
+    fn clone(&self) -> Self {
+        let mut buf = GapBuffer::with_capacity(self.len());
+        buf.extend(self.iter().cloned());
+        buf.set_position(self.position()).unwrap();
+        buf
+    }
+}
+
+impl<T: Debug> Debug for GapBuffer<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// 只比较元素，不比较光标的位置
+impl<T: PartialEq> PartialEq for GapBuffer<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Eq> Eq for GapBuffer<T> {}
+
+// 在光标处插入元素
+impl<T> Extend<T> for GapBuffer<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        self.insert_iter(iter);
+    }
+}
+
+impl<'a, T: Copy + 'a> Extend<&'a T> for GapBuffer<T> {
+    fn extend<I: IntoIterator<Item = &'a T>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().copied());
+    }
+}
+
+// 光标在末尾
+impl<T> FromIterator<T> for GapBuffer<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut buf = GapBuffer::new();
+        buf.extend(iter);
+        buf
+    }
+}
+
+impl<'a, T> IntoIterator for &'a GapBuffer<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut GapBuffer<T> {
+    type Item = &'a mut T;
+    type IntoIter = IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<T> IntoIterator for GapBuffer<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    // 把光标移到开头，之后依次删除光标后面的元素
+    fn into_iter(mut self) -> Self::IntoIter {
+        self.set_position(0).unwrap();
+        IntoIter { buf: self }
+    }
+}
+
+// 按值遍历元素，没有遍历完的元素在释放时一起释放
+pub struct IntoIter<T> {
+    buf: GapBuffer<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.buf.remove()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.buf.len(), Some(self.buf.len()))
     }
 }
 
+impl<T> ExactSizeIterator for IntoIter<T> {}
+
+// 测试中只使用很小的数据，可以在 Miri 下运行：cargo +nightly miri test -p gap-buffer
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::rc::Rc;
+
+    fn buffer(text: &str, pos: usize) -> GapBuffer<char> {
+        let mut buf = GapBuffer::new();
+        buf.insert_iter(text.chars());
+        buf.set_position(pos).unwrap();
+        buf
+    }
+
+    fn text(buf: &GapBuffer<char>) -> String {
+        (0..buf.len()).map(|i| *buf.get(i).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_slices_and_iter() {
+        let mut buf = buffer("Lord of the Rings", 5);
+        assert_eq!(buf.as_slices(), (&['L', 'o', 'r', 'd', ' '][..], &"of the Rings".chars().collect::<Vec<_>>()[..]));
+        assert_eq!(buf.iter().collect::<String>(), "Lord of the Rings");
+        assert_eq!((&buf).into_iter().rev().take(5).collect::<String>(), "sgniR");
+
+        for c in &mut buf {
+            *c = c.to_ascii_uppercase();
+        }
+        buf[0] = 'l';
+        assert_eq!(buf[1], 'O');
+        assert_eq!(text(&buf), "lORD OF THE RINGS");
+        assert_eq!(buf.into_iter().skip(12).collect::<String>(), "RINGS");
+    }
+
+    #[test]
+    #[should_panic(expected = "index 3 out of bounds 3")]
+    fn test_index_out_of_bounds() {
+        let buf = buffer("abc", 1);
+        let _ = buf[3];
+    }
+
+    #[test]
+    fn test_collect_and_extend() {
+        let mut buf: GapBuffer<char> = "Rings".chars().collect();
+        assert_eq!(buf.position(), 5);
+        buf.set_position(0).unwrap();
+        buf.extend("Lord of the ".chars());
+        buf.extend(&['!']);
+        assert_eq!(text(&buf), "Lord of the !Rings");
+        assert_eq!(format!("{:?}", buffer("ab", 1)), "['a', 'b']");
+        assert_eq!(GapBuffer::<char>::default(), GapBuffer::new());
+    }
+
+    #[test]
+    fn test_clone_and_eq() {
+        let buf = buffer("Lord of the Rings", 4);
+        let copy = buf.clone();
+        assert_eq!(copy, buf);
+        assert_eq!(copy.position(), 4);
+        // 间隙的位置不同，元素相同时也相等
+        assert_eq!(buffer("abc", 0), buffer("abc", 3));
+        assert_ne!(buffer("abc", 0), buffer("abd", 0));
+        assert_ne!(buffer("abc", 0), buffer("ab", 0));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut buf = GapBuffer::with_capacity(10);
+        assert!(buf.capacity() >= 10);
+        buf.insert_iter("Lord of the Rings".chars());
+        buf.set_position(4).unwrap();
+        buf.reserve(100);
+        assert!(buf.capacity() >= 117);
+        assert_eq!(buf.position(), 4);
+        buf.shrink_to_fit();
+        assert_eq!(buf.capacity(), 17);
+        assert_eq!(text(&buf), "Lord of the Rings");
+        buf.insert('!');
+        assert_eq!(text(&buf), "Lord! of the Rings");
+
+        let mut empty = GapBuffer::<char>::new();
+        empty.shrink_to_fit();
+        empty.insert('a');
+        assert_eq!(text(&empty), "a");
+    }
 
     #[test]
     fn it_works() {
         let mut buf = GapBuffer::new();
         buf.insert_iter("Lord of the Rings".chars());
-        buf.set_position(12);
+        buf.set_position(12).unwrap();
+        assert_eq!(buf.set_position(18), Err(Error::OutOfBounds { index: 18, len: 17 }));
+        assert_eq!(buf.position(), 12);
+    }
+
+    #[test]
+    fn test_get() {
+        let buf = buffer("abc", 1);
+        assert!(buf.capacity() > buf.len());
+        assert_eq!(text(&buf), "abc");
+        assert_eq!(buf.get(3), None);
+        assert_eq!(buf.get(usize::MAX), None);
+        assert_eq!(GapBuffer::<char>::new().get(0), None);
+    }
+
+    #[test]
+    fn test_delete_backward() {
+        let mut buf = buffer("abc", 2);
+        assert_eq!(buf.delete_backward(), Ok('b'));
+        assert_eq!(buf.delete_backward(), Ok('a'));
+        assert_eq!(buf.delete_backward(), Err(Error::AtStart));
+        assert_eq!(text(&buf), "c");
+        assert_eq!(buf.position(), 0);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut buf = buffer("Lord of the Rings", 3);
+        buf.delete_range(4..11).unwrap();
+        assert_eq!(text(&buf), "Lord Rings");
+        assert_eq!(buf.position(), 4);
+        buf.delete_range(0..0).unwrap();
+        assert_eq!(text(&buf), "Lord Rings");
+
+        assert_eq!(buf.delete_range(5..11), Err(Error::OutOfBounds { index: 11, len: 10 }));
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 3..2;
+        assert_eq!(buf.delete_range(reversed), Err(Error::InvalidRange { start: 3, end: 2 }));
+        assert_eq!(text(&buf), "Lord Rings");
+    }
+
+    #[test]
+    fn test_replace_range() {
+        let mut buf = buffer("Lord of the Rings", 17);
+        buf.replace_range(0..4, "King".chars()).unwrap();
+        assert_eq!(text(&buf), "King of the Rings");
+        assert_eq!(buf.position(), 4);
+        buf.replace_range(12..17, "Hill and more".chars()).unwrap();
+        assert_eq!(text(&buf), "King of the Hill and more");
+        assert_eq!(buf.replace_range(26..27, "x".chars()), Err(Error::OutOfBounds { index: 27, len: 25 }));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut buf = buffer("Lord of the Rings", 0);
+        assert_eq!(buf.drain(5..12), Ok("of the ".chars().collect()));
+        assert_eq!(text(&buf), "Lord Rings");
+        assert_eq!(buf.drain(0..10).map(|v| v.len()), Ok(10));
+        assert!(buf.is_empty());
+        assert_eq!(buf.drain(0..1), Err(Error::OutOfBounds { index: 1, len: 0 }));
+    }
+
+    #[test]
+    fn test_truncate_and_clear() {
+        let mut buf = buffer("Lord of the Rings", 2);
+        assert_eq!(buf.truncate(18), Err(Error::OutOfBounds { index: 18, len: 17 }));
+        buf.truncate(4).unwrap();
+        assert_eq!(text(&buf), "Lord");
+        buf.truncate(4).unwrap();
+        assert_eq!(text(&buf), "Lord");
+
+        let capacity = buf.capacity();
+        buf.clear();
+        assert!(buf.is_empty());
+        assert_eq!(buf.position(), 0);
+        assert_eq!(buf.capacity(), capacity);
+        buf.insert_iter("Rings".chars());
+        assert_eq!(text(&buf), "Rings");
+    }
+
+    #[test]
+    fn test_drop_once() {
+        // 每个被删除的元素都只释放一次
+        let item = Rc::new(());
+        let mut buf = GapBuffer::new();
+        buf.insert_iter((0..10).map(|_| item.clone()));
+        buf.set_position(3).unwrap();
+        assert_eq!(Rc::strong_count(&item), 11);
+
+        drop(buf.delete_backward().unwrap());
+        buf.delete_range(1..3).unwrap();
+        drop(buf.drain(0..1).unwrap());
+        buf.replace_range(0..1, [item.clone()]).unwrap();
+        assert_eq!(Rc::strong_count(&item), 7);
+        buf.truncate(3).unwrap();
+        assert_eq!(Rc::strong_count(&item), 4);
+        buf.clear();
+        assert_eq!(Rc::strong_count(&item), 1);
+
+        buf.insert_iter((0..2).map(|_| item.clone()));
+        drop(buf);
+        assert_eq!(Rc::strong_count(&item), 1);
     }
 }
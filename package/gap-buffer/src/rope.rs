@@ -0,0 +1,526 @@
+use crate::{check_range, Error, Result};
+use std::fmt::Debug;
+use std::ops::{Index, Range};
+use std::rc::Rc;
+use std::slice;
+
+// 叶子节点最多保存的元素个数，内部节点最多的子节点个数
+// 测试中使用很小的节点，少量数据也能生成多层的树
+#[cfg(not(test))]
+const MAX_LEAF: usize = 1024;
+#[cfg(not(test))]
+const MAX_CHILDREN: usize = 16;
+#[cfg(test)]
+const MAX_LEAF: usize = 4;
+#[cfg(test)]
+const MAX_CHILDREN: usize = 4;
+
+// B 树的节点，所有叶子节点的深度相同
+// 子节点使用 Rc 共享，修改时只复制从根节点到被修改的叶子节点路径上的节点
+#[derive(Clone)]
+enum Node<T> {
+    Leaf(Vec<T>),
+    // 子节点和所有子节点的元素个数之和
+    Internal(Vec<Rc<Node<T>>>, usize),
+}
+
+impl<T: Clone> Node<T> {
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf(items) => items.len(),
+            Node::Internal(_, len) => *len,
+        }
+    }
+
+    // 元素少于一半时需要和相邻的节点合并
+    fn is_underfull(&self) -> bool {
+        match self {
+            Node::Leaf(items) => items.len() < MAX_LEAF / 2,
+            Node::Internal(children, _) => children.len() < MAX_CHILDREN / 2,
+        }
+    }
+
+    fn internal(children: Vec<Rc<Node<T>>>) -> Rc<Node<T>> {
+        let len = children.iter().map(|child| child.len()).sum();
+        Rc::new(Node::Internal(children, len))
+    }
+
+    // 在 index 处插入元素，节点超出大小时分裂，返回需要插入到节点后面的兄弟节点
+    fn insert<I>(node: &mut Rc<Node<T>>, index: usize, items: I) -> Vec<Rc<Node<T>>>
+    where
+        I: Iterator<Item = T>,
+    {
+        match Rc::make_mut(node) {
+            Node::Leaf(leaf) => {
+                leaf.splice(index..index, items);
+                if leaf.len() <= MAX_LEAF {
+                    return vec![];
+                }
+                let mut pieces = split(std::mem::take(leaf), MAX_LEAF).into_iter().map(|items| Rc::new(Node::Leaf(items)));
+                *node = pieces.next().unwrap();
+                pieces.collect()
+            }
+            Node::Internal(children, len) => {
+                // 在子节点的边界上时插入到前一个子节点的末尾
+                let (i, offset) = locate(children, index, true);
+                let siblings = Node::insert(&mut children[i], offset, items);
+                children.splice(i + 1..i + 1, siblings);
+                *len = children.iter().map(|child| child.len()).sum();
+                if children.len() <= MAX_CHILDREN {
+                    return vec![];
+                }
+                let mut pieces = split(std::mem::take(children), MAX_CHILDREN).into_iter().map(Node::internal);
+                *node = pieces.next().unwrap();
+                pieces.collect()
+            }
+        }
+    }
+
+    // 删除范围内的元素，放到 out 中
+    fn remove(node: &mut Rc<Node<T>>, range: Range<usize>, out: &mut Vec<T>) {
+        match Rc::make_mut(node) {
+            Node::Leaf(leaf) => out.extend(leaf.drain(range)),
+            Node::Internal(children, len) => {
+                let mut start = 0;
+                for child in children.iter_mut() {
+                    let end = start + child.len();
+                    if range.start < end && start < range.end {
+                        let from = range.start.max(start) - start;
+                        let to = range.end.min(end) - start;
+                        Node::remove(child, from..to, out);
+                    }
+                    start = end;
+                }
+                *len -= range.len();
+                rebalance(children);
+            }
+        }
+    }
+
+    // 把 right 的内容合并到 left，超出大小时平均分成两个节点，返回右边的节点
+    fn merge(left: &mut Rc<Node<T>>, right: Rc<Node<T>>) -> Option<Rc<Node<T>>> {
+        match (Rc::make_mut(left), Rc::unwrap_or_clone(right)) {
+            (Node::Leaf(items), Node::Leaf(more)) => {
+                items.extend(more);
+                (items.len() > MAX_LEAF).then(|| Rc::new(Node::Leaf(items.split_off(items.len() / 2))))
+            }
+            (Node::Internal(children, len), Node::Internal(more, _)) => {
+                children.extend(more);
+                if children.len() <= MAX_CHILDREN {
+                    *len = children.iter().map(|child| child.len()).sum();
+                    return None;
+                }
+                let tail = children.split_off(children.len() / 2);
+                *len = children.iter().map(|child| child.len()).sum();
+                Some(Node::internal(tail))
+            }
+            _ => unreachable!("siblings have the same depth"),
+        }
+    }
+}
+
+// 把元素平均分成若干份，每份不超过 max 个，超过 max 时每份至少 max / 2 个
+fn split<X>(mut items: Vec<X>, max: usize) -> Vec<Vec<X>> {
+    let count = items.len().div_ceil(max);
+    let mut pieces = Vec::with_capacity(count);
+    for i in (1..count).rev() {
+        let size = items.len() / (i + 1);
+        pieces.push(items.split_off(items.len() - size));
+    }
+    pieces.push(items);
+    pieces.reverse();
+    pieces
+}
+
+// index 所在的子节点和在子节点中的位置
+// at_end 为 true 时，子节点的末尾也算作在子节点中，用于插入
+fn locate<T: Clone>(children: &[Rc<Node<T>>], mut index: usize, at_end: bool) -> (usize, usize) {
+    for (i, child) in children.iter().enumerate() {
+        if index < child.len() || (at_end && index == child.len()) {
+            return (i, index);
+        }
+        index -= child.len();
+    }
+    unreachable!("index is checked against the rope length")
+}
+
+// 删除之后去掉空的子节点，并且把元素太少的子节点和相邻的节点合并
+fn rebalance<T: Clone>(children: &mut Vec<Rc<Node<T>>>) {
+    children.retain(|child| child.len() > 0);
+    let mut i = 0;
+    while i < children.len() {
+        if children.len() == 1 || !children[i].is_underfull() {
+            i += 1;
+            continue;
+        }
+        let left = if i + 1 < children.len() { i } else { i - 1 };
+        let right = children.remove(left + 1);
+        match Node::merge(&mut children[left], right) {
+            Some(extra) => {
+                children.insert(left + 1, extra);
+                i = left + 2;
+            }
+            // 合并之后仍然可能太少，继续和下一个节点合并
+            None => i = left,
+        }
+    }
+}
+
+// 绳索，使用 B 树保存元素的分块，适合非常大的文档
+// 插入、删除和按下标访问都是 O(log n)，克隆只需要复制根节点的指针，可以作为快照
+// 编辑接口和 GapBuffer 相同，光标只是一个下标，移动光标不需要移动元素
+#[derive(Clone)]
+pub struct Rope<T> {
+    root: Rc<Node<T>>,
+    pos: usize,
+}
+
+impl<T: Clone> Default for Rope<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> Rope<T> {
+    pub fn new() -> Self {
+        Self {
+            root: Rc::new(Node::Leaf(Vec::new())),
+            pos: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn set_position(&mut self, pos: usize) -> Result<()> {
+        if pos > self.len() {
+            return Err(Error::OutOfBounds {
+                index: pos,
+                len: self.len(),
+            });
+        }
+        self.pos = pos;
+        Ok(())
+    }
+
+    pub fn get(&self, mut index: usize) -> Option<&T> {
+        if index >= self.len() {
+            return None;
+        }
+        let mut node = &self.root;
+        loop {
+            match &**node {
+                Node::Leaf(items) => return items.get(index),
+                Node::Internal(children, _) => {
+                    let (i, offset) = locate(children, index, false);
+                    node = &children[i];
+                    index = offset;
+                }
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![slice::from_ref(&self.root).iter()],
+            leaf: [].iter(),
+            len: self.len(),
+        }
+    }
+
+    // 在光标处插入元素，光标移动到插入的元素后面
+    pub fn insert(&mut self, val: T) {
+        self.insert_iter(Some(val));
+    }
+
+    pub fn insert_iter<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let len = self.len();
+        let siblings = Node::insert(&mut self.root, self.pos, iter.into_iter());
+        if !siblings.is_empty() {
+            // 根节点分裂，树的高度增加，一次插入很多元素时可能需要增加好几层
+            let mut nodes = vec![self.root.clone()];
+            nodes.extend(siblings);
+            while nodes.len() > 1 {
+                nodes = split(nodes, MAX_CHILDREN).into_iter().map(Node::internal).collect();
+            }
+            self.root = nodes.pop().unwrap();
+        }
+        self.pos += self.len() - len;
+    }
+
+    // 删除光标后面的元素
+    pub fn remove(&mut self) -> Option<T> {
+        self.drain(self.pos..self.pos + 1).ok()?.pop()
+    }
+
+    // 删除光标前面的元素，相当于退格键
+    pub fn delete_backward(&mut self) -> Result<T> {
+        if self.pos == 0 {
+            return Err(Error::AtStart);
+        }
+        Ok(self.drain(self.pos - 1..self.pos)?.pop().unwrap())
+    }
+
+    // 删除范围内的元素并返回，光标移动到范围的起点
+    pub fn drain(&mut self, range: Range<usize>) -> Result<Vec<T>> {
+        check_range(&range, self.len())?;
+        let mut drained = Vec::with_capacity(range.len());
+        if !range.is_empty() {
+            Node::remove(&mut self.root, range.clone(), &mut drained);
+        }
+        // 根节点只有一个子节点时，树的高度减小
+        while let Node::Internal(children, _) = &*self.root {
+            match children.len() {
+                0 => self.root = Rc::new(Node::Leaf(Vec::new())),
+                1 => self.root = children[0].clone(),
+                _ => break,
+            }
+        }
+        self.pos = range.start;
+        Ok(drained)
+    }
+
+    pub fn delete_range(&mut self, range: Range<usize>) -> Result<()> {
+        self.drain(range).map(drop)
+    }
+
+    pub fn replace_range<I>(&mut self, range: Range<usize>, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.delete_range(range)?;
+        self.insert_iter(iter);
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.len() {
+            return Err(Error::OutOfBounds {
+                index: len,
+                len: self.len(),
+            });
+        }
+        self.delete_range(len..self.len())
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl<T: Clone> Index<usize> for Rope<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        let len = self.len();
+        self.get(index)
+            .unwrap_or_else(|| panic!("{}", Error::OutOfBounds { index, len }))
+    }
+}
+
+impl<T: Clone + Debug> Debug for Rope<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// 只比较元素，不比较光标的位置和树的结构
+impl<T: Clone + PartialEq> PartialEq for Rope<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Clone + Eq> Eq for Rope<T> {}
+
+// 在光标处插入元素
+impl<T: Clone> Extend<T> for Rope<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.insert_iter(iter);
+    }
+}
+
+impl<'a, T: Copy + 'a> Extend<&'a T> for Rope<T> {
+    fn extend<I: IntoIterator<Item = &'a T>>(&mut self, iter: I) {
+        self.insert_iter(iter.into_iter().copied());
+    }
+}
+
+// 光标在末尾
+impl<T: Clone> FromIterator<T> for Rope<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut rope = Rope::new();
+        rope.insert_iter(iter);
+        rope
+    }
+}
+
+impl<'a, T: Clone> IntoIterator for &'a Rope<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// 按顺序遍历叶子节点中的元素，栈中保存每一层还没有遍历的子节点
+pub struct Iter<'a, T> {
+    stack: Vec<slice::Iter<'a, Rc<Node<T>>>>,
+    leaf: slice::Iter<'a, T>,
+    len: usize,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            if let Some(item) = self.leaf.next() {
+                self.len -= 1;
+                return Some(item);
+            }
+            let node = loop {
+                match self.stack.last_mut()?.next() {
+                    Some(node) => break node,
+                    None => {
+                        self.stack.pop();
+                    }
+                }
+            };
+            match &**node {
+                Node::Leaf(items) => self.leaf = items.iter(),
+                Node::Internal(children, _) => self.stack.push(children.iter()),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<T> ExactSizeIterator for Iter<'_, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(rope: &Rope<char>) -> String {
+        rope.iter().collect()
+    }
+
+    // 检查 B 树的结构：长度正确，叶子节点的深度相同，节点不为空也不超出大小
+    fn check<T: Clone>(rope: &Rope<T>) {
+        fn depth<T: Clone>(node: &Node<T>, root: bool) -> usize {
+            match node {
+                Node::Leaf(items) => {
+                    assert!(items.len() <= MAX_LEAF && (root || !items.is_empty()));
+                    0
+                }
+                Node::Internal(children, len) => {
+                    assert!(children.len() <= MAX_CHILDREN && (root && children.len() > 1 || !root && !children.is_empty()));
+                    assert_eq!(*len, children.iter().map(|child| child.len()).sum::<usize>());
+                    let depths = children.iter().map(|child| depth(child, false)).collect::<Vec<_>>();
+                    assert!(depths.iter().all(|&d| d == depths[0]));
+                    depths[0] + 1
+                }
+            }
+        }
+        depth(&rope.root, true);
+    }
+
+    #[test]
+    fn test_editing() {
+        let mut rope: Rope<char> = "Lord of the Rings".chars().collect();
+        check(&rope);
+        assert_eq!(rope.position(), 17);
+        rope.set_position(4).unwrap();
+        assert_eq!(rope.set_position(18), Err(Error::OutOfBounds { index: 18, len: 17 }));
+        rope.insert('!');
+        assert_eq!(text(&rope), "Lord! of the Rings");
+        assert_eq!(rope.delete_backward(), Ok('!'));
+        assert_eq!(rope.remove(), Some(' '));
+        assert_eq!(rope.drain(4..11), Ok("of the ".chars().collect()));
+        assert_eq!(text(&rope), "LordRings");
+        rope.replace_range(0..4, "King of the ".chars()).unwrap();
+        assert_eq!(rope.position(), 12);
+        rope.truncate(16).unwrap();
+        assert_eq!(text(&rope), "King of the Ring");
+        check(&rope);
+
+        assert_eq!(rope[5], 'o');
+        assert_eq!(rope.get(16), None);
+        assert_eq!(rope.iter().len(), 16);
+        assert_eq!(rope.truncate(17), Err(Error::OutOfBounds { index: 17, len: 16 }));
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 3..2;
+        assert_eq!(rope.delete_range(reversed), Err(Error::InvalidRange { start: 3, end: 2 }));
+        rope.clear();
+        assert!(rope.is_empty());
+        assert_eq!(rope.remove(), None);
+        assert_eq!(rope.delete_backward(), Err(Error::AtStart));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut rope: Rope<u32> = (0..1000).collect();
+        let snapshot = rope.clone();
+        rope.set_position(500).unwrap();
+        rope.insert(42);
+        rope.delete_range(0..10).unwrap();
+        assert_eq!(rope.len(), 991);
+        assert_eq!(rope[490], 42);
+        // 快照不受影响，没有修改的节点仍然共享
+        assert_eq!(snapshot.iter().copied().collect::<Vec<_>>(), (0..1000).collect::<Vec<_>>());
+        let copy = snapshot.clone();
+        assert!(Rc::ptr_eq(&copy.root, &snapshot.root));
+        check(&rope);
+        check(&snapshot);
+        assert_ne!(rope, snapshot);
+        assert_eq!(snapshot, (0..1000).collect());
+    }
+
+    #[test]
+    fn test_random_edits() {
+        // 和 Vec 比较随机编辑之后的结果，同时检查树的结构
+        let mut seed = 7u64;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        let mut rope = Rope::new();
+        let mut model = Vec::new();
+        for _ in 0..300 {
+            let pos = random(model.len() + 1);
+            rope.set_position(pos).unwrap();
+            if random(3) == 0 {
+                let end = (pos + random(40)).min(model.len());
+                assert_eq!(rope.drain(pos..end).unwrap(), model.drain(pos..end).collect::<Vec<_>>());
+            } else {
+                let count = random(30);
+                rope.extend(0..count);
+                model.splice(pos..pos, 0..count);
+            }
+            check(&rope);
+            assert_eq!(rope.len(), model.len());
+            assert!(rope.iter().eq(model.iter()));
+            if !model.is_empty() {
+                let index = random(model.len());
+                assert_eq!(rope[index], model[index]);
+            }
+        }
+    }
+}
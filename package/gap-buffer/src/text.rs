@@ -0,0 +1,585 @@
+use crate::{Error, GapBuffer, Result};
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::ops::Range;
+use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
+
+// 文本编辑缓冲区，UTF-8 编码的字节保存在 GapBuffer 中，间隙的位置就是光标
+// 光标始终在字符的边界上，所以间隙前后两段都是合法的 UTF-8
+//
+// 行索引保存第 1 行开始的每一行的起点，也就是每个换行符后面的位置，同样使用 GapBuffer 保存
+// 行索引的间隙在光标所在的行后面：间隙前面的起点保存为偏移量，间隙后面的起点保存为到文本末尾的距离，
+// 这样在光标处插入和删除时只需要修改光标附近的几项，不需要更新后面所有行的起点
+#[derive(Clone, Default)]
+pub struct TextBuffer {
+    text: GapBuffer<u8>,
+    lines: GapBuffer<usize>,
+    // 上下移动时希望保持的列，左右移动和编辑之后清除
+    goal: Option<usize>,
+}
+
+impl TextBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 字节长度
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    // 光标的字节偏移量
+    pub fn cursor(&self) -> usize {
+        self.text.position()
+    }
+
+    // 间隙前后的两段文本
+    fn halves(&self) -> (&str, &str) {
+        let (before, after) = self.text.as_slices();
+        // 只插入完整的 str，间隙只会移动到字符的边界上，两段都是合法的 UTF-8
+        unsafe { (std::str::from_utf8_unchecked(before), std::str::from_utf8_unchecked(after)) }
+    }
+
+    // 偏移量必须在文本范围内，并且在字符的边界上
+    fn check_offset(&self, offset: usize) -> Result<()> {
+        match self.text.get(offset) {
+            None if offset > self.len() => Err(Error::OutOfBounds {
+                index: offset,
+                len: self.len(),
+            }),
+            // UTF-8 的后续字节都是 0b10xxxxxx
+            Some(b) if b & 0xc0 == 0x80 => Err(Error::NotCharBoundary(offset)),
+            _ => Ok(()),
+        }
+    }
+
+    // 范围内的文本，范围在间隙一侧时不需要复制
+    pub fn slice(&self, range: Range<usize>) -> Result<Cow<'_, str>> {
+        self.check_offset(range.start)?;
+        self.check_offset(range.end)?;
+        if range.start > range.end {
+            return Err(Error::InvalidRange {
+                start: range.start,
+                end: range.end,
+            });
+        }
+        Ok(self.text_range(range))
+    }
+
+    fn text_range(&self, range: Range<usize>) -> Cow<'_, str> {
+        let (before, after) = self.halves();
+        let mid = before.len();
+        if range.end <= mid {
+            Cow::Borrowed(&before[range])
+        } else if range.start >= mid {
+            Cow::Borrowed(&after[range.start - mid..range.end - mid])
+        } else {
+            Cow::Owned([&before[range.start..], &after[..range.end - mid]].concat())
+        }
+    }
+
+    // 偏移量后面的字符
+    fn chars_after(&self, offset: usize) -> impl Iterator<Item = char> + '_ {
+        let (before, after) = self.halves();
+        let (first, second) = if offset <= before.len() {
+            (&before[offset..], after)
+        } else {
+            (&after[offset - before.len()..], "")
+        };
+        first.chars().chain(second.chars())
+    }
+
+    // 偏移量前面的字符，从后往前
+    fn chars_before(&self, offset: usize) -> impl Iterator<Item = char> + '_ {
+        let (before, after) = self.halves();
+        let (first, second) = if offset >= before.len() {
+            (before, &after[..offset - before.len()])
+        } else {
+            (&before[..offset], "")
+        };
+        second.chars().rev().chain(first.chars().rev())
+    }
+
+    // 第 i 个行索引项对应的行起点
+    fn entry(&self, i: usize) -> usize {
+        if i < self.lines.position() {
+            self.lines[i]
+        } else {
+            self.len() - self.lines[i]
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.len() + 1
+    }
+
+    fn line_start(&self, line: usize) -> usize {
+        match line {
+            0 => 0,
+            _ => self.entry(line - 1),
+        }
+    }
+
+    // 一行的范围，不包括换行符
+    fn line_range(&self, line: usize) -> Range<usize> {
+        let end = if line + 1 < self.line_count() {
+            self.line_start(line + 1) - 1
+        } else {
+            self.len()
+        };
+        self.line_start(line)..end
+    }
+
+    // 偏移量所在的行，二分查找起点不大于偏移量的行索引项
+    fn line_of(&self, offset: usize) -> usize {
+        let (mut lo, mut hi) = (0, self.lines.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.entry(mid) <= offset {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    // 第 line 行的内容，不包括换行符
+    pub fn line(&self, line: usize) -> Option<Cow<'_, str>> {
+        (line < self.line_count()).then(|| self.text_range(self.line_range(line)))
+    }
+
+    // 遍历所有行，除了跨过光标的那一行，都不需要复制
+    pub fn lines(&self) -> Lines<'_> {
+        Lines {
+            buf: self,
+            lines: 0..self.line_count(),
+        }
+    }
+
+    // 偏移量对应的行号和列号，都从 0 开始，列号按字符计算
+    pub fn line_col(&self, offset: usize) -> Result<(usize, usize)> {
+        self.check_offset(offset)?;
+        let line = self.line_of(offset);
+        let col = self.text_range(self.line_start(line)..offset).chars().count();
+        Ok((line, col))
+    }
+
+    // 行号和列号对应的偏移量，列号可以等于行的长度，表示行尾
+    pub fn offset(&self, line: usize, col: usize) -> Result<usize> {
+        if line >= self.line_count() {
+            return Err(Error::OutOfBounds {
+                index: line,
+                len: self.line_count(),
+            });
+        }
+        let range = self.line_range(line);
+        let text = self.text_range(range.clone());
+        let mut offsets = text.char_indices().map(|(i, _)| range.start + i).chain([range.end]);
+        offsets.nth(col).ok_or_else(|| Error::OutOfBounds {
+            index: col,
+            len: text.chars().count(),
+        })
+    }
+
+    // 移动光标，行索引中跨过光标的项在偏移量和到末尾的距离之间转换
+    fn move_to(&mut self, offset: usize) {
+        self.text.set_position(offset).unwrap();
+        let old = self.lines.position();
+        let mut new = old;
+        while new < self.lines.len() && self.entry(new) <= offset {
+            new += 1;
+        }
+        while new > 0 && self.entry(new - 1) > offset {
+            new -= 1;
+        }
+        self.lines.set_position(new).unwrap();
+        let len = self.len();
+        for i in old.min(new)..old.max(new) {
+            self.lines[i] = len - self.lines[i];
+        }
+    }
+
+    pub fn set_cursor(&mut self, offset: usize) -> Result<()> {
+        self.check_offset(offset)?;
+        self.goal = None;
+        self.move_to(offset);
+        Ok(())
+    }
+
+    // 在光标处插入文本，光标移动到插入的文本后面
+    pub fn insert_str(&mut self, s: &str) {
+        let cursor = self.cursor();
+        self.goal = None;
+        self.text.extend(s.bytes());
+        // 间隙后面的行起点保存为到末尾的距离，不需要修改
+        for (i, _) in s.match_indices('\n') {
+            self.lines.insert(cursor + i + 1);
+        }
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        self.insert_str(c.encode_utf8(&mut [0; 4]));
+    }
+
+    // 删除范围内的文本并返回，光标移动到范围的起点
+    pub fn drain(&mut self, range: Range<usize>) -> Result<String> {
+        self.slice(range.clone())?;
+        self.goal = None;
+        self.move_to(range.start);
+        // 删除的换行符对应的行起点都在间隙后面
+        while self.lines.position() < self.lines.len() && self.entry(self.lines.position()) <= range.end {
+            self.lines.remove();
+        }
+        let bytes = self.text.drain(range)?;
+        Ok(String::from_utf8(bytes).expect("range is on char boundaries"))
+    }
+
+    // 删除光标前面的一个字素，相当于退格键
+    pub fn delete_backward(&mut self) -> Result<String> {
+        let cursor = self.cursor();
+        if cursor == 0 {
+            return Err(Error::AtStart);
+        }
+        self.drain(self.prev_grapheme(cursor)..cursor)
+    }
+
+    // 删除光标后面的一个字素，光标在末尾时返回 None
+    pub fn delete_forward(&mut self) -> Option<String> {
+        let cursor = self.cursor();
+        if cursor == self.len() {
+            return None;
+        }
+        self.drain(cursor..self.next_grapheme(cursor)).ok()
+    }
+
+    // 字素（用户看到的一个字符）的边界，例如 e + 组合重音符、国旗都是一个字素
+    // GraphemeCursor 可以分段处理文本，间隙前后的两段分别提供给它
+    fn grapheme_boundary(&self, offset: usize, forward: bool) -> usize {
+        let (before, after) = self.halves();
+        let mid = before.len();
+        // 包含 offset 后面（向前时是前面）字符的一段
+        let chunk = |offset: usize, forward: bool| {
+            if offset < mid || (!forward && offset == mid) {
+                (before, 0)
+            } else {
+                (after, mid)
+            }
+        };
+        let mut cursor = GraphemeCursor::new(offset, self.len(), true);
+        loop {
+            let (text, start) = chunk(cursor.cur_cursor(), forward);
+            let result = if forward {
+                cursor.next_boundary(text, start)
+            } else {
+                cursor.prev_boundary(text, start)
+            };
+            match result {
+                Ok(Some(boundary)) => return boundary,
+                Ok(None) => return if forward { self.len() } else { 0 },
+                Err(GraphemeIncomplete::NextChunk | GraphemeIncomplete::PrevChunk) => {}
+                // 需要 n 前面的文本才能判断
+                Err(GraphemeIncomplete::PreContext(n)) => {
+                    let (text, start) = chunk(n, false);
+                    cursor.provide_context(&text[..n - start], start);
+                }
+                Err(GraphemeIncomplete::InvalidOffset) => unreachable!(),
+            }
+        }
+    }
+
+    fn next_grapheme(&self, offset: usize) -> usize {
+        self.grapheme_boundary(offset, true)
+    }
+
+    fn prev_grapheme(&self, offset: usize) -> usize {
+        self.grapheme_boundary(offset, false)
+    }
+
+    pub fn move_left(&mut self) {
+        let offset = self.prev_grapheme(self.cursor());
+        self.goal = None;
+        self.move_to(offset);
+    }
+
+    pub fn move_right(&mut self) {
+        let offset = self.next_grapheme(self.cursor());
+        self.goal = None;
+        self.move_to(offset);
+    }
+
+    // 移动到上一个单词的开头
+    pub fn move_word_left(&mut self) {
+        let offset = self.cursor() - skip_word(self.chars_before(self.cursor()));
+        self.goal = None;
+        self.move_to(offset);
+    }
+
+    // 移动到下一个单词的结尾
+    pub fn move_word_right(&mut self) {
+        let offset = self.cursor() + skip_word(self.chars_after(self.cursor()));
+        self.goal = None;
+        self.move_to(offset);
+    }
+
+    pub fn move_line_start(&mut self) {
+        let offset = self.line_start(self.line_of(self.cursor()));
+        self.goal = None;
+        self.move_to(offset);
+    }
+
+    pub fn move_line_end(&mut self) {
+        let offset = self.line_range(self.line_of(self.cursor())).end;
+        self.goal = None;
+        self.move_to(offset);
+    }
+
+    // 移动到上一行，列号保持不变，上一行比较短时移动到行尾
+    // 连续上下移动时经过短的行之后仍然回到原来的列
+    pub fn move_up(&mut self) {
+        let (line, col) = self.line_col(self.cursor()).unwrap();
+        match line {
+            0 => self.set_cursor(0).unwrap(),
+            _ => self.move_vertical(line - 1, col),
+        }
+    }
+
+    pub fn move_down(&mut self) {
+        let (line, col) = self.line_col(self.cursor()).unwrap();
+        if line + 1 == self.line_count() {
+            self.set_cursor(self.len()).unwrap();
+        } else {
+            self.move_vertical(line + 1, col);
+        }
+    }
+
+    fn move_vertical(&mut self, line: usize, col: usize) {
+        let goal = self.goal.unwrap_or(col);
+        let range = self.line_range(line);
+        let offset = self.chars_after(range.start).take(goal).map(char::len_utf8).sum::<usize>();
+        self.move_to((range.start + offset).min(range.end));
+        self.goal = Some(goal);
+    }
+}
+
+// 跳过空白，再跳过一个单词或者一串标点，返回跳过的字节数
+fn skip_word(chars: impl Iterator<Item = char>) -> usize {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut chars = chars.peekable();
+    let mut len = 0;
+    while let Some(c) = chars.next_if(|c| c.is_whitespace()) {
+        len += c.len_utf8();
+    }
+    if let Some(word) = chars.peek().map(|&c| is_word(c)) {
+        while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && is_word(c) == word) {
+            len += c.len_utf8();
+        }
+    }
+    len
+}
+
+impl From<&str> for TextBuffer {
+    fn from(s: &str) -> Self {
+        let mut buf = TextBuffer::new();
+        buf.insert_str(s);
+        buf
+    }
+}
+
+impl Display for TextBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (before, after) = self.halves();
+        write!(f, "{}{}", before, after)
+    }
+}
+
+// 按行遍历文本，不包括换行符
+pub struct Lines<'a> {
+    buf: &'a TextBuffer,
+    lines: Range<usize>,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Cow<'a, str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lines.next().map(|line| self.buf.text_range(self.buf.line_range(line)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.lines.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Lines<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(buf: &TextBuffer) -> Vec<String> {
+        buf.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_line_index() {
+        let mut buf = TextBuffer::from("Lord\nof the\nRings");
+        assert_eq!(buf.line_count(), 3);
+        assert_eq!(lines(&buf), ["Lord", "of the", "Rings"]);
+        buf.set_cursor(7).unwrap();
+        buf.insert_str("\n");
+        assert_eq!(lines(&buf), ["Lord", "of", " the", "Rings"]);
+        // 只有光标所在的行跨过间隙时才需要复制
+        assert!(buf.lines().all(|line| matches!(line, Cow::Borrowed(_))));
+        buf.set_cursor(10).unwrap();
+        assert!(matches!(buf.line(2), Some(Cow::Owned(_))));
+        assert_eq!(buf.line(3).as_deref(), Some("Rings"));
+        assert_eq!(buf.line(4), None);
+
+        assert_eq!(buf.drain(2..9), Ok("rd\nof\n ".into()));
+        assert_eq!(lines(&buf), ["Lothe", "Rings"]);
+        assert_eq!(buf.cursor(), 2);
+        buf.insert_str("\n\n");
+        buf.set_cursor(buf.len()).unwrap();
+        buf.insert_char('\n');
+        assert_eq!(buf.to_string(), "Lo\n\nthe\nRings\n");
+        assert_eq!(lines(&buf), ["Lo", "", "the", "Rings", ""]);
+        assert_eq!(TextBuffer::new().line_count(), 1);
+    }
+
+    #[test]
+    fn test_line_col() {
+        let mut buf = TextBuffer::from("héllo\nwörld\n");
+        buf.set_cursor(4).unwrap();
+        assert_eq!(buf.line_col(0), Ok((0, 0)));
+        assert_eq!(buf.line_col(6), Ok((0, 5)));
+        assert_eq!(buf.line_col(7), Ok((1, 0)));
+        assert_eq!(buf.line_col(10), Ok((1, 2)));
+        assert_eq!(buf.line_col(14), Ok((2, 0)));
+        assert_eq!(buf.line_col(2), Err(Error::NotCharBoundary(2)));
+        assert_eq!(buf.line_col(15), Err(Error::OutOfBounds { index: 15, len: 14 }));
+
+        assert_eq!(buf.offset(1, 2), Ok(10));
+        assert_eq!(buf.offset(1, 5), Ok(13));
+        assert_eq!(buf.offset(1, 6), Err(Error::OutOfBounds { index: 6, len: 5 }));
+        assert_eq!(buf.offset(2, 0), Ok(14));
+        assert_eq!(buf.offset(3, 0), Err(Error::OutOfBounds { index: 3, len: 3 }));
+        assert_eq!(buf.set_cursor(2), Err(Error::NotCharBoundary(2)));
+    }
+
+    #[test]
+    fn test_grapheme_moves() {
+        // e + 组合重音符，两个区域指示符组成的国旗
+        let text = "ae\u{301}\u{1F1E8}\u{1F1F3}b";
+        let mut buf = TextBuffer::from(text);
+        let mut stops = vec![buf.cursor()];
+        while buf.cursor() > 0 {
+            buf.move_left();
+            stops.push(buf.cursor());
+        }
+        assert_eq!(stops, [13, 12, 4, 1, 0]);
+        for &stop in stops.iter().rev().skip(1) {
+            buf.move_right();
+            assert_eq!(buf.cursor(), stop);
+        }
+
+        // 间隙在字素中间时也能找到正确的边界
+        buf.set_cursor(8).unwrap();
+        buf.move_right();
+        assert_eq!(buf.cursor(), 12);
+        buf.set_cursor(8).unwrap();
+        buf.move_left();
+        assert_eq!(buf.cursor(), 4);
+        buf.set_cursor(12).unwrap();
+        assert_eq!(buf.delete_backward(), Ok("\u{1F1E8}\u{1F1F3}".into()));
+        buf.set_cursor(2).unwrap();
+        assert_eq!(buf.delete_forward(), Some("\u{301}".into()));
+        buf.set_cursor(1).unwrap();
+        assert_eq!(buf.delete_forward(), Some("e".into()));
+        assert_eq!(buf.to_string(), "ab");
+        buf.set_cursor(0).unwrap();
+        assert_eq!(buf.delete_backward(), Err(Error::AtStart));
+        buf.set_cursor(2).unwrap();
+        assert_eq!(buf.delete_forward(), None);
+    }
+
+    #[test]
+    fn test_word_moves() {
+        let mut buf = TextBuffer::from("let x_1 = foo(bar);  ");
+        let mut stops = vec![];
+        while buf.cursor() > 0 {
+            buf.move_word_left();
+            stops.push(buf.cursor());
+        }
+        assert_eq!(stops, [17, 14, 13, 10, 8, 4, 0]);
+        let mut stops = vec![];
+        while buf.cursor() < buf.len() {
+            buf.move_word_right();
+            stops.push(buf.cursor());
+        }
+        assert_eq!(stops, [3, 7, 9, 13, 14, 17, 19, 21]);
+    }
+
+    #[test]
+    fn test_vertical_moves() {
+        let mut buf = TextBuffer::from("Lord of\nthe\nRings");
+        buf.set_cursor(6).unwrap();
+        buf.move_down();
+        assert_eq!(buf.line_col(buf.cursor()), Ok((1, 3)));
+        // 经过短的行之后回到原来的列
+        buf.move_down();
+        assert_eq!(buf.line_col(buf.cursor()), Ok((2, 5)));
+        buf.move_up();
+        buf.move_up();
+        assert_eq!(buf.cursor(), 6);
+        buf.move_up();
+        assert_eq!(buf.cursor(), 0);
+
+        buf.set_cursor(9).unwrap();
+        buf.move_line_end();
+        assert_eq!(buf.cursor(), 11);
+        buf.move_line_start();
+        assert_eq!(buf.cursor(), 8);
+        buf.move_down();
+        buf.move_down();
+        assert_eq!(buf.cursor(), buf.len());
+    }
+
+    #[test]
+    fn test_random_edits() {
+        // 和 String 比较随机编辑之后的结果
+        let mut seed = 42u64;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        let mut buf = TextBuffer::new();
+        let mut model = String::new();
+        for _ in 0..500 {
+            let boundaries = (0..=model.len()).filter(|&i| model.is_char_boundary(i)).collect::<Vec<_>>();
+            let pos = boundaries[random(boundaries.len())];
+            buf.set_cursor(pos).unwrap();
+            if random(3) == 0 && pos < model.len() {
+                let end = boundaries[random(boundaries.len())].max(pos);
+                assert_eq!(buf.drain(pos..end).unwrap(), model[pos..end]);
+                model.replace_range(pos..end, "");
+            } else {
+                let s = ["a", "\n", "é\n", "ab\ncd", "中文"][random(5)];
+                buf.insert_str(s);
+                model.insert_str(pos, s);
+            }
+            assert_eq!(buf.to_string(), model);
+            assert_eq!(lines(&buf), model.split('\n').collect::<Vec<_>>());
+            let mut offset = random(model.len() + 1);
+            while !model.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            let line = model[..offset].matches('\n').count();
+            assert_eq!(buf.line_col(offset).map(|(line, _)| line), Ok(line));
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use crate::{Error, GapBuffer, Result};
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::ops::{Deref, Range};
+
+// 默认最多使用 16MB 保存编辑历史
+const DEFAULT_LIMIT: usize = 16 << 20;
+
+// 撤销一次编辑需要执行的操作，执行之后得到重做这次编辑需要的操作
+// 元素在缓冲区和编辑记录之间移动，不需要 T: Clone
+enum Edit<T> {
+    // 插入的元素，撤销时删除
+    Remove { at: usize, len: usize },
+    // 删除的元素，撤销时重新插入
+    Insert { at: usize, items: Vec<T> },
+}
+
+impl<T> Edit<T> {
+    // 编辑记录占用的内存
+    fn size(&self) -> usize {
+        match self {
+            Edit::Remove { .. } => size_of::<Self>(),
+            Edit::Insert { items, .. } => size_of::<Self>() + items.len() * size_of::<T>(),
+        }
+    }
+
+    // 执行操作，返回相反的操作
+    fn apply(self, buf: &mut GapBuffer<T>) -> Edit<T> {
+        match self {
+            Edit::Remove { at, len } => {
+                let items = buf.drain(at..at + len).expect("history out of sync with buffer");
+                Edit::Insert { at, items }
+            }
+            Edit::Insert { at, items } => {
+                let len = items.len();
+                buf.set_position(at).expect("history out of sync with buffer");
+                buf.insert_iter(items);
+                Edit::Remove { at, len }
+            }
+        }
+    }
+}
+
+// 一次撤销或者重做的编辑
+type Group<T> = Vec<Edit<T>>;
+
+fn group_size<T>(group: &Group<T>) -> usize {
+    group.iter().map(Edit::size).sum()
+}
+
+// 可以撤销的间隙缓冲区，所有的修改都记录在编辑历史中
+// 通过 Deref 可以使用 GapBuffer 的只读方法
+pub struct UndoBuffer<T> {
+    buf: GapBuffer<T>,
+    // 最早的编辑在前面
+    undo: VecDeque<Group<T>>,
+    redo: Vec<Group<T>>,
+    // begin_group 的嵌套层数，大于 0 时所有的编辑都加入最后一组
+    depth: usize,
+    // 上一次编辑是在光标处插入单个元素，下一次插入可以合并到同一条记录
+    typing: bool,
+    // 编辑历史占用的内存和上限
+    size: usize,
+    limit: usize,
+}
+
+impl<T> Default for UndoBuffer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> UndoBuffer<T> {
+    pub fn new() -> Self {
+        Self {
+            buf: GapBuffer::new(),
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            depth: 0,
+            typing: false,
+            size: 0,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+
+    // 设置编辑历史的内存上限（字节），超出时丢弃最早的编辑
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.trim();
+    }
+
+    // 编辑历史估计占用的内存
+    pub fn history_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    // 开始一组编辑，之后到 end_group 之间的编辑作为一个整体撤销，可以嵌套
+    pub fn begin_group(&mut self) {
+        if self.depth == 0 {
+            self.undo.push_back(Group::new());
+            self.typing = false;
+        }
+        self.depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+        self.depth -= 1;
+        if self.depth == 0 {
+            if self.undo.back().is_some_and(Vec::is_empty) {
+                self.undo.pop_back();
+            }
+            self.typing = false;
+            self.trim();
+        }
+    }
+
+    // 记录一次编辑，新的编辑会清空重做的历史
+    fn record(&mut self, edit: Edit<T>) {
+        self.size += edit.size();
+        for group in std::mem::take(&mut self.redo) {
+            self.size -= group_size(&group);
+        }
+        match self.undo.back_mut() {
+            Some(group) if self.depth > 0 => group.push(edit),
+            _ => self.undo.push_back(vec![edit]),
+        }
+        if self.depth == 0 {
+            self.trim();
+        }
+    }
+
+    // 超出内存上限时先从最早的编辑开始丢弃，然后丢弃最远的重做，正在记录的一组不会丢弃
+    // 撤销和重做之后记录中可能保存了删除的元素，也需要检查
+    fn trim(&mut self) {
+        let open = usize::from(self.depth > 0);
+        while self.size > self.limit {
+            let group = if self.undo.len() > open {
+                self.undo.pop_front().unwrap()
+            } else if !self.redo.is_empty() {
+                self.redo.remove(0)
+            } else {
+                break;
+            };
+            self.size -= group_size(&group);
+        }
+    }
+
+    // 倒序执行一组操作，得到相反的一组操作
+    fn replay(&mut self, group: Group<T>) -> Group<T> {
+        let old = group_size(&group);
+        let inverse = group.into_iter().rev().map(|edit| edit.apply(&mut self.buf)).collect::<Group<T>>();
+        self.size = self.size - old + group_size(&inverse);
+        inverse
+    }
+
+    // 撤销最近的一组编辑，没有可以撤销的编辑时返回 false
+    pub fn undo(&mut self) -> bool {
+        while self.depth > 0 {
+            self.end_group();
+        }
+        self.typing = false;
+        let Some(group) = self.undo.pop_back() else {
+            return false;
+        };
+        let inverse = self.replay(group);
+        self.redo.push(inverse);
+        self.trim();
+        true
+    }
+
+    pub fn redo(&mut self) -> bool {
+        while self.depth > 0 {
+            self.end_group();
+        }
+        self.typing = false;
+        let Some(group) = self.redo.pop() else {
+            return false;
+        };
+        let inverse = self.replay(group);
+        self.undo.push_back(inverse);
+        self.trim();
+        true
+    }
+
+    // 移动光标会结束连续的输入
+    pub fn set_position(&mut self, pos: usize) -> Result<()> {
+        self.buf.set_position(pos)?;
+        self.typing = false;
+        Ok(())
+    }
+
+    // 连续在光标处插入的元素合并为一条记录，一次撤销
+    pub fn insert(&mut self, val: T) {
+        let at = self.buf.position();
+        self.buf.insert(val);
+        if self.typing {
+            if let Some(Some(Edit::Remove { at: start, len })) = self.undo.back_mut().map(|group| group.last_mut()) {
+                if *start + *len == at {
+                    *len += 1;
+                    return;
+                }
+            }
+        }
+        self.record(Edit::Remove { at, len: 1 });
+        self.typing = true;
+    }
+
+    // 一次插入多个元素，例如粘贴，单独作为一条记录
+    pub fn insert_iter<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let at = self.buf.position();
+        self.buf.insert_iter(iter);
+        let len = self.buf.position() - at;
+        self.typing = false;
+        if len > 0 {
+            self.record(Edit::Remove { at, len });
+        }
+    }
+
+    pub fn delete_range(&mut self, range: Range<usize>) -> Result<()> {
+        let at = range.start;
+        let items = self.buf.drain(range)?;
+        self.typing = false;
+        if !items.is_empty() {
+            self.record(Edit::Insert { at, items });
+        }
+        Ok(())
+    }
+
+    // 删除和插入作为一组，一次撤销
+    pub fn replace_range<I>(&mut self, range: Range<usize>, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.begin_group();
+        let result = self.delete_range(range);
+        if result.is_ok() {
+            self.insert_iter(iter);
+        }
+        self.end_group();
+        result
+    }
+
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.buf.len() {
+            return Err(Error::OutOfBounds {
+                index: len,
+                len: self.buf.len(),
+            });
+        }
+        self.delete_range(len..self.buf.len())
+    }
+
+    pub fn clear(&mut self) {
+        self.delete_range(0..self.buf.len()).unwrap();
+    }
+}
+
+// 删除的元素同时需要返回和保存在编辑历史中
+impl<T: Clone> UndoBuffer<T> {
+    pub fn drain(&mut self, range: Range<usize>) -> Result<Vec<T>> {
+        let at = range.start;
+        let items = self.buf.drain(range)?;
+        self.typing = false;
+        if !items.is_empty() {
+            self.record(Edit::Insert { at, items: items.clone() });
+        }
+        Ok(items)
+    }
+
+    pub fn remove(&mut self) -> Option<T> {
+        let pos = self.buf.position();
+        self.drain(pos..pos + 1).ok().map(|mut items| items.remove(0))
+    }
+
+    pub fn delete_backward(&mut self) -> Result<T> {
+        let pos = self.buf.position();
+        if pos == 0 {
+            return Err(Error::AtStart);
+        }
+        self.drain(pos - 1..pos).map(|mut items| items.remove(0))
+    }
+}
+
+impl<T> Deref for UndoBuffer<T> {
+    type Target = GapBuffer<T>;
+
+    fn deref(&self) -> &GapBuffer<T> {
+        &self.buf
+    }
+}
+
+impl<T> From<GapBuffer<T>> for UndoBuffer<T> {
+    fn from(buf: GapBuffer<T>) -> Self {
+        Self { buf, ..Self::new() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    fn text(buf: &UndoBuffer<char>) -> String {
+        buf.iter().collect()
+    }
+
+    fn typed(s: &str) -> UndoBuffer<char> {
+        let mut buf = UndoBuffer::new();
+        for c in s.chars() {
+            buf.insert(c);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_coalesce_typing() {
+        let mut buf = typed("Lord");
+        buf.set_position(0).unwrap();
+        buf.insert('!');
+        buf.insert('!');
+        buf.set_position(6).unwrap();
+        buf.insert_iter(" of the Rings".chars());
+        assert_eq!(text(&buf), "!!Lord of the Rings");
+
+        assert!(buf.undo());
+        assert_eq!(text(&buf), "!!Lord");
+        assert!(buf.undo());
+        assert_eq!(text(&buf), "Lord");
+        assert!(buf.undo());
+        assert_eq!(text(&buf), "");
+        assert!(!buf.undo());
+
+        assert!(buf.redo());
+        assert!(buf.redo());
+        assert_eq!(text(&buf), "!!Lord");
+        assert_eq!(buf.position(), 2);
+        // 新的编辑清空重做的历史
+        buf.insert('?');
+        assert!(!buf.redo());
+        assert!(buf.undo());
+        assert_eq!(text(&buf), "!!Lord");
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut buf = typed("Lord of the Rings");
+        buf.set_position(4).unwrap();
+        assert_eq!(buf.delete_backward(), Ok('d'));
+        assert_eq!(buf.remove(), Some(' '));
+        buf.delete_range(0..3).unwrap();
+        buf.replace_range(3..12, "Kings".chars()).unwrap();
+        assert_eq!(text(&buf), "of Kings");
+
+        let mut history = vec![];
+        while buf.undo() {
+            history.push(text(&buf));
+        }
+        assert_eq!(history, ["of the Rings", "Lorof the Rings", "Lor of the Rings", "Lord of the Rings", ""]);
+        while buf.redo() {}
+        assert_eq!(text(&buf), "of Kings");
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut buf = typed("abc");
+        buf.begin_group();
+        buf.insert('d');
+        buf.begin_group();
+        buf.set_position(0).unwrap();
+        buf.delete_range(0..1).unwrap();
+        buf.end_group();
+        buf.insert_iter("xy".chars());
+        buf.end_group();
+        // 没有编辑的一组不会记录
+        buf.begin_group();
+        buf.end_group();
+        buf.end_group();
+        assert_eq!(text(&buf), "xybcd");
+
+        assert!(buf.undo());
+        assert_eq!(text(&buf), "abc");
+        assert!(buf.redo());
+        assert_eq!(text(&buf), "xybcd");
+        assert!(buf.undo());
+        assert!(buf.undo());
+        assert!(!buf.can_undo());
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut buf = UndoBuffer::<u64>::new();
+        let edit = size_of::<Edit<u64>>();
+        let limit = 3 * edit;
+        buf.set_history_limit(limit);
+        for i in 0..5 {
+            buf.set_position(0).unwrap();
+            buf.insert(i);
+        }
+        assert_eq!(buf.history_size(), limit);
+
+        // 撤销之后删除的元素保存在重做的历史中，也计算在内，最早的编辑被丢弃
+        let mut undone = 0;
+        while buf.undo() {
+            undone += 1;
+            assert!(buf.history_size() <= limit);
+        }
+        assert_eq!(undone, 2);
+        assert_eq!(buf.iter().copied().collect::<Vec<_>>(), [2, 1, 0]);
+        assert_eq!(buf.history_size(), 2 * (edit + 8));
+        buf.set_history_limit(edit + 8);
+        assert!(buf.redo());
+        assert!(!buf.can_redo());
+        assert!(buf.history_size() <= edit + 8);
+
+        // 撤销很大的粘贴之后，保存删除的元素会超出上限，不能再重做
+        buf.insert_iter(0..100);
+        assert!(buf.undo());
+        assert_eq!(buf.len(), 4);
+        assert!(!buf.can_redo());
+        assert!(buf.history_size() <= edit + 8);
+
+        // 超出上限的一次编辑不能撤销
+        buf.clear();
+        assert_eq!(buf.history_size(), 0);
+        buf.insert(7);
+        assert_eq!(buf.history_size(), edit);
+        assert!(buf.undo());
+        assert!(!buf.undo());
+    }
+
+    #[test]
+    fn test_drop_once() {
+        let item = Rc::new(());
+        let mut buf = UndoBuffer::new();
+        buf.insert_iter((0..4).map(|_| item.clone()));
+        buf.delete_range(1..3).unwrap();
+        assert_eq!(Rc::strong_count(&item), 5);
+        // 删除的元素移动到编辑历史中，撤销和重做都不会复制
+        buf.undo();
+        buf.undo();
+        assert!(buf.is_empty());
+        assert_eq!(Rc::strong_count(&item), 5);
+        buf.redo();
+        assert_eq!(Rc::strong_count(&item), 5);
+        buf.insert(item.clone());
+        assert_eq!(Rc::strong_count(&item), 6);
+        drop(buf);
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+}
@@ -0,0 +1,109 @@
+use mvcc::client::Client;
+use mvcc::protocol::{KvResult, TxnMode};
+use std::io::{self, BufRead, Write};
+
+fn main() -> KvResult<()> {
+    let address = std::env::args().nth(1).expect("Usage: client ADDRESS:PORT");
+    let mut client = Client::connect(address)?;
+
+    println!("Commands:\n\
+              begin [serializable | readonly | asof VERSION]\n\
+              get KEY\n\
+              set KEY VALUE...\n\
+              delete KEY\n\
+              scan [START [END]]\n\
+              commit\n\
+              rollback\n\
+              Type Control-D (on Unix) or Control-Z (on Windows) \
+              to close the connection.");
+
+    let stdin = io::stdin();
+    prompt()?;
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if let Err(e) = execute(&mut client, &line) {
+            println!("Error: {}", e);
+        }
+        prompt()?;
+    }
+    Ok(())
+}
+
+fn prompt() -> io::Result<()> {
+    print!("mvcc> ");
+    io::stdout().flush()
+}
+
+/// Parse a line from the standard input and send it to the server as a request.
+fn execute(client: &mut Client, line: &str) -> KvResult<()> {
+    let Some((command, rest)) = get_next_token(line) else {
+        return Ok(());
+    };
+    match command {
+        "begin" => {
+            let mode = match get_next_token(rest) {
+                None => TxnMode::Snapshot,
+                Some(("serializable", _)) => TxnMode::Serializable,
+                Some(("readonly", _)) => TxnMode::ReadOnly,
+                Some(("asof", rest)) => {
+                    let (version, _) = get_next_token(rest).ok_or("missing version")?;
+                    TxnMode::AsOf(version.parse()?)
+                }
+                Some((mode, _)) => return Err(format!("unknown mode: {}", mode).into()),
+            };
+            client.begin(mode)?;
+            println!("OK");
+        }
+        "get" => {
+            let (key, _) = get_next_token(rest).ok_or("missing key")?;
+            match client.get(key)? {
+                Some(value) => println!("{}", value),
+                None => println!("(nil)"),
+            }
+        }
+        "set" => {
+            let (key, rest) = get_next_token(rest).ok_or("missing key")?;
+            client.set(key, rest.trim())?;
+            println!("OK");
+        }
+        "delete" => {
+            let (key, _) = get_next_token(rest).ok_or("missing key")?;
+            client.delete(key)?;
+            println!("OK");
+        }
+        "scan" => {
+            let (start, rest) = get_next_token(rest).unzip();
+            let end = rest.and_then(get_next_token).map(|(end, _)| end);
+            for (key, value) in client.scan(start, end)? {
+                println!("{}={}", key, value);
+            }
+        }
+        "commit" => {
+            client.commit()?;
+            println!("OK");
+        }
+        "rollback" => {
+            client.rollback()?;
+            println!("OK");
+        }
+        _ => return Err(format!("Unrecognized command: {:?}", line).into()),
+    }
+    Ok(())
+}
+
+/// Given a string `input`, return `Some((token, rest))`, where `token` is the
+/// first run of non-whitespace characters in `input`, and `rest` is the rest of
+/// the string. If the string contains no non-whitespace characters, return
+/// `None`.
+fn get_next_token(mut input: &str) -> Option<(&str, &str)> {
+    input = input.trim_start();
+
+    if input.is_empty() {
+        return None;
+    }
+
+    match input.find(char::is_whitespace) {
+        Some(space) => Some((&input[0..space], &input[space..])),
+        None => Some((input, "")),
+    }
+}
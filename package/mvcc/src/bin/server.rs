@@ -0,0 +1,15 @@
+use mvcc::protocol::KvResult;
+use mvcc::{server, KVEngine, MVCC};
+use std::net::TcpListener;
+use std::time::Duration;
+
+fn main() -> KvResult<()> {
+    let address = std::env::args().nth(1).expect("Usage: server ADDRESS:PORT");
+
+    let mvcc = MVCC::new(KVEngine::new());
+    // 后台定期清理旧版本
+    let _vacuum = mvcc.start_vacuum(Duration::from_secs(10));
+
+    let listener = TcpListener::bind(address)?;
+    server::serve(listener, mvcc)
+}
@@ -0,0 +1,195 @@
+use crate::{commit_log, decode_key, Error, Key, KVEngine, Result, TxnState, MVCC};
+use std::{
+    collections::BTreeMap,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+// 一次写入，删除时 value 为 None
+pub type Write = (Vec<u8>, Option<Vec<u8>>);
+
+// 一个已提交事务的修改：提交序号，以及按 key 排序的写入
+pub type Change = (u64, Vec<Write>);
+
+impl MVCC {
+    // 订阅之后提交的修改，按照提交顺序推送，回滚的事务和没有写入的事务不会推送
+    pub fn subscribe(&self) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::channel();
+        self.state.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    // 从指定的提交序号之后继续订阅，先补发存储引擎中保留的历史修改，再推送新的修改
+    // 持有事务状态的锁完成补发和注册，期间不会有事务提交，修改既不会重复也不会遗漏
+    pub fn subscribe_from(&self, version: u64) -> Result<Receiver<Change>> {
+        let mut txn_state = self.state.lock().unwrap();
+        if version >= txn_state.next_commit {
+            return Err(Error::InvalidVersion(version));
+        }
+
+        let kv_engine = self.kv.lock().unwrap();
+        let commits = commit_log::committed_after(&kv_engine, version);
+        // 清理过的事务的写入可能已经不完整，提交序号是连续的，缺少的提交说明状态记录已经被清理
+        let missing = commits.len() as u64 != txn_state.next_commit - 1 - version;
+        if missing || commits.keys().any(|v| *v < txn_state.vacuumed) {
+            return Err(Error::HistoryUnavailable(version));
+        }
+
+        let mut history: BTreeMap<u64, Vec<Write>> = BTreeMap::new();
+        for (enc_key, value) in kv_engine.iter() {
+            let Some(key_version) = decode_key(enc_key) else {
+                continue;
+            };
+            if let Some(seq) = commits.get(&key_version.version) {
+                history.entry(*seq).or_default().push((key_version.raw_key, value.clone()));
+            }
+        }
+        drop(kv_engine);
+
+        let (sender, receiver) = mpsc::channel();
+        for (seq, mut writes) in history {
+            writes.sort();
+            sender.send((seq, writes)).unwrap();
+        }
+        txn_state.subscribers.push(sender);
+        Ok(receiver)
+    }
+}
+
+// 事务提交后推送修改，已经取消订阅的订阅者会被移除
+pub(crate) fn publish(txn_state: &mut TxnState, kv_engine: &KVEngine, version: u64, seq: u64, keys: &[Vec<u8>]) {
+    if keys.is_empty() || txn_state.subscribers.is_empty() {
+        return;
+    }
+
+    let mut writes: Vec<_> = keys
+        .iter()
+        .map(|k| {
+            let enc_key = Key {
+                raw_key: k.clone(),
+                version,
+            };
+            (k.clone(), kv_engine.get(&enc_key.encode()).cloned().flatten())
+        })
+        .collect();
+    writes.sort();
+    txn_state
+        .subscribers
+        .retain(|s: &Sender<Change>| s.send((seq, writes.clone())).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::thread;
+
+    fn change(seq: u64, writes: &[(&[u8], Option<&[u8]>)]) -> cdc::Change {
+        let writes = writes
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.map(|v| v.to_vec())))
+            .collect();
+        (seq, writes)
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let changes = mvcc.subscribe();
+
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.set(b"b", b"b1".to_vec()).unwrap();
+        tx1.set(b"a", b"a1".to_vec()).unwrap();
+        tx2.set(b"c", b"c2".to_vec()).unwrap();
+
+        // 回滚的事务和没有写入的事务不会推送
+        let tx3 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx3.set(b"d", b"d3".to_vec()).unwrap();
+        tx3.rollback();
+        mvcc.begin_transaction(IsolationLevel::Snapshot).commit().unwrap();
+
+        // 按照提交顺序推送，而不是开启顺序
+        tx2.commit().unwrap();
+        tx1.delete(b"b").unwrap();
+        tx1.commit().unwrap();
+        assert_eq!(changes.try_recv(), Ok(change(2, &[(b"c", Some(b"c2"))])));
+        assert_eq!(changes.try_recv(), Ok(change(3, &[(b"a", Some(b"a1")), (b"b", None)])));
+        assert!(changes.try_recv().is_err());
+
+        // 取消订阅后不再推送
+        drop(changes);
+        let tx4 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx4.set(b"a", b"a4".to_vec()).unwrap();
+        tx4.commit().unwrap();
+        assert!(mvcc.state.lock().unwrap().subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_from() {
+        let mvcc = MVCC::new(KVEngine::new());
+        for i in 1..=3 {
+            let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+            txn.set(b"a", format!("a{}", i).into_bytes()).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let changes = mvcc.subscribe_from(1).unwrap();
+        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        txn.set(b"b", b"b4".to_vec()).unwrap();
+        txn.commit().unwrap();
+        let received: Vec<_> = changes.try_iter().collect();
+        assert_eq!(
+            received,
+            vec![
+                change(2, &[(b"a", Some(b"a2"))]),
+                change(3, &[(b"a", Some(b"a3"))]),
+                change(4, &[(b"b", Some(b"b4"))]),
+            ]
+        );
+
+        // 重启后仍然可以从存储引擎中补发
+        let mvcc = MVCC::new(mvcc.export());
+        let received: Vec<_> = mvcc.subscribe_from(3).unwrap().try_iter().collect();
+        assert_eq!(received, vec![change(4, &[(b"b", Some(b"b4"))])]);
+        assert_eq!(mvcc.subscribe_from(5).err(), Some(Error::InvalidVersion(5)));
+
+        // 被清理过的历史不能再补发，最新的提交之后仍然可以订阅
+        mvcc.vacuum();
+        assert_eq!(mvcc.subscribe_from(0).err(), Some(Error::HistoryUnavailable(0)));
+        assert!(mvcc.subscribe_from(4).unwrap().try_recv().is_err());
+    }
+
+    #[test]
+    fn test_subscribe_concurrent() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let changes = mvcc.subscribe();
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let mvcc = mvcc.clone();
+                thread::spawn(move || {
+                    for j in 0..25 {
+                        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+                        txn.set(format!("k{}", i).as_bytes(), format!("{}", j).into_bytes()).unwrap();
+                        txn.commit().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // 提交序号连续递增，每个 key 的修改按顺序到达
+        let received: Vec<_> = changes.try_iter().collect();
+        let seqs: Vec<u64> = received.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, (1..=100).collect::<Vec<_>>());
+        for i in 0..4 {
+            let values: Vec<_> = received
+                .iter()
+                .flat_map(|(_, writes)| writes.iter())
+                .filter(|(k, _)| *k == format!("k{}", i).into_bytes())
+                .map(|(_, v)| String::from_utf8(v.clone().unwrap()).unwrap())
+                .collect();
+            assert_eq!(values, (0..25).map(|j| j.to_string()).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use crate::protocol::{self, KvResult, Request, Response, TxnMode};
+use std::{
+    io::BufReader,
+    net::{TcpStream, ToSocketAddrs},
+};
+
+// 事务型 KV 服务的客户端，一个连接同时只能有一个事务
+pub struct Client {
+    inbound: BufReader<TcpStream>,
+    outbound: TcpStream,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(address: A) -> KvResult<Self> {
+        let socket = TcpStream::connect(address)?;
+        socket.set_nodelay(true)?;
+        Ok(Self {
+            outbound: socket.try_clone()?,
+            inbound: BufReader::new(socket),
+        })
+    }
+
+    pub fn begin(&mut self, mode: TxnMode) -> KvResult<()> {
+        self.call(Request::Begin(mode)).map(|_| ())
+    }
+
+    pub fn get(&mut self, key: &str) -> KvResult<Option<String>> {
+        match self.call(Request::Get { key: key.to_string() })? {
+            Response::Value(value) => Ok(value),
+            response => Err(format!("unexpected response: {:?}", response).into()),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> KvResult<()> {
+        let request = Request::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        self.call(request).map(|_| ())
+    }
+
+    pub fn delete(&mut self, key: &str) -> KvResult<()> {
+        self.call(Request::Delete { key: key.to_string() }).map(|_| ())
+    }
+
+    // 扫描 [start, end) 范围内的数据
+    pub fn scan(&mut self, start: Option<&str>, end: Option<&str>) -> KvResult<Vec<(String, String)>> {
+        let request = Request::Scan {
+            start: start.map(str::to_string),
+            end: end.map(str::to_string),
+        };
+        match self.call(request)? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(format!("unexpected response: {:?}", response).into()),
+        }
+    }
+
+    pub fn commit(&mut self) -> KvResult<()> {
+        self.call(Request::Commit).map(|_| ())
+    }
+
+    pub fn rollback(&mut self) -> KvResult<()> {
+        self.call(Request::Rollback).map(|_| ())
+    }
+
+    // 发送请求并等待响应，服务端返回的错误转换为 Err
+    pub fn call(&mut self, request: Request) -> KvResult<Response> {
+        protocol::send_as_json(&mut self.outbound, &request)?;
+        match protocol::receive_as_json(&mut self.inbound)? {
+            Some(Response::Error(message)) => Err(message.into()),
+            Some(response) => Ok(response),
+            None => Err("connection closed by server".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server, KVEngine, MVCC};
+    use std::{io::Write, net::TcpListener, thread};
+
+    fn start_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || server::serve(listener, MVCC::new(KVEngine::new())));
+        address
+    }
+
+    // 其他连接的事务回滚之后才能写入，服务端在后台回滚，需要重试
+    fn write_eventually(address: &str, key: &str) -> bool {
+        let mut client = Client::connect(address).unwrap();
+        (0..100).any(|_| {
+            client.begin(TxnMode::Snapshot).unwrap();
+            if client.set(key, "written").is_ok() {
+                return client.commit().is_ok();
+            }
+            client.rollback().unwrap();
+            thread::sleep(std::time::Duration::from_millis(10));
+            false
+        })
+    }
+
+    #[test]
+    fn test_client_server() {
+        let address = start_server();
+        let mut c1 = Client::connect(&address).unwrap();
+        let mut c2 = Client::connect(&address).unwrap();
+
+        c1.begin(TxnMode::Snapshot).unwrap();
+        c1.set("a", "a1").unwrap();
+        c1.set("b", "b1").unwrap();
+        c1.delete("b").unwrap();
+        c1.set("c", "c1").unwrap();
+
+        // 每个连接有自己的事务，看不到其他连接未提交的修改
+        c2.begin(TxnMode::Snapshot).unwrap();
+        assert_eq!(c2.get("a").unwrap(), None);
+        c1.commit().unwrap();
+        assert_eq!(c2.get("a").unwrap(), None);
+        assert!(c2.set("a", "a2").is_err());
+        c2.rollback().unwrap();
+
+        c2.begin(TxnMode::ReadOnly).unwrap();
+        assert_eq!(c2.get("a").unwrap(), Some("a1".to_string()));
+        assert_eq!(
+            c2.scan(Some("b"), None).unwrap(),
+            vec![("c".to_string(), "c1".to_string())]
+        );
+        assert!(c2.set("d", "d1").is_err());
+        c2.commit().unwrap();
+    }
+
+    #[test]
+    fn test_session_errors() {
+        let address = start_server();
+        let mut client = Client::connect(&address).unwrap();
+        assert!(client.get("a").is_err());
+        assert!(client.commit().is_err());
+
+        client.begin(TxnMode::Snapshot).unwrap();
+        assert!(client.begin(TxnMode::Snapshot).is_err());
+        client.set("a", "a1").unwrap();
+
+        // 连接断开时回滚未提交的事务，之后其他连接可以写入同一个 key
+        drop(client);
+        assert!(write_eventually(&address, "a"));
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let address = start_server();
+        let mut client = Client::connect(&address).unwrap();
+        client.begin(TxnMode::Snapshot).unwrap();
+        client.set("a", "a1").unwrap();
+
+        // 无法解析的请求返回错误，事务仍然可以继续
+        client.outbound.write_all(b"garbage\n").unwrap();
+        let response = protocol::receive_as_json(&mut client.inbound).unwrap();
+        assert!(matches!(response, Some(Response::Error(_))));
+        assert_eq!(client.get("a").unwrap(), Some("a1".to_string()));
+
+        // 读取请求出错时服务端断开连接，同样回滚未提交的事务
+        client.outbound.write_all(b"\xff\xfe\n").unwrap();
+        assert!(protocol::receive_as_json::<_, Response>(&mut client.inbound).unwrap().is_none());
+        assert!(write_eventually(&address, "a"));
+    }
+}
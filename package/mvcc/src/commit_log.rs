@@ -0,0 +1,180 @@
+use crate::{decode_key, KVEngine, META_PREFIX, TXN_STATUS_PREFIX};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+// 事务的最终状态，和用户数据一起保存在存储引擎中
+// 没有状态记录的版本说明事务还没有结束，或者在结束前进程已经退出
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum TxnStatus {
+    // 已提交，记录提交的先后顺序
+    Committed(u64),
+    Aborted,
+}
+
+// 事务状态记录的 key，版本号使用大端序，保证按照版本排序
+fn status_key(version: u64) -> Vec<u8> {
+    let mut buf = vec![TXN_STATUS_PREFIX];
+    buf.extend(version.to_be_bytes());
+    buf
+}
+
+// 从事务状态记录的 key 中解析出版本号
+fn decode_status_key(b: &[u8]) -> Option<u64> {
+    match b.split_first() {
+        Some((&TXN_STATUS_PREFIX, rest)) => Some(u64::from_be_bytes(rest.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+// 记录事务的最终状态
+pub(crate) fn set_status(kv: &mut KVEngine, version: u64, status: TxnStatus) {
+    kv.insert(status_key(version), Some(bincode::serialize(&status).unwrap()));
+}
+
+// 查询事务的最终状态
+pub(crate) fn get_status(kv: &KVEngine, version: u64) -> Option<TxnStatus> {
+    match kv.get(&status_key(version)) {
+        Some(Some(v)) => Some(bincode::deserialize(v).unwrap()),
+        _ => None,
+    }
+}
+
+// 提交序号大于 seq 的已提交事务，返回版本号到提交序号的映射
+pub(crate) fn committed_after(kv: &KVEngine, seq: u64) -> BTreeMap<u64, u64> {
+    kv.range(vec![TXN_STATUS_PREFIX]..vec![TXN_STATUS_PREFIX + 1])
+        .filter_map(|(k, _)| decode_status_key(k))
+        .filter_map(|version| match get_status(kv, version) {
+            Some(TxnStatus::Committed(s)) if s > seq => Some((version, s)),
+            _ => None,
+        })
+        .collect()
+}
+
+// 保存清理时的低水位，重启之后仍然能判断哪些历史数据已经不完整
+pub(crate) fn set_vacuumed(kv: &mut KVEngine, low_water: u64) {
+    kv.insert(vec![META_PREFIX], Some(bincode::serialize(&low_water).unwrap()));
+}
+
+fn get_vacuumed(kv: &KVEngine) -> u64 {
+    match kv.get(&vec![META_PREFIX]) {
+        Some(Some(v)) => bincode::deserialize(v).unwrap(),
+        _ => 0,
+    }
+}
+
+// 清理不再需要的状态记录，在清理旧版本数据之后调用
+// 低水位之下的事务都已经结束，写入的数据全部删除之后，状态记录也不会再被读到
+// 提交序号最大的记录需要保留，重启后从它恢复下一个提交序号，保证提交序号连续
+pub(crate) fn prune(kv: &mut KVEngine, low_water: u64) {
+    let live: HashSet<u64> = kv.keys().filter_map(|k| decode_key(k)).map(|k| k.version).collect();
+    let mut latest = None;
+    let mut garbage = vec![];
+    for enc_key in kv.range(vec![TXN_STATUS_PREFIX]..vec![TXN_STATUS_PREFIX + 1]).map(|(k, _)| k) {
+        let Some(version) = decode_status_key(enc_key) else {
+            continue;
+        };
+        if let Some(TxnStatus::Committed(seq)) = get_status(kv, version) {
+            if latest.is_none_or(|(s, _)| seq > s) {
+                latest = Some((seq, version));
+            }
+        }
+        if version < low_water && !live.contains(&version) {
+            garbage.push(version);
+        }
+    }
+
+    for version in garbage {
+        if latest.is_none_or(|(_, v)| v != version) {
+            kv.remove(&status_key(version));
+        }
+    }
+}
+
+// 从存储引擎中恢复，返回下一个可用的版本号、提交序号，以及上一次清理时的低水位
+// 状态记录可能已经被清理，下一个版本号不小于清理时的低水位，避免重复使用版本号
+// 启动时不会有活跃的事务，没有状态记录的写入都来自崩溃前未提交的事务，
+// 它们不可能再提交了，记录为回滚并清除写入的数据
+pub(crate) fn recover(kv: &mut KVEngine) -> (u64, u64, u64) {
+    let vacuumed = get_vacuumed(kv);
+    let mut next_version = vacuumed.max(1);
+    let mut next_commit = 1;
+    let mut uncommitted = BTreeSet::new();
+    let mut garbage = vec![];
+    for enc_key in kv.keys() {
+        if let Some(version) = decode_status_key(enc_key) {
+            next_version = next_version.max(version + 1);
+            if let Some(TxnStatus::Committed(seq)) = get_status(kv, version) {
+                next_commit = next_commit.max(seq + 1);
+            }
+        } else if let Some(key_version) = decode_key(enc_key) {
+            next_version = next_version.max(key_version.version + 1);
+            if get_status(kv, key_version.version).is_none() {
+                uncommitted.insert(key_version.version);
+                garbage.push(enc_key.clone());
+            }
+        }
+    }
+
+    for enc_key in garbage {
+        kv.remove(&enc_key);
+    }
+    for version in uncommitted {
+        set_status(kv, version, TxnStatus::Aborted);
+    }
+    (next_version, next_commit, vacuumed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_restart() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.set(b"a", b"a1".to_vec()).unwrap();
+        tx1.commit().unwrap();
+
+        // T2 还没有提交，进程就退出了
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx2.set(b"a", b"a2".to_vec()).unwrap();
+        tx2.set(b"b", b"b2".to_vec()).unwrap();
+        let tx3 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx3.set(b"c", b"c3".to_vec()).unwrap();
+        tx3.rollback();
+        let kv = mvcc.export();
+
+        // 重启后 T2 的写入不可见，也不会和新的事务冲突
+        let mvcc = MVCC::new(kv);
+        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        assert!(txn.version > tx3.version);
+        assert_eq!(txn.scan(..), vec![(b"a".to_vec(), b"a1".to_vec())]);
+        txn.set(b"b", b"b4".to_vec()).unwrap();
+        txn.commit().unwrap();
+
+        let kv = mvcc.export();
+        assert_eq!(commit_log::get_status(&kv, tx1.version), Some(TxnStatus::Committed(1)));
+        assert_eq!(commit_log::get_status(&kv, tx2.version), Some(TxnStatus::Aborted));
+        assert_eq!(commit_log::get_status(&kv, tx3.version), Some(TxnStatus::Aborted));
+        assert_eq!(commit_log::get_status(&kv, txn.version), Some(TxnStatus::Committed(2)));
+    }
+
+    #[test]
+    fn test_uncommitted_invisible() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.set(b"a", b"a1".to_vec()).unwrap();
+        tx1.commit().unwrap();
+
+        // 直接在存储引擎中留下一条没有提交记录的写入
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx2.set(b"a", b"a2".to_vec()).unwrap();
+        mvcc.state.lock().unwrap().active.remove(&tx2.version);
+
+        // 即使不在活跃事务列表中，没有提交的版本也不可见
+        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        assert_eq!(txn.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(mvcc.begin_read_only().get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(mvcc.begin_as_of(tx2.version).unwrap().get(b"a"), Some(b"a1".to_vec()));
+    }
+}
@@ -0,0 +1,330 @@
+// 事务交错执行的测试工具
+// 用简单的脚本描述多个事务的操作顺序，语句之间用分号或者换行分隔，例如：
+//   T1 set a 1; T2 get a => nil; T1 commit; T2 get a => nil
+// `=>` 后面是期望的结果，和实际结果不一致时 panic
+//
+// 支持的语句：
+//   Tn begin [snapshot | serializable | readonly]   第一次使用时会用默认的隔离级别自动开启
+//   Tn get KEY                                     结果为 value 或 nil
+//   Tn set KEY VALUE / Tn delete KEY               结果为 ok 或 error
+//   Tn scan [START END]                            结果为 k=v,k=v，没有数据时为空
+//   Tn commit / Tn rollback                        结果为 ok 或 error
+//   vacuum                                         清理旧版本，结果为 ok
+use crate::{IsolationLevel, KVEngine, Transaction, MVCC};
+use std::collections::{BTreeMap, HashMap};
+
+pub struct Harness {
+    mvcc: MVCC,
+    txns: HashMap<String, Transaction>,
+    isolation: IsolationLevel,
+}
+
+impl Harness {
+    pub fn new(isolation: IsolationLevel) -> Self {
+        Self {
+            mvcc: MVCC::new(KVEngine::new()),
+            txns: HashMap::new(),
+            isolation,
+        }
+    }
+
+    // 执行脚本，返回每条语句的结果
+    pub fn run(&mut self, script: &str) -> Vec<String> {
+        let mut results = vec![];
+        for stmt in script.split([';', '\n']).map(str::trim).filter(|s| !s.is_empty()) {
+            let (stmt, expected) = match stmt.split_once("=>") {
+                Some((stmt, expected)) => (stmt.trim(), Some(expected.trim())),
+                None => (stmt, None),
+            };
+
+            let result = self.execute(stmt);
+            if let Some(expected) = expected {
+                assert_eq!(
+                    result, expected,
+                    "statement #{} `{}` got unexpected result\nscript:\n{}",
+                    results.len(), stmt, script
+                );
+            }
+            results.push(result);
+        }
+        results
+    }
+
+    fn execute(&mut self, stmt: &str) -> String {
+        let tokens: Vec<&str> = stmt.split_whitespace().collect();
+        if tokens == ["vacuum"] {
+            self.mvcc.vacuum();
+            return "ok".into();
+        }
+
+        let (name, op, args) = match tokens.as_slice() {
+            [name, op, args @ ..] => (name.to_string(), *op, args),
+            _ => panic!("invalid statement: {}", stmt),
+        };
+
+        if op == "begin" {
+            let txn = match args {
+                [] => self.mvcc.begin_transaction(self.isolation),
+                ["snapshot"] => self.mvcc.begin_transaction(IsolationLevel::Snapshot),
+                ["serializable"] => self.mvcc.begin_transaction(IsolationLevel::Serializable),
+                ["readonly"] => self.mvcc.begin_read_only(),
+                _ => panic!("invalid statement: {}", stmt),
+            };
+            assert!(self.txns.insert(name, txn).is_none(), "{} already began", stmt);
+            return "ok".into();
+        }
+
+        let isolation = self.isolation;
+        let txn = self
+            .txns
+            .entry(name.clone())
+            .or_insert_with(|| self.mvcc.begin_transaction(isolation));
+        let status = |res: crate::Result<()>| match res {
+            Ok(()) => "ok".to_string(),
+            Err(_) => "error".to_string(),
+        };
+        match (op, args) {
+            ("get", [key]) => match txn.get(key.as_bytes()) {
+                Some(v) => String::from_utf8(v).unwrap(),
+                None => "nil".into(),
+            },
+            ("set", [key, value]) => status(txn.set(key.as_bytes(), value.as_bytes().to_vec())),
+            ("delete", [key]) => status(txn.delete(key.as_bytes())),
+            ("scan", range) => {
+                let pairs = match range {
+                    [] => txn.scan(..),
+                    [start, end] => txn.scan(start.as_bytes().to_vec()..end.as_bytes().to_vec()),
+                    _ => panic!("invalid statement: {}", stmt),
+                };
+                pairs
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", String::from_utf8_lossy(k), String::from_utf8_lossy(v)))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }
+            ("commit", []) => status(self.txns.remove(&name).unwrap().commit()),
+            ("rollback", []) => {
+                self.txns.remove(&name).unwrap().rollback();
+                "ok".into()
+            }
+            _ => panic!("invalid statement: {}", stmt),
+        }
+    }
+}
+
+// 简单的伪随机数生成器，保证同一个种子生成同样的脚本
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+// 快照隔离的模型，用来计算随机脚本中每条语句期望的结果
+// 1. 事务读到开启时已经提交的数据，加上自己的写入
+// 2. 写入的 key 被其他活跃事务写过，或者在事务开启之后被其他事务提交过，写入失败
+#[derive(Default)]
+struct Model {
+    // 已经提交的数据
+    committed: BTreeMap<String, String>,
+    // 每个 key 最后一次被提交的序号
+    last_commit: HashMap<String, u64>,
+    // 已经提交的事务个数
+    commits: u64,
+    // 活跃的事务
+    txns: HashMap<usize, ModelTxn>,
+}
+
+struct ModelTxn {
+    read_only: bool,
+    begin: u64,
+    snapshot: BTreeMap<String, String>,
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl ModelTxn {
+    fn view(&self) -> BTreeMap<String, String> {
+        let mut view = self.snapshot.clone();
+        for (k, v) in self.writes.iter() {
+            match v {
+                Some(v) => view.insert(k.clone(), v.clone()),
+                None => view.remove(k),
+            };
+        }
+        view
+    }
+}
+
+// 生成随机的事务交错脚本，每条语句都带上模型计算出的期望结果
+fn random_script(seed: u64, steps: usize) -> String {
+    const KEYS: [&str; 4] = ["a", "b", "c", "d"];
+    let mut rng = Rng(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1);
+    let mut model = Model::default();
+    let mut script = vec![];
+
+    for step in 0..steps {
+        if rng.next(20) == 0 {
+            script.push("vacuum => ok".to_string());
+            continue;
+        }
+
+        let t = rng.next(4) as usize;
+        let name = format!("T{}", t);
+        let Some(txn) = model.txns.get_mut(&t) else {
+            let read_only = rng.next(4) == 0;
+            model.txns.insert(
+                t,
+                ModelTxn {
+                    read_only,
+                    begin: model.commits,
+                    snapshot: model.committed.clone(),
+                    writes: BTreeMap::new(),
+                },
+            );
+            let mode = if read_only { "readonly" } else { "snapshot" };
+            script.push(format!("{} begin {} => ok", name, mode));
+            continue;
+        };
+
+        let key = KEYS[rng.next(KEYS.len() as u64) as usize].to_string();
+        match rng.next(10) {
+            0..=3 => {
+                let value = txn.view().get(&key).cloned().unwrap_or("nil".into());
+                script.push(format!("{} get {} => {}", name, key, value));
+            }
+            4..=6 => {
+                let value = if rng.next(4) == 0 { None } else { Some(format!("{}{}", key, step)) };
+                let conflict = txn.read_only
+                    || model.last_commit.get(&key).is_some_and(|seq| *seq > txn.begin)
+                    || model.txns.iter().any(|(u, other)| *u != t && other.writes.contains_key(&key));
+
+                let txn = model.txns.get_mut(&t).unwrap();
+                let op = match &value {
+                    Some(v) => format!("set {} {}", key, v),
+                    None => format!("delete {}", key),
+                };
+                if conflict {
+                    // 写入失败后回滚事务
+                    script.push(format!("{} {} => error", name, op));
+                    script.push(format!("{} rollback => ok", name));
+                    model.txns.remove(&t);
+                } else {
+                    txn.writes.insert(key, value);
+                    script.push(format!("{} {} => ok", name, op));
+                }
+            }
+            7 => {
+                let view = txn.view();
+                let pairs: Vec<String> = view.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                script.push(format!("{} scan => {}", name, pairs.join(",")));
+            }
+            8 => {
+                let txn = model.txns.remove(&t).unwrap();
+                if !txn.writes.is_empty() {
+                    model.commits += 1;
+                }
+                for (k, v) in txn.writes {
+                    model.last_commit.insert(k.clone(), model.commits);
+                    match v {
+                        Some(v) => model.committed.insert(k, v),
+                        None => model.committed.remove(&k),
+                    };
+                }
+                script.push(format!("{} commit => ok", name));
+            }
+            _ => {
+                model.txns.remove(&t);
+                script.push(format!("{} rollback => ok", name));
+            }
+        }
+    }
+    script.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(script: &str) -> Vec<String> {
+        Harness::new(IsolationLevel::Snapshot).run(script)
+    }
+
+    fn serializable(script: &str) -> Vec<String> {
+        Harness::new(IsolationLevel::Serializable).run(script)
+    }
+
+    #[test]
+    fn test_dirty_read() {
+        let script = "T1 set a 1; T2 get a => nil; T2 scan => ; T1 commit; T2 get a => nil";
+        snapshot(script);
+        serializable(script);
+    }
+
+    #[test]
+    fn test_non_repeatable_read() {
+        let script = "T0 set a 0; T0 commit
+                      T1 get a => 0; T2 set a 1; T2 commit => ok; T1 get a => 0
+                      T3 get a => 1";
+        snapshot(script);
+        serializable(script);
+    }
+
+    #[test]
+    fn test_phantom_read() {
+        let script = "T0 set a1 x; T0 commit
+                      T1 scan a b => a1=x; T2 set a2 y; T2 commit; T1 scan a b => a1=x";
+        snapshot(script);
+        serializable(script);
+    }
+
+    #[test]
+    fn test_lost_update() {
+        // 两个事务读到同样的值后都去修改，后写入的事务失败
+        let script = "T0 set x 0; T0 commit
+                      T1 get x => 0; T2 get x => 0
+                      T1 set x 1 => ok; T2 set x 1 => error; T1 commit => ok
+                      T2 rollback; T3 get x => 1";
+        snapshot(script);
+        serializable(script);
+
+        // 先提交的事务修改过之后，快照之前开启的事务也不能再修改
+        snapshot("T0 set x 0; T0 commit; T1 get x => 0; T2 set x 1; T2 commit; T1 set x 1 => error");
+    }
+
+    #[test]
+    fn test_write_skew() {
+        let script = "T0 set alice on; T0 set bob on; T0 commit
+                      T1 get alice => on; T1 get bob => on
+                      T2 get alice => on; T2 get bob => on
+                      T1 set alice off; T2 set bob off
+                      T1 commit => ok";
+        assert_eq!(snapshot(&format!("{}; T2 commit", script)).last().unwrap(), "ok");
+        assert_eq!(serializable(&format!("{}; T2 commit", script)).last().unwrap(), "error");
+
+        // 读取时混用不同的隔离级别，只有可串行化的事务会检查读写冲突
+        let script = "T0 set alice on; T0 set bob on; T0 commit
+                      T1 begin serializable; T2 begin snapshot
+                      T1 get bob => on; T2 get alice => on
+                      T1 set alice off; T2 set bob off";
+        snapshot(&format!("{}; T2 commit => ok; T1 commit => error", script));
+        snapshot(&format!("{}; T1 commit => ok; T2 commit => ok", script));
+    }
+
+    #[test]
+    fn test_read_only() {
+        snapshot("T0 set a 1; T1 begin readonly; T0 commit; T1 get a => nil; T1 set a 2 => error
+                  T2 begin readonly; T2 get a => 1; T2 commit => ok");
+    }
+
+    #[test]
+    fn test_random_interleavings() {
+        for seed in 0..200 {
+            let script = random_script(seed, 200);
+            snapshot(&script);
+        }
+    }
+}
@@ -1,45 +1,199 @@
-use lazy_static::lazy_static;
+mod cdc;
+pub mod client;
+mod commit_log;
+#[cfg(test)]
+mod harness;
+mod lock;
+pub mod protocol;
+pub mod server;
+mod vacuum;
+
+use commit_log::TxnStatus;
+use lock::LockTable;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, Mutex,
-    },
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    ops::{Bound, RangeBounds},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
 };
 
 
 // 存储引擎定义，这里使用一个简单的内存 BTreeMap
 pub type KVEngine = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
 
-// 全局递增的版本号
-static VERSION: AtomicU64 = AtomicU64::new(1);
+pub use cdc::{Change, Write};
+pub use vacuum::VacuumHandle;
+
+// 自定义 Result 类型
+pub type Result<T> = std::result::Result<T, Error>;
+
+// 自定义错误类型
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // 和其他事务冲突，需要重试
+    Serialization,
+    // 只读事务不能写入数据
+    ReadOnly,
+    // 指定的版本还不存在
+    InvalidVersion(u64),
+    // 指定版本的历史数据已经被清理
+    HistoryUnavailable(u64),
+    // 等待行锁时出现死锁
+    Deadlock,
+    // 等待行锁超时
+    LockTimeout,
+    // 保存点不存在
+    NoSuchSavepoint(String),
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Serialization => write!(f, "serialization error, try again."),
+            Self::ReadOnly => write!(f, "cannot write in a read-only transaction"),
+            Self::InvalidVersion(v) => write!(f, "version {} does not exist yet", v),
+            Self::HistoryUnavailable(v) => write!(f, "history of version {} has been vacuumed", v),
+            Self::Deadlock => write!(f, "deadlock detected, try again."),
+            Self::LockTimeout => write!(f, "lock wait timeout, try again."),
+            Self::NoSuchSavepoint(name) => write!(f, "savepoint {} does not exist", name),
+        }
+    }
+}
+
+// 事务隔离级别
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IsolationLevel {
+    // 快照隔离，可能出现写偏斜
+    Snapshot,
+    // 可串行化，提交时检查读写冲突
+    Serializable,
+}
+
+// 活跃事务的信息
+struct ActiveTxn {
+    // 已经写入的 key
+    keys: Vec<Vec<u8>>,
+    // 开启时仍然活跃的最小版本号（包括自己）
+    xmin: u64,
+}
 
-// 获取下一个版本号
-fn acquire_next_version() -> u64 {
-    VERSION.fetch_add(1, Ordering::SeqCst)
+// 所有事务共享的状态
+struct TxnState {
+    // 下一个版本号
+    next_version: u64,
+    // 下一个提交序号，记录事务提交的先后顺序
+    next_commit: u64,
+    // 当前活跃的事务 id，及其信息
+    active: HashMap<u64, ActiveTxn>,
+    // 已提交事务写入的 key，可串行化事务提交时需要和它们做冲突检查
+    committed: BTreeMap<u64, Vec<Vec<u8>>>,
+    // 只读事务的 id，及其开启时仍然活跃的最小版本号
+    readers: HashMap<u64, u64>,
+    // 下一个只读事务的 id
+    next_reader: u64,
+    // 上一次清理时的低水位，比它更早的历史数据可能已经不完整
+    vacuumed: u64,
+    // 每个版本开启时的下一个提交序号，以及仍然活跃的最小版本号，用于读取历史数据
+    snapshots: BTreeMap<u64, (u64, u64)>,
+    // 订阅已提交修改的订阅者
+    subscribers: Vec<mpsc::Sender<Change>>,
 }
 
-lazy_static! {
-    // 当前活跃的事务 id，及其已经写入的 key 信息
-    static ref ACTIVE_TXN: Arc<Mutex<HashMap<u64, Vec<Vec<u8>>>>> = Arc::new(Mutex::new(HashMap::new()));
+impl TxnState {
+    fn new(next_version: u64, next_commit: u64, vacuumed: u64) -> Self {
+        Self {
+            next_version,
+            next_commit,
+            active: HashMap::new(),
+            committed: BTreeMap::new(),
+            readers: HashMap::new(),
+            next_reader: 1,
+            vacuumed,
+            snapshots: BTreeMap::new(),
+            subscribers: vec![],
+        }
+    }
+
+    // 获取下一个版本号
+    fn acquire_next_version(&mut self) -> u64 {
+        let version = self.next_version;
+        self.next_version += 1;
+        version
+    }
+
+    // 低水位：所有事务都能看到的版本的上界
+    // 比它小的版本一定已经提交，并且对任何活跃事务和只读事务都可见
+    fn low_water(&self) -> u64 {
+        self.active
+            .values()
+            .map(|txn| txn.xmin)
+            .chain(self.readers.values().cloned())
+            .min()
+            .unwrap_or(self.next_version)
+    }
+
+    // 清理已提交事务的写入记录
+    // 低水位之下的版本对任何事务都可见，不会再产生冲突
+    fn prune_committed(&mut self) {
+        let low_water = self.low_water();
+        self.committed = self.committed.split_off(&low_water);
+    }
 }
 
 // MVCC 事务定义
+#[derive(Clone)]
 pub struct MVCC {
     kv: Arc<Mutex<KVEngine>>,
+    state: Arc<Mutex<TxnState>>,
+    locks: Arc<LockTable>,
 }
 
 impl MVCC {
-    pub fn new(kv: KVEngine) -> Self {
+    // 存储引擎中可能是重启前保存的数据，需要先根据提交记录恢复
+    pub fn new(mut kv: KVEngine) -> Self {
+        let (next_version, next_commit, vacuumed) = commit_log::recover(&mut kv);
         Self {
             kv: Arc::new(Mutex::new(kv)),
+            state: Arc::new(Mutex::new(TxnState::new(next_version, next_commit, vacuumed))),
+            locks: Arc::new(LockTable::new()),
         }
     }
 
-    pub fn begin_transaction(&self) -> Transaction { Transaction::begin(self.kv.clone()) }
+    // 导出存储引擎中的数据，包括提交记录，可以用来持久化
+    pub fn export(&self) -> KVEngine {
+        self.kv.lock().unwrap().clone()
+    }
+
+    pub fn begin_transaction(&self, isolation: IsolationLevel) -> Transaction {
+        Transaction::begin(self, isolation)
+    }
+
+    // 开启只读事务，读取当前已提交的数据
+    pub fn begin_read_only(&self) -> Transaction {
+        Transaction::begin_read_only(self, None).unwrap()
+    }
+
+    // 开启只读事务，读取指定版本时的历史数据
+    pub fn begin_as_of(&self, version: u64) -> Result<Transaction> {
+        Transaction::begin_read_only(self, Some(version))
+    }
+
+    // 设置等待行锁的超时时间
+    pub fn set_lock_timeout(&self, timeout: Duration) {
+        self.locks.set_timeout(timeout);
+    }
 }
 
+// 存储引擎中 key 的前缀，用户数据和事务的提交记录保存在一起
+const DATA_PREFIX: u8 = 0;
+const TXN_STATUS_PREFIX: u8 = 1;
+// 清理时的低水位等元数据
+const META_PREFIX: u8 = 2;
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Key {
     raw_key: Vec<u8>,
@@ -47,122 +201,414 @@ struct Key {
 }
 
 impl Key {
-    fn encode(&self) -> Vec<u8> { bincode::serialize(self).unwrap() }
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![DATA_PREFIX];
+        bincode::serialize_into(&mut buf, self).unwrap();
+        buf
+    }
+}
+
+// 解析用户数据的 key，提交记录返回 None
+fn decode_key(b: &[u8]) -> Option<Key> {
+    match b.split_first() {
+        Some((&DATA_PREFIX, rest)) => Some(bincode::deserialize(rest).unwrap()),
+        _ => None,
+    }
 }
 
-fn decode_key(b: &Vec<u8>) -> Key { bincode::deserialize(&b).unwrap() }
+// 扫描的 key 范围
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// 事务读取过的 key 和扫描过的范围
+#[derive(Default)]
+struct ReadSet {
+    keys: BTreeSet<Vec<u8>>,
+    ranges: Vec<KeyRange>,
+}
+
+impl ReadSet {
+    // 判断 key 是否被读取过，或者落在扫描过的范围内
+    fn contains(&self, key: &Vec<u8>) -> bool {
+        self.keys.contains(key) || self.ranges.iter().any(|range| range.contains(key))
+    }
+}
+
+// 事务的一次写入，记录写入前自己的版本中的数据，用于回滚到保存点
+struct Undo {
+    key: Vec<u8>,
+    // 第一次写入这个 key 时为 None
+    previous: Option<Option<Vec<u8>>>,
+}
 
 pub struct Transaction {
     kv: Arc<Mutex<KVEngine>>,
+    state: Arc<Mutex<TxnState>>,
+    locks: Arc<LockTable>,
     version: u64,
     active_xid: HashSet<u64>,
+    isolation: IsolationLevel,
+    // 只读事务在读者列表中的 id，以及能看到的提交序号的上界
+    // 只读事务不在活跃事务列表中，也不能写入
+    reader: Option<(u64, u64)>,
+    // 可串行化模式下记录的读集合
+    reads: Mutex<ReadSet>,
+    // 按顺序记录的写入
+    undo: Mutex<Vec<Undo>>,
+    // 保存点的名字，及其创建时已经写入的次数
+    savepoints: Mutex<Vec<(String, usize)>>,
 }
 
 impl Transaction {
     // 开启事务
-    pub fn begin(kv: Arc<Mutex<KVEngine>>) -> Self {
-        let version = acquire_next_version();
-
-        let mut active_txn = ACTIVE_TXN.lock().unwrap();
+    fn begin(mvcc: &MVCC, isolation: IsolationLevel) -> Self {
+        let mut txn_state = mvcc.state.lock().unwrap();
+        let version = txn_state.acquire_next_version();
 
         // 这个 map 的 key 就是当前所有活跃的事务
-        let active_xid = active_txn.keys().cloned().collect();
+        let active_xid: HashSet<u64> = txn_state.active.keys().cloned().collect();
 
         // 添加到当前活跃事务 id 列表中
-        active_txn.insert(version, vec![]);
+        let xmin = active_xid.iter().cloned().min().unwrap_or(version);
+        txn_state.active.insert(version, ActiveTxn { keys: vec![], xmin });
+        let next_commit = txn_state.next_commit;
+        txn_state.snapshots.insert(version, (next_commit, xmin));
+        drop(txn_state);
 
         // 返回结果
         Self {
-            kv,
+            kv: mvcc.kv.clone(),
+            state: mvcc.state.clone(),
+            locks: mvcc.locks.clone(),
             version,
             active_xid,
+            isolation,
+            reader: None,
+            reads: Mutex::new(ReadSet::default()),
+            undo: Mutex::new(vec![]),
+            savepoints: Mutex::new(vec![]),
         }
     }
 
+    // 开启只读事务
+    // 只读事务不分配新的版本号，也不加入活跃事务列表，所以永远不会和其他事务冲突
+    // 不指定版本时读取当前已经提交的数据，否则读取下一个版本开启时已经提交的数据
+    // 历史数据按照提交序号划分，之后才提交的旧事务也不可见，所以重复读取的结果相同
+    fn begin_read_only(mvcc: &MVCC, as_of: Option<u64>) -> Result<Self> {
+        let mut txn_state = mvcc.state.lock().unwrap();
+        let latest = txn_state.next_version - 1;
+        let current = txn_state.active.keys().cloned().fold(txn_state.next_version, u64::min);
+        let (version, (next_commit, xmin)) = match as_of {
+            Some(v) if v > latest => return Err(Error::InvalidVersion(v)),
+            Some(v) if v < latest => {
+                // 清理后比 xmin 更早的版本可能已经被删除
+                // 重启之前的版本没有记录开启时的提交序号，同样无法读取
+                match txn_state.snapshots.get(&(v + 1)) {
+                    Some(&(next_commit, xmin)) if xmin >= txn_state.vacuumed => (v, (next_commit, xmin)),
+                    _ => return Err(Error::HistoryUnavailable(v)),
+                }
+            }
+            _ => (latest, (txn_state.next_commit, current)),
+        };
+
+        // 加入读者列表，防止需要读取的版本被清理
+        // 比 xmin 小的版本都在 next_commit 之前已经结束，清理时保留的版本仍然是需要读取的版本
+        let reader = txn_state.next_reader;
+        txn_state.next_reader += 1;
+        txn_state.readers.insert(reader, xmin);
+        drop(txn_state);
+
+        Ok(Self {
+            kv: mvcc.kv.clone(),
+            state: mvcc.state.clone(),
+            locks: mvcc.locks.clone(),
+            version,
+            active_xid: HashSet::new(),
+            isolation: IsolationLevel::Snapshot,
+            reader: Some((reader, next_commit)),
+            reads: Mutex::new(ReadSet::default()),
+            undo: Mutex::new(vec![]),
+            savepoints: Mutex::new(vec![]),
+        })
+    }
+
     // 写入数据
-    pub fn set(&self, key: &[u8], value: Vec<u8>) { self.write(key, Some(value)) }
+    pub fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> { self.write(key, Some(value)) }
 
     // 写入数据
-    pub fn delete(&self, key: &[u8]) { self.write(key, None) }
+    pub fn delete(&self, key: &[u8]) -> Result<()> { self.write(key, None) }
 
-    fn write(&self, key: &[u8], value: Option<Vec<u8>>) {
-        // 判断当前写入的 key 是否和其他的事务冲突
-        // key 是按照 key-version 排序的，所以只需要判断最近的一个 key 即可
+    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+        if self.reader.is_some() {
+            return Err(Error::ReadOnly);
+        }
+
+        // 先锁住事务状态，再锁住存储引擎，和提交、回滚保持相同的加锁顺序
+        let mut txn_state = self.state.lock().unwrap();
         let mut kv_engine = self.kv.lock().unwrap();
-        for (enc_key, _) in kv_engine.iter().rev() {
-            let key_version = decode_key(&enc_key);
-            if key_version.raw_key.eq(key) {
-                if !self.is_visible(key_version.version) {
-                    panic!("serialization error, try again.");
-                }
-                break;
+
+        // 被其他事务锁住的 key 不能写入，自己锁住的 key 可以覆盖已经提交的最新版本
+        // 持有事务状态的锁检查，select_for_update 锁住 key 之后的检查不会漏掉这次写入
+        let locked = match self.locks.owner(key) {
+            Some(owner) if owner != self.version => return Err(Error::Serialization),
+            Some(_) => true,
+            None => false,
+        };
+
+        // 判断当前写入的 key 是否和其他的事务冲突
+        // 如果 key 有当前事务看不到的版本（其他活跃事务的写入，或者快照之后才提交的写入），说明发生了冲突
+        // 已经回滚的版本跳过，自己锁住的 key 可以覆盖已经提交的版本
+        for enc_key in kv_engine.keys() {
+            let Some(key_version) = decode_key(enc_key) else {
+                continue;
+            };
+            if !key_version.raw_key.eq(key) {
+                continue;
+            }
+            match commit_log::get_status(&kv_engine, key_version.version) {
+                Some(TxnStatus::Aborted) => continue,
+                Some(TxnStatus::Committed(_)) if locked => continue,
+                _ => {}
+            }
+            if self.visible_order(&kv_engine, key_version.version).is_none() {
+                return Err(Error::Serialization);
             }
         }
 
-        // 写入 TxnWrite
-        let mut active_txn = ACTIVE_TXN.lock().unwrap();
-        active_txn
-            .entry(self.version)
-            .and_modify(|keys| keys.push(key.to_vec()))
-            .or_insert_with(|| vec![key.to_vec()]);
+        // 写入 TxnWrite，同一个 key 只记录一次
+        if let Some(txn) = txn_state.active.get_mut(&self.version) {
+            if !txn.keys.iter().any(|k| k.eq(key)) {
+                txn.keys.push(key.to_vec());
+            }
+        }
 
-        // 写入数据
+        // 写入数据，并记录写入前的数据
         let enc_key = Key {
             raw_key: key.to_vec(),
             version: self.version,
         };
-        kv_engine.insert(enc_key.encode(), value);
+        let previous = kv_engine.insert(enc_key.encode(), value);
+        self.undo.lock().unwrap().push(Undo {
+            key: key.to_vec(),
+            previous,
+        });
+        Ok(())
+    }
+
+    // 创建保存点，同名的保存点会覆盖之前的
+    pub fn savepoint(&self, name: &str) {
+        let writes = self.undo.lock().unwrap().len();
+        self.savepoints.lock().unwrap().push((name.to_string(), writes));
+    }
+
+    // 回滚到保存点，撤销保存点之后的写入，保存点本身仍然保留
+    pub fn rollback_to(&self, name: &str) -> Result<()> {
+        let mut savepoints = self.savepoints.lock().unwrap();
+        let Some(pos) = savepoints.iter().rposition(|(n, _)| n == name) else {
+            return Err(Error::NoSuchSavepoint(name.to_string()));
+        };
+        let writes = savepoints[pos].1;
+        savepoints.truncate(pos + 1);
+
+        let mut txn_state = self.state.lock().unwrap();
+        let mut kv_engine = self.kv.lock().unwrap();
+        let mut undo = self.undo.lock().unwrap();
+        for Undo { key, previous } in undo.drain(writes..).rev() {
+            let enc_key = Key {
+                raw_key: key.clone(),
+                version: self.version,
+            }
+            .encode();
+            match previous {
+                // 恢复成之前写入的数据
+                Some(value) => {
+                    kv_engine.insert(enc_key, value);
+                }
+                // 保存点之前没有写入过这个 key，从 TxnWrite 中移除
+                None => {
+                    kv_engine.remove(&enc_key);
+                    if let Some(txn) = txn_state.active.get_mut(&self.version) {
+                        txn.keys.retain(|k| *k != key);
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 
-    // 读取数据，从最后一条数据进行遍历，找到第一条可见的数据
+    // 读取数据，找到提交顺序最新的一条可见数据
     pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads.lock().unwrap().keys.insert(key.to_vec());
+        }
+
         let kv_engine = self.kv.lock().unwrap();
-        for (k, v) in kv_engine.iter().rev() {
-            let key_version = decode_key(k);
-            if key_version.raw_key.eq(key) && self.is_visible(key_version.version) {
-                return v.clone();
+        let mut latest = None;
+        for (k, v) in kv_engine.iter() {
+            let Some(key_version) = decode_key(k) else {
+                continue;
+            };
+            if !key_version.raw_key.eq(key) {
+                continue;
+            }
+            if let Some(order) = self.visible_order(&kv_engine, key_version.version) {
+                if latest.as_ref().is_none_or(|(o, _)| order > *o) {
+                    latest = Some((order, v));
+                }
             }
         }
-        None
+        latest.and_then(|(_, v)| v.clone())
     }
 
 
-    // 打印出所有可见的数据
-    pub fn print_all(&self) {
-        let mut records = BTreeMap::new();
+    // 锁住 key 并读取最新提交的数据，其他事务已经锁住时等待
+    // 和 get 不同，读到的不是快照中的数据，锁住之后其他事务不能再修改它，直到当前事务结束
+    // 其他活跃事务已经写入但还没有提交时，读到的数据可能被覆盖，返回冲突错误
+    pub fn select_for_update(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.reader.is_some() {
+            return Err(Error::ReadOnly);
+        }
+        let owned = self.locks.owner(key) == Some(self.version);
+        self.locks.acquire(self.version, key)?;
+
+        // 自己的写入最新，否则找提交顺序最新的版本
+        let txn_state = self.state.lock().unwrap();
         let kv_engine = self.kv.lock().unwrap();
+        let mut latest = None;
         for (k, v) in kv_engine.iter() {
-            let key_version = decode_key(k);
-            if self.is_visible(key_version.version) {
-                records.insert(key_version.raw_key.to_vec(), v.clone());
+            let Some(key_version) = decode_key(k) else {
+                continue;
+            };
+            if !key_version.raw_key.eq(key) {
+                continue;
+            }
+            let order = match commit_log::get_status(&kv_engine, key_version.version) {
+                _ if key_version.version == self.version => u64::MAX,
+                Some(TxnStatus::Committed(seq)) => seq,
+                None if txn_state.active.contains_key(&key_version.version) => {
+                    // 这次才锁住的 key 释放掉，不阻塞写入它的事务
+                    if !owned {
+                        self.locks.release(self.version, key);
+                    }
+                    return Err(Error::Serialization);
+                }
+                _ => continue,
+            };
+            if latest.as_ref().is_none_or(|(o, _)| order > *o) {
+                latest = Some((order, v));
             }
         }
+        Ok(latest.and_then(|(_, v)| v.clone()))
+    }
+
+    // 扫描范围内所有可见的数据
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let range: KeyRange = (range.start_bound().cloned(), range.end_bound().cloned());
 
-        for (k, v) in records.iter() {
-            if let Some(v) = v {
-                print!(
-                    "{}={} ",
-                    String::from_utf8_lossy(k),
-                    String::from_utf8_lossy(v)
-                );
+        // 每个 key 提交顺序最新的可见数据
+        let mut records: BTreeMap<Vec<u8>, (u64, Option<Vec<u8>>)> = BTreeMap::new();
+        let kv_engine = self.kv.lock().unwrap();
+        for (k, v) in kv_engine.iter() {
+            let Some(key_version) = decode_key(k) else {
+                continue;
+            };
+            if !range.contains(&key_version.raw_key) {
+                continue;
             }
+            if let Some(order) = self.visible_order(&kv_engine, key_version.version) {
+                match records.get(&key_version.raw_key) {
+                    Some((o, _)) if *o > order => {}
+                    _ => {
+                        records.insert(key_version.raw_key, (order, v.clone()));
+                    }
+                }
+            }
+        }
+        drop(kv_engine);
+
+        // 可串行化模式下记录扫描过的范围，这样范围内新插入的数据也能被检测到
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads.lock().unwrap().ranges.push(range);
+        }
+
+        records
+            .into_iter()
+            .filter_map(|(k, (_, v))| v.map(|v| (k, v)))
+            .collect()
+    }
+
+    // 打印出所有可见的数据
+    pub fn print_all(&self) {
+        for (k, v) in self.scan(..) {
+            print!(
+                "{}={} ",
+                String::from_utf8_lossy(&k),
+                String::from_utf8_lossy(&v)
+            );
         }
         println!();
     }
 
     // 提交事务
-    pub fn commit(&self) {
-        let mut active_txn = ACTIVE_TXN.lock().unwrap();
-        active_txn.remove(&self.version);
+    pub fn commit(&self) -> Result<()> {
+        // 只读事务没有需要提交的数据
+        if self.reader.is_some() {
+            return Ok(());
+        }
+
+        let mut txn_state = self.state.lock().unwrap();
+
+        // 可串行化模式下，如果和当前事务并发提交的事务修改了当前事务读取过的数据，
+        // 说明存在读写冲突，当前事务需要中止
+        if self.isolation == IsolationLevel::Serializable {
+            let reads = self.reads.lock().unwrap();
+            let conflict = txn_state
+                .committed
+                .iter()
+                .filter(|(version, _)| !self.in_snapshot(**version))
+                .any(|(_, keys)| keys.iter().any(|k| reads.contains(k)));
+            drop(reads);
+
+            if conflict {
+                self.abort(&mut txn_state);
+                return Err(Error::Serialization);
+            }
+        }
+
+        // 写入提交记录，之后写入的数据才对其他事务可见
+        let seq = txn_state.next_commit;
+        txn_state.next_commit += 1;
+        let mut kv_engine = self.kv.lock().unwrap();
+        commit_log::set_status(&mut kv_engine, self.version, TxnStatus::Committed(seq));
+
+        // 记录写入的 key，供之后提交的可串行化事务做冲突检查
+        if let Some(txn) = txn_state.active.remove(&self.version) {
+            cdc::publish(&mut txn_state, &kv_engine, self.version, seq, &txn.keys);
+            if !txn.keys.is_empty() {
+                txn_state.committed.insert(self.version, txn.keys);
+            }
+        }
+        drop(kv_engine);
+        txn_state.prune_committed();
+        self.locks.release_all(self.version);
+        Ok(())
     }
 
     // 回滚事务
     pub fn rollback(&self) {
-        // 清除写入的数据
-        let mut active_txn = ACTIVE_TXN.lock().unwrap();
-        if let Some(keys) = active_txn.get(&self.version) {
+        if self.reader.is_some() {
+            return;
+        }
+
+        let mut txn_state = self.state.lock().unwrap();
+        self.abort(&mut txn_state);
+    }
+
+    // 写入回滚记录，清除写入的数据，并从活跃事务列表中移除
+    fn abort(&self, txn_state: &mut TxnState) {
+        if let Some(txn) = txn_state.active.remove(&self.version) {
             let mut kv_engine = self.kv.lock().unwrap();
-            for k in keys {
+            commit_log::set_status(&mut kv_engine, self.version, TxnStatus::Aborted);
+            for k in txn.keys {
                 let enc_key = Key {
                     raw_key: k.to_vec(),
                     version: self.version,
@@ -171,15 +617,39 @@ impl Transaction {
                 assert!(res.is_some());
             }
         }
+        txn_state.prune_committed();
+        self.locks.release_all(self.version);
+    }
 
-        // 清除活跃事务列表中的数据
-        active_txn.remove(&self.version);
+    // 判断一个版本的数据对当前事务是否可见，可见时返回它的提交顺序
+    // 1. 只读事务只能看到提交序号小于上界的修改
+    // 2. 如果不在当前事务的快照中，则不可见
+    // 3. 自己的修改可见，并且比其他版本都新
+    // 4. 其他事务的修改，只有提交记录中已经提交才可见
+    // 版本号是事务开启的顺序，等待行锁的事务可能比后开启的事务更晚提交，所以同一个 key 的版本按照提交顺序比较新旧
+    fn visible_order(&self, kv_engine: &KVEngine, version: u64) -> Option<u64> {
+        if let Some((_, next_commit)) = self.reader {
+            return match commit_log::get_status(kv_engine, version) {
+                Some(TxnStatus::Committed(seq)) if seq < next_commit => Some(seq),
+                _ => None,
+            };
+        }
+        if !self.in_snapshot(version) {
+            return None;
+        }
+        if version == self.version {
+            return Some(u64::MAX);
+        }
+        match commit_log::get_status(kv_engine, version) {
+            Some(TxnStatus::Committed(seq)) => Some(seq),
+            _ => None,
+        }
     }
 
-    // 判断一个版本的数据对当前事务是否可见
-    // 1. 如果是另一个活跃事务的修改，则不可见
-    // 2. 如果版本号比当前大，则不可见
-    fn is_visible(&self, version: u64) -> bool {
+    // 判断一个版本是否在当前事务开启时的快照中
+    // 1. 如果是另一个活跃事务的修改，则不在快照中
+    // 2. 如果版本号比当前大，则不在快照中
+    fn in_snapshot(&self, version: u64) -> bool {
         if self.active_xid.contains(&version) {
             return false;
         }
@@ -187,6 +657,15 @@ impl Transaction {
     }
 }
 
+impl Drop for Transaction {
+    // 只读事务结束后从读者列表中移除，不再阻止旧版本被清理
+    fn drop(&mut self) {
+        if let Some((reader, _)) = self.reader {
+            self.state.lock().unwrap().readers.remove(&reader);
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -197,19 +676,19 @@ mod tests {
         let eng = KVEngine::new();
         let mvcc = MVCC::new(eng);
         // 先新增几条数据
-        let tx0 = mvcc.begin_transaction();
-        tx0.set(b"a", b"a1".to_vec());
-        tx0.set(b"b", b"b1".to_vec());
-        tx0.set(b"c", b"c1".to_vec());
-        tx0.set(b"d", b"d1".to_vec());
-        tx0.set(b"e", b"e1".to_vec());
-        tx0.commit();
+        let tx0 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx0.set(b"a", b"a1".to_vec()).unwrap();
+        tx0.set(b"b", b"b1".to_vec()).unwrap();
+        tx0.set(b"c", b"c1".to_vec()).unwrap();
+        tx0.set(b"d", b"d1".to_vec()).unwrap();
+        tx0.set(b"e", b"e1".to_vec()).unwrap();
+        tx0.commit().unwrap();
 
         // 开启一个事务
-        let tx1 = mvcc.begin_transaction();
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
         // 将 a 改为 a2，e 改为 e2
-        tx1.set(b"a", b"a2".to_vec());
-        tx1.set(b"e", b"e2".to_vec());
+        tx1.set(b"a", b"a2".to_vec()).unwrap();
+        tx1.set(b"e", b"e2".to_vec()).unwrap();
         // Time
         //  1  a2              e2
         //  0  a1  b1  c1  d1  e1
@@ -219,9 +698,9 @@ mod tests {
         tx1.print_all(); // a=a2 b=b1 c=c1 d=d1 e=e2
 
         // 开启一个新的事务
-        let tx2 = mvcc.begin_transaction();
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
         // 删除 b
-        tx2.delete(b"b");
+        tx2.delete(b"b").unwrap();
         // Time
         //  2      X
         //  1  a2              e2
@@ -231,12 +710,12 @@ mod tests {
         // 此时 T1 没提交，所以 T2 看到的是
         tx2.print_all(); // a=a1 c=c1 d=d1 e=e1
         // 提交 T1
-        tx1.commit();
+        tx1.commit().unwrap();
         // 此时 T2 仍然看不到 T1 的提交，因为 T2 开启的时候，T2 还没有提交（可重复读）
         tx2.print_all(); // a=a1 c=c1 d=d1 e=e1
 
         // 再开启一个新的事务
-        let tx3 = mvcc.begin_transaction();
+        let tx3 = mvcc.begin_transaction(IsolationLevel::Snapshot);
         // Time
         //  3
         //  2      X               uncommitted
@@ -247,8 +726,238 @@ mod tests {
         tx3.print_all(); // a=a2 b=b1 c=c1 d=d1 e=e2
 
         // T3 写新的数据
-        tx3.set(b"f", b"f1".to_vec());
+        tx3.set(b"f", b"f1".to_vec()).unwrap();
         // T2 写同样的数据，会冲突
-        // tx2.set(b"f", b"f1".to_vec());
+        assert_eq!(tx2.set(b"f", b"f1".to_vec()), Err(Error::Serialization));
+    }
+
+    // 两个医生值班，事务都先检查至少还有一个人在值班，再让自己下班
+    fn on_call(mvcc: &MVCC, isolation: IsolationLevel) -> (Result<()>, Result<()>) {
+        let tx0 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx0.set(b"alice", b"on".to_vec()).unwrap();
+        tx0.set(b"bob", b"on".to_vec()).unwrap();
+        tx0.commit().unwrap();
+
+        let on_duty = |txn: &Transaction| {
+            [&b"alice"[..], &b"bob"[..]]
+                .iter()
+                .filter(|k| txn.get(k) == Some(b"on".to_vec()))
+                .count()
+        };
+
+        let tx1 = mvcc.begin_transaction(isolation);
+        let tx2 = mvcc.begin_transaction(isolation);
+        assert_eq!(on_duty(&tx1), 2);
+        assert_eq!(on_duty(&tx2), 2);
+        tx1.set(b"alice", b"off".to_vec()).unwrap();
+        tx2.set(b"bob", b"off".to_vec()).unwrap();
+        (tx1.commit(), tx2.commit())
+    }
+
+    #[test]
+    fn test_write_skew_snapshot() {
+        let mvcc = MVCC::new(KVEngine::new());
+        // 快照隔离下两个事务都能提交，最后没有人值班
+        assert_eq!(on_call(&mvcc, IsolationLevel::Snapshot), (Ok(()), Ok(())));
+
+        let tx = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        assert_eq!(tx.get(b"alice"), Some(b"off".to_vec()));
+        assert_eq!(tx.get(b"bob"), Some(b"off".to_vec()));
+    }
+
+    #[test]
+    fn test_write_skew_serializable() {
+        let mvcc = MVCC::new(KVEngine::new());
+        // 可串行化模式下，后提交的事务读到的数据已经被修改，需要中止
+        assert_eq!(
+            on_call(&mvcc, IsolationLevel::Serializable),
+            (Ok(()), Err(Error::Serialization))
+        );
+
+        // 中止事务的写入被清除
+        let tx = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        assert_eq!(tx.get(b"alice"), Some(b"off".to_vec()));
+        assert_eq!(tx.get(b"bob"), Some(b"on".to_vec()));
+    }
+
+    #[test]
+    fn test_serializable_scan() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx0.set(b"task1", b"todo".to_vec()).unwrap();
+        tx0.commit().unwrap();
+
+        // 两个事务都统计 task 的数量，再插入一条新的 task
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Serializable);
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Serializable);
+        let range = b"task".to_vec()..b"tasl".to_vec();
+        assert_eq!(tx1.scan(range.clone()).len(), 1);
+        assert_eq!(tx2.scan(range.clone()).len(), 1);
+        tx1.set(b"task2", b"todo".to_vec()).unwrap();
+        tx2.set(b"task3", b"todo".to_vec()).unwrap();
+
+        // T1 插入的数据落在 T2 扫描过的范围内
+        assert_eq!(tx1.commit(), Ok(()));
+        assert_eq!(tx2.commit(), Err(Error::Serialization));
+
+        // 范围外的写入不会产生冲突
+        let tx3 = mvcc.begin_transaction(IsolationLevel::Serializable);
+        let tx4 = mvcc.begin_transaction(IsolationLevel::Serializable);
+        assert_eq!(tx3.scan(range.clone()).len(), 2);
+        tx4.set(b"other", b"x".to_vec()).unwrap();
+        assert_eq!(tx4.commit(), Ok(()));
+        assert_eq!(tx3.commit(), Ok(()));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx0.set(b"a", b"a1".to_vec()).unwrap();
+        tx0.commit().unwrap();
+
+        // 未提交的修改对只读事务不可见
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.set(b"b", b"b1".to_vec()).unwrap();
+        let ro = mvcc.begin_read_only();
+        assert_eq!(ro.scan(..), vec![(b"a".to_vec(), b"a1".to_vec())]);
+
+        // 只读事务不在活跃事务列表中
+        assert_eq!(mvcc.state.lock().unwrap().active.len(), 1);
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Serializable);
+        assert_eq!(tx2.active_xid, HashSet::from([tx1.version]));
+
+        // 只读事务不能写入
+        assert_eq!(ro.set(b"c", b"c1".to_vec()), Err(Error::ReadOnly));
+        assert_eq!(ro.delete(b"a"), Err(Error::ReadOnly));
+
+        // 之后提交的修改也不可见，可重复读
+        tx1.commit().unwrap();
+        assert_eq!(ro.get(b"b"), None);
+        assert_eq!(ro.commit(), Ok(()));
+
+        // 只读事务不影响其他事务的提交
+        assert_eq!(tx2.get(b"a"), Some(b"a1".to_vec()));
+        tx2.set(b"a", b"a2".to_vec()).unwrap();
+        assert_eq!(tx2.commit(), Ok(()));
+    }
+
+    #[test]
+    fn test_as_of() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.set(b"a", b"a1".to_vec()).unwrap();
+        tx1.commit().unwrap();
+
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx2.set(b"a", b"a2".to_vec()).unwrap();
+        tx2.set(b"b", b"b2".to_vec()).unwrap();
+        tx2.commit().unwrap();
+
+        let tx3 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx3.delete(b"a").unwrap();
+        tx3.commit().unwrap();
+
+        let tx4 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx4.set(b"c", b"c4".to_vec()).unwrap();
+
+        let history = |version| {
+            let txn = mvcc.begin_as_of(version).unwrap();
+            txn.scan(..)
+        };
+        assert_eq!(history(1), vec![(b"a".to_vec(), b"a1".to_vec())]);
+        assert_eq!(
+            history(2),
+            vec![(b"a".to_vec(), b"a2".to_vec()), (b"b".to_vec(), b"b2".to_vec())]
+        );
+        assert_eq!(history(3), vec![(b"b".to_vec(), b"b2".to_vec())]);
+        // 未提交的事务仍然不可见
+        assert_eq!(history(4), vec![(b"b".to_vec(), b"b2".to_vec())]);
+
+        assert_eq!(mvcc.begin_as_of(5).err(), Some(Error::InvalidVersion(5)));
+        let txn = mvcc.begin_as_of(2).unwrap();
+        assert_eq!(txn.set(b"a", b"x".to_vec()), Err(Error::ReadOnly));
+    }
+
+    #[test]
+    fn test_as_of_repeatable() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.set(b"a", b"a1".to_vec()).unwrap();
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx2.set(b"b", b"b2".to_vec()).unwrap();
+        tx2.commit().unwrap();
+        let tx3 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+
+        let history = |version| mvcc.begin_as_of(version).unwrap().scan(..);
+        assert_eq!(history(1), vec![]);
+        assert_eq!(history(2), vec![(b"b".to_vec(), b"b2".to_vec())]);
+
+        // 更早开启的 T1 在版本 3 开启之后才提交，对版本 2 的历史仍然不可见
+        tx1.commit().unwrap();
+        assert_eq!(history(1), vec![]);
+        assert_eq!(history(2), vec![(b"b".to_vec(), b"b2".to_vec())]);
+        assert_eq!(
+            history(3),
+            vec![(b"a".to_vec(), b"a1".to_vec()), (b"b".to_vec(), b"b2".to_vec())]
+        );
+
+        // 清理之后仍然能读到需要的版本
+        let ro = mvcc.begin_as_of(2).unwrap();
+        tx3.commit().unwrap();
+        mvcc.vacuum();
+        assert_eq!(ro.scan(..), vec![(b"b".to_vec(), b"b2".to_vec())]);
+    }
+
+    #[test]
+    fn test_savepoint() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx0.set(b"a", b"a0".to_vec()).unwrap();
+        tx0.set(b"b", b"b0".to_vec()).unwrap();
+        tx0.commit().unwrap();
+
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.set(b"a", b"a1".to_vec()).unwrap();
+        tx1.savepoint("sp1");
+        tx1.set(b"a", b"a2".to_vec()).unwrap();
+        tx1.delete(b"b").unwrap();
+        tx1.set(b"c", b"c1".to_vec()).unwrap();
+        tx1.savepoint("sp2");
+        tx1.set(b"d", b"d1".to_vec()).unwrap();
+
+        // 只撤销 sp2 之后的写入
+        tx1.rollback_to("sp2").unwrap();
+        assert_eq!(tx1.get(b"d"), None);
+        assert_eq!(tx1.get(b"c"), Some(b"c1".to_vec()));
+
+        // 撤销 sp1 之后的写入，同时 sp2 也失效了
+        tx1.rollback_to("sp1").unwrap();
+        assert_eq!(
+            tx1.scan(..),
+            vec![(b"a".to_vec(), b"a1".to_vec()), (b"b".to_vec(), b"b0".to_vec())]
+        );
+        assert_eq!(tx1.rollback_to("sp2"), Err(Error::NoSuchSavepoint("sp2".into())));
+
+        // 保存点可以重复回滚
+        tx1.set(b"e", b"e1".to_vec()).unwrap();
+        tx1.rollback_to("sp1").unwrap();
+        assert_eq!(tx1.get(b"e"), None);
+
+        // 回滚掉的 key 不再属于当前事务，其他事务可以写入
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx2.set(b"c", b"c2".to_vec()).unwrap();
+        tx2.commit().unwrap();
+        tx1.commit().unwrap();
+
+        let txn = mvcc.begin_read_only();
+        assert_eq!(
+            txn.scan(..),
+            vec![
+                (b"a".to_vec(), b"a1".to_vec()),
+                (b"b".to_vec(), b"b0".to_vec()),
+                (b"c".to_vec(), b"c2".to_vec())
+            ]
+        );
     }
 }
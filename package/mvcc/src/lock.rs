@@ -0,0 +1,274 @@
+use crate::{Error, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+// 默认的锁等待超时时间
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// 行锁表，悲观模式下写入前先锁住 key，冲突的事务排队等待而不是直接失败
+pub(crate) struct LockTable {
+    locks: Mutex<Locks>,
+    // 有锁被释放，或者有事务退出等待时通知等待的事务
+    released: Condvar,
+}
+
+struct Locks {
+    // key 被哪个事务锁住
+    owners: HashMap<Vec<u8>, u64>,
+    // 每个 key 的等待队列，先到先得
+    waiters: HashMap<Vec<u8>, VecDeque<u64>>,
+    // 等待图：事务正在等待哪个事务释放锁
+    waits_for: HashMap<u64, u64>,
+    // 锁等待超时时间
+    timeout: Duration,
+}
+
+impl Locks {
+    // 从等待队列和等待图中移除
+    fn stop_waiting(&mut self, txn: u64, key: &[u8]) {
+        if let Some(queue) = self.waiters.get_mut(key) {
+            queue.retain(|t| *t != txn);
+            if queue.is_empty() {
+                self.waiters.remove(key);
+            }
+        }
+        self.waits_for.remove(&txn);
+    }
+
+    // 沿着等待图查找，如果又回到了自己，说明出现了死锁
+    fn has_cycle(&self, txn: u64) -> bool {
+        let mut current = txn;
+        for _ in 0..self.waits_for.len() {
+            match self.waits_for.get(&current) {
+                Some(&next) if next == txn => return true,
+                Some(&next) => current = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl LockTable {
+    pub(crate) fn new() -> Self {
+        Self {
+            locks: Mutex::new(Locks {
+                owners: HashMap::new(),
+                waiters: HashMap::new(),
+                waits_for: HashMap::new(),
+                timeout: DEFAULT_LOCK_TIMEOUT,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn set_timeout(&self, timeout: Duration) {
+        self.locks.lock().unwrap().timeout = timeout;
+    }
+
+    // 锁住 key，已经被其他事务锁住时排队等待
+    // 等待会形成环时返回死锁错误，等待超时返回超时错误，调用方需要回滚事务
+    pub(crate) fn acquire(&self, txn: u64, key: &[u8]) -> Result<()> {
+        let mut locks = self.locks.lock().unwrap();
+        let deadline = Instant::now() + locks.timeout;
+
+        loop {
+            // 锁是空闲的，并且前面没有其他事务在排队
+            let owner = locks.owners.get(key).cloned();
+            let first = locks.waiters.get(key).and_then(|q| q.front().cloned());
+            match (owner, first) {
+                (Some(owner), _) if owner == txn => return Ok(()),
+                (None, None) => {
+                    locks.owners.insert(key.to_vec(), txn);
+                    return Ok(());
+                }
+                (None, Some(first)) if first == txn => {
+                    locks.stop_waiting(txn, key);
+                    locks.owners.insert(key.to_vec(), txn);
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            // 加入等待队列，等待持有锁的事务，或者排在前面的事务
+            let queue = locks.waiters.entry(key.to_vec()).or_default();
+            if !queue.contains(&txn) {
+                queue.push_back(txn);
+            }
+            let blocker = owner.or(first).unwrap();
+            locks.waits_for.insert(txn, blocker);
+
+            if locks.has_cycle(txn) {
+                locks.stop_waiting(txn, key);
+                self.released.notify_all();
+                return Err(Error::Deadlock);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                locks.stop_waiting(txn, key);
+                self.released.notify_all();
+                return Err(Error::LockTimeout);
+            }
+            locks = self.released.wait_timeout(locks, deadline - now).unwrap().0;
+        }
+    }
+
+    // key 被哪个事务锁住
+    pub(crate) fn owner(&self, key: &[u8]) -> Option<u64> {
+        self.locks.lock().unwrap().owners.get(key).cloned()
+    }
+
+    // 释放一个 key 上的锁，并唤醒等待的事务
+    // 释放锁的事务还会继续执行，等待它的边需要立即移除，否则它再等待其他事务时会误判为死锁
+    // 被唤醒的事务重新检查时会加入新的等待边
+    pub(crate) fn release(&self, txn: u64, key: &[u8]) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.owners.get(key) == Some(&txn) {
+            locks.owners.remove(key);
+            locks.waits_for.retain(|_, blocker| *blocker != txn);
+            self.released.notify_all();
+        }
+    }
+
+    // 事务结束时释放所有的锁，并唤醒等待的事务
+    pub(crate) fn release_all(&self, txn: u64) {
+        let mut locks = self.locks.lock().unwrap();
+        locks.owners.retain(|_, owner| *owner != txn);
+        self.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LockTable;
+    use crate::*;
+    use std::{thread, time::Duration};
+
+    fn counter(txn: &Transaction) -> u64 {
+        match txn.get(b"counter") {
+            Some(v) => String::from_utf8(v).unwrap().parse().unwrap(),
+            None => 0,
+        }
+    }
+
+    #[test]
+    fn test_hot_counter() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let mvcc = mvcc.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+                        // 锁住之后读到的是最新提交的数据
+                        let value = txn.select_for_update(b"counter").unwrap();
+                        let n: u64 = value.map_or(0, |v| String::from_utf8(v).unwrap().parse().unwrap());
+                        txn.set(b"counter", (n + 1).to_string().into_bytes()).unwrap();
+                        txn.commit().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(counter(&mvcc.begin_read_only()), 100);
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.select_for_update(b"a").unwrap();
+        tx2.select_for_update(b"b").unwrap();
+
+        // T1 等待 T2 释放 b
+        let handle = thread::spawn(move || {
+            let res = tx1.select_for_update(b"b");
+            tx1.set(b"b", b"b1".to_vec()).unwrap();
+            tx1.commit().unwrap();
+            res
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // T2 再等待 T1 释放 a，形成死锁
+        assert_eq!(tx2.select_for_update(b"a"), Err(Error::Deadlock));
+        tx2.rollback();
+
+        // T2 回滚后，T1 拿到了锁
+        assert_eq!(handle.join().unwrap(), Ok(None));
+        assert_eq!(mvcc.begin_read_only().get(b"b"), Some(b"b1".to_vec()));
+    }
+
+    #[test]
+    fn test_pending_write() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx0.set(b"a", b"a0".to_vec()).unwrap();
+        tx0.commit().unwrap();
+
+        // T2 乐观写入 a 还没有提交，T1 锁住 a 读到的旧值会被 T2 覆盖
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx2.set(b"a", b"a2".to_vec()).unwrap();
+        assert_eq!(tx1.select_for_update(b"a"), Err(Error::Serialization));
+
+        // 冲突时不持有锁，T2 可以继续写入并提交
+        tx2.set(b"a", b"a3".to_vec()).unwrap();
+        tx2.commit().unwrap();
+
+        // T2 提交之后 T1 读到的是 T2 写入的数据，不会丢失更新
+        assert_eq!(tx1.select_for_update(b"a"), Ok(Some(b"a3".to_vec())));
+        tx1.set(b"a", b"a1".to_vec()).unwrap();
+        tx1.commit().unwrap();
+        assert_eq!(mvcc.begin_read_only().get(b"a"), Some(b"a1".to_vec()));
+
+        // 已经回滚的写入不会冲突
+        let tx3 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        let tx4 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx4.set(b"a", b"a4".to_vec()).unwrap();
+        tx4.rollback();
+        assert_eq!(tx3.select_for_update(b"a"), Ok(Some(b"a1".to_vec())));
+    }
+
+    #[test]
+    fn test_release_waits_for() {
+        let table = LockTable::new();
+        table.set_timeout(Duration::from_millis(20));
+        table.acquire(1, b"a").unwrap();
+        table.acquire(2, b"b").unwrap();
+
+        // T2 在等待 T1 释放 a，T1 释放之后 T2 还没有被唤醒
+        table.locks.lock().unwrap().waits_for.insert(2, 1);
+        table.release(1, b"a");
+
+        // T1 再等待 T2 持有的 b，没有形成环，只会等待超时
+        assert_eq!(table.acquire(1, b"b"), Err(Error::LockTimeout));
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let mvcc = MVCC::new(KVEngine::new());
+        mvcc.set_lock_timeout(Duration::from_millis(20));
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx1.select_for_update(b"a").unwrap();
+        assert_eq!(tx2.select_for_update(b"a"), Err(Error::LockTimeout));
+
+        // 乐观写入遇到被锁住的 key，直接失败
+        assert_eq!(tx2.set(b"a", b"a2".to_vec()), Err(Error::Serialization));
+
+        tx1.set(b"a", b"a1".to_vec()).unwrap();
+        tx1.commit().unwrap();
+        assert_eq!(tx2.select_for_update(b"a"), Ok(Some(b"a1".to_vec())));
+        tx2.set(b"a", b"a2".to_vec()).unwrap();
+        tx2.commit().unwrap();
+        assert_eq!(mvcc.begin_read_only().get(b"a"), Some(b"a2".to_vec()));
+    }
+}
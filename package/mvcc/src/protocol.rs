@@ -0,0 +1,89 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    error::Error,
+    io::{BufRead, Write},
+};
+
+pub type KvError = Box<dyn Error + Send + Sync + 'static>;
+pub type KvResult<T> = std::result::Result<T, KvError>;
+
+// 开启事务的方式
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+pub enum TxnMode {
+    Snapshot,
+    Serializable,
+    ReadOnly,
+    AsOf(u64),
+}
+
+// 客户端发送的请求，每行一个 JSON，key 和 value 都是字符串
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub enum Request {
+    Begin(TxnMode),
+    Get { key: String },
+    Set { key: String, value: String },
+    Delete { key: String },
+    // 扫描 [start, end) 范围内的数据，不指定时不限制
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+    },
+    Commit,
+    Rollback,
+}
+
+// 服务端的响应，每个请求对应一个响应
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub enum Response {
+    Ok,
+    Value(Option<String>),
+    Pairs(Vec<(String, String)>),
+    Error(String),
+}
+
+// 发送一行 JSON
+pub fn send_as_json<W, P>(outbound: &mut W, packet: &P) -> KvResult<()>
+where
+    W: Write,
+    P: Serialize,
+{
+    let mut json = serde_json::to_string(&packet)?;
+    json.push('\n');
+    outbound.write_all(json.as_bytes())?;
+    outbound.flush()?;
+    Ok(())
+}
+
+// 读取一行 JSON，连接关闭时返回 None
+pub fn receive_as_json<R, P>(inbound: &mut R) -> KvResult<Option<P>>
+where
+    R: BufRead,
+    P: DeserializeOwned,
+{
+    let mut line = String::new();
+    if inbound.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_json() {
+        let request = Request::Set {
+            key: "a".to_string(),
+            value: "a1".to_string(),
+        };
+
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(json, r#"{"Set":{"key":"a","value":"a1"}}"#);
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+        assert_eq!(
+            serde_json::to_string(&Request::Begin(TxnMode::AsOf(3))).unwrap(),
+            r#"{"Begin":{"AsOf":3}}"#
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use crate::protocol::{self, KvResult, Request, Response, TxnMode};
+use crate::{IsolationLevel, Transaction, MVCC};
+use std::{
+    io::{BufRead, BufReader},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+// 接受连接，每个连接在单独的线程中处理
+// 事务的读写会等待锁，所以这里使用线程而不是异步任务
+pub fn serve(listener: TcpListener, mvcc: MVCC) -> KvResult<()> {
+    for socket in listener.incoming() {
+        let socket = socket?;
+        let mvcc = mvcc.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(socket, mvcc) {
+                eprintln!("Error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// 每个连接持有自己的会话事务，连接断开或者出错时回滚未提交的事务
+// 无法解析的请求返回错误响应，连接和事务仍然保留
+fn handle_connection(socket: TcpStream, mvcc: MVCC) -> KvResult<()> {
+    socket.set_nodelay(true)?;
+    let mut outbound = socket.try_clone()?;
+    let mut inbound = BufReader::new(socket);
+    let mut session = Session { mvcc, txn: None };
+
+    let mut line = String::new();
+    while inbound.read_line(&mut line)? > 0 {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => session.execute(request).unwrap_or_else(Response::Error),
+            Err(e) => Response::Error(format!("invalid request: {}", e)),
+        };
+        protocol::send_as_json(&mut outbound, &response)?;
+        line.clear();
+    }
+    Ok(())
+}
+
+struct Session {
+    mvcc: MVCC,
+    txn: Option<Transaction>,
+}
+
+impl Drop for Session {
+    // 不管连接以什么方式结束，都回滚未提交的事务，释放持有的锁
+    fn drop(&mut self) {
+        if let Some(txn) = self.txn.take() {
+            txn.rollback();
+        }
+    }
+}
+
+impl Session {
+    fn execute(&mut self, request: Request) -> Result<Response, String> {
+        if let Request::Begin(mode) = request {
+            if self.txn.is_some() {
+                return Err("transaction already in progress".into());
+            }
+            let txn = match mode {
+                TxnMode::Snapshot => self.mvcc.begin_transaction(IsolationLevel::Snapshot),
+                TxnMode::Serializable => self.mvcc.begin_transaction(IsolationLevel::Serializable),
+                TxnMode::ReadOnly => self.mvcc.begin_read_only(),
+                TxnMode::AsOf(version) => self.mvcc.begin_as_of(version).map_err(|e| e.to_string())?,
+            };
+            self.txn = Some(txn);
+            return Ok(Response::Ok);
+        }
+
+        let Some(txn) = self.txn.as_ref() else {
+            return Err("no transaction in progress".into());
+        };
+        let response = match request {
+            Request::Get { key } => {
+                let value = txn.get(key.as_bytes());
+                Response::Value(value.map(|v| String::from_utf8_lossy(&v).into_owned()))
+            }
+            Request::Set { key, value } => {
+                txn.set(key.as_bytes(), value.into_bytes()).map_err(|e| e.to_string())?;
+                Response::Ok
+            }
+            Request::Delete { key } => {
+                txn.delete(key.as_bytes()).map_err(|e| e.to_string())?;
+                Response::Ok
+            }
+            Request::Scan { start, end } => {
+                let start = start.map(String::into_bytes);
+                let end = end.map(String::into_bytes);
+                let pairs = match (start, end) {
+                    (Some(start), Some(end)) => txn.scan(start..end),
+                    (Some(start), None) => txn.scan(start..),
+                    (None, Some(end)) => txn.scan(..end),
+                    (None, None) => txn.scan(..),
+                };
+                let pairs = pairs
+                    .into_iter()
+                    .map(|(k, v)| {
+                        let k = String::from_utf8_lossy(&k).into_owned();
+                        let v = String::from_utf8_lossy(&v).into_owned();
+                        (k, v)
+                    })
+                    .collect();
+                Response::Pairs(pairs)
+            }
+            Request::Commit => {
+                // 提交失败时事务已经中止，会话同样结束
+                let txn = self.txn.take().unwrap();
+                txn.commit().map_err(|e| e.to_string())?;
+                Response::Ok
+            }
+            Request::Rollback => {
+                self.txn.take().unwrap().rollback();
+                Response::Ok
+            }
+            Request::Begin(_) => unreachable!(),
+        };
+        Ok(response)
+    }
+}
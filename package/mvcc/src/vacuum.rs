@@ -0,0 +1,304 @@
+use crate::{commit_log, decode_key, TxnStatus, MVCC};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+impl MVCC {
+    // 清理不再需要的旧版本数据，返回删除的数据条数
+    // 1. 低水位之下的版本都已经结束，已提交的版本对所有事务可见，已回滚的版本可以直接删除
+    // 2. 每个 key 只需要保留低水位之下最新的一个版本，更早的版本不会再被读到
+    // 3. 如果保留的版本是删除标记，并且 key 没有低水位之上的版本，说明所有事务都看不到这个 key 了，删除标记也可以清理
+    // 4. 数据全部删除之后，低水位之下的事务的状态记录也可以清理
+    pub fn vacuum(&self) -> usize {
+        // 清理期间不允许开启新的事务，保证低水位不会变化
+        let mut txn_state = self.state.lock().unwrap();
+        let low_water = txn_state.low_water();
+        let mut kv_engine = self.kv.lock().unwrap();
+
+        // 每个 key 在低水位之下提交顺序最新的版本
+        let mut latest: HashMap<Vec<u8>, (u64, Vec<u8>)> = HashMap::new();
+        // 在低水位之上还有版本的 key
+        let mut recent = HashSet::new();
+        let mut garbage = vec![];
+        for enc_key in kv_engine.keys() {
+            let Some(key_version) = decode_key(enc_key) else {
+                continue;
+            };
+            if key_version.version >= low_water {
+                recent.insert(key_version.raw_key);
+                continue;
+            }
+            let status = commit_log::get_status(&kv_engine, key_version.version);
+            let Some(TxnStatus::Committed(seq)) = status else {
+                garbage.push(enc_key.clone());
+                continue;
+            };
+
+            match latest.get_mut(&key_version.raw_key) {
+                Some(newest) if newest.0 < seq => {
+                    let older = std::mem::replace(newest, (seq, enc_key.clone()));
+                    garbage.push(older.1);
+                }
+                Some(_) => garbage.push(enc_key.clone()),
+                None => {
+                    latest.insert(key_version.raw_key, (seq, enc_key.clone()));
+                }
+            }
+        }
+
+        // 对所有事务都已经删除的 key
+        // 锁住 key 的事务可以覆盖更晚开启的事务提交的版本，低水位之上的版本提交顺序可能更早，
+        // 删除标记去掉之后它会重新变得可见，所以这样的 key 需要保留删除标记
+        for (raw_key, (_, enc_key)) in latest {
+            if !recent.contains(&raw_key) && kv_engine.get(&enc_key) == Some(&None) {
+                garbage.push(enc_key);
+            }
+        }
+
+        for enc_key in garbage.iter() {
+            kv_engine.remove(enc_key);
+        }
+        commit_log::prune(&mut kv_engine, low_water);
+        txn_state.vacuumed = txn_state.vacuumed.max(low_water);
+        commit_log::set_vacuumed(&mut kv_engine, txn_state.vacuumed);
+        // 比清理时的低水位更早的版本，历史数据已经不完整，不再需要它们开启时的状态
+        let vacuumed = txn_state.vacuumed;
+        txn_state.snapshots = txn_state.snapshots.split_off(&vacuumed);
+        garbage.len()
+    }
+
+    // 在后台线程中定期清理旧版本数据
+    pub fn start_vacuum(&self, interval: Duration) -> VacuumHandle {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let mvcc = self.clone();
+        let handle = thread::spawn(move || {
+            // 收到停止信号，或者通道关闭时停止
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                mvcc.vacuum();
+            }
+        });
+
+        VacuumHandle {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+// 后台清理任务，drop 时停止
+pub struct VacuumHandle {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl VacuumHandle {
+    // 停止后台清理，并等待线程退出
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    // 通知后台线程停止并等待它退出，已经停止时什么也不做
+    fn shutdown(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            // 线程已经退出时发送会失败，可以忽略
+            let _ = stop.send(());
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Drop for VacuumHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::time::{Duration, Instant};
+
+    // 用户数据的条数，不包括提交记录
+    fn versions(mvcc: &MVCC) -> usize {
+        let kv_engine = mvcc.kv.lock().unwrap();
+        kv_engine.keys().filter_map(|k| decode_key(k)).count()
+    }
+
+    // 事务状态记录的条数
+    fn statuses(mvcc: &MVCC) -> usize {
+        let kv_engine = mvcc.kv.lock().unwrap();
+        kv_engine.keys().filter(|k| k[0] == TXN_STATUS_PREFIX).count()
+    }
+
+    fn set(mvcc: &MVCC, key: &[u8], value: Option<&[u8]>) {
+        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        match value {
+            Some(value) => txn.set(key, value.to_vec()).unwrap(),
+            None => txn.delete(key).unwrap(),
+        }
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_vacuum_overwrites() {
+        let mvcc = MVCC::new(KVEngine::new());
+        for i in 0..10 {
+            set(&mvcc, b"a", Some(format!("a{}", i).as_bytes()));
+        }
+        set(&mvcc, b"b", Some(b"b1"));
+        assert_eq!(versions(&mvcc), 11);
+
+        assert_eq!(mvcc.vacuum(), 9);
+        assert_eq!(versions(&mvcc), 2);
+        let txn = mvcc.begin_read_only();
+        assert_eq!(txn.get(b"a"), Some(b"a9".to_vec()));
+        assert_eq!(txn.get(b"b"), Some(b"b1".to_vec()));
+
+        // 没有新的写入，不需要再清理
+        assert_eq!(mvcc.vacuum(), 0);
+    }
+
+    #[test]
+    fn test_vacuum_keeps_visible_versions() {
+        let mvcc = MVCC::new(KVEngine::new());
+        set(&mvcc, b"a", Some(b"a1"));
+
+        // 只读事务能看到 a1，活跃事务能看到 a2
+        let ro = mvcc.begin_read_only();
+        set(&mvcc, b"a", Some(b"a2"));
+        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        set(&mvcc, b"a", Some(b"a3"));
+        set(&mvcc, b"a", Some(b"a4"));
+
+        assert_eq!(mvcc.vacuum(), 0);
+        assert_eq!(ro.get(b"a"), Some(b"a1".to_vec()));
+
+        // 只读事务结束后，只剩下活跃事务需要的 a2
+        drop(ro);
+        assert_eq!(mvcc.vacuum(), 1);
+        assert_eq!(txn.get(b"a"), Some(b"a2".to_vec()));
+
+        // 活跃事务结束后，只保留最新版本
+        txn.commit().unwrap();
+        assert_eq!(mvcc.vacuum(), 2);
+        assert_eq!(versions(&mvcc), 1);
+    }
+
+    #[test]
+    fn test_vacuum_tombstones() {
+        let mvcc = MVCC::new(KVEngine::new());
+        set(&mvcc, b"a", Some(b"a1"));
+        set(&mvcc, b"b", Some(b"b1"));
+        set(&mvcc, b"a", None);
+
+        // 还有事务能看到删除前的数据时，删除标记需要保留
+        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        set(&mvcc, b"b", None);
+        assert_eq!(mvcc.vacuum(), 2);
+        assert_eq!(txn.get(b"a"), None);
+        assert_eq!(txn.get(b"b"), Some(b"b1".to_vec()));
+
+        txn.rollback();
+        assert_eq!(mvcc.vacuum(), 2);
+        assert_eq!(versions(&mvcc), 0);
+    }
+
+    #[test]
+    fn test_vacuum_locked_tombstone() {
+        let mvcc = MVCC::new(KVEngine::new());
+        set(&mvcc, b"a", Some(b"old"));
+        let tx1 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        let tx2 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        let tx3 = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        tx3.set(b"a", b"a3".to_vec()).unwrap();
+        tx3.commit().unwrap();
+
+        // T1 锁住 a 之后删除，覆盖了更晚开启的 T3 的提交
+        tx1.select_for_update(b"a").unwrap();
+        tx1.delete(b"a").unwrap();
+        tx1.commit().unwrap();
+        let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+        assert_eq!(txn.get(b"a"), None);
+
+        // T3 的版本在低水位之上，删除标记需要保留，否则 T3 的写入会重新可见
+        tx2.rollback();
+        mvcc.vacuum();
+        assert_eq!(txn.get(b"a"), None);
+        txn.commit().unwrap();
+        mvcc.vacuum();
+        assert_eq!(mvcc.begin_read_only().get(b"a"), None);
+        assert_eq!(versions(&mvcc), 0);
+    }
+
+    #[test]
+    fn test_vacuum_status_records() {
+        let mvcc = MVCC::new(KVEngine::new());
+        for _ in 0..100 {
+            let txn = mvcc.begin_transaction(IsolationLevel::Snapshot);
+            txn.set(b"a", b"a0".to_vec()).unwrap();
+            txn.rollback();
+        }
+        assert_eq!(statuses(&mvcc), 100);
+        assert_eq!(mvcc.vacuum(), 0);
+        assert_eq!(statuses(&mvcc), 0);
+
+        for i in 0..10 {
+            set(&mvcc, b"a", Some(format!("a{}", i).as_bytes()));
+        }
+        set(&mvcc, b"b", Some(b"b1"));
+        set(&mvcc, b"b", None);
+        assert_eq!(mvcc.vacuum(), 11);
+
+        // 只保留 a9 的提交记录，以及提交序号最大的记录
+        assert_eq!(statuses(&mvcc), 2);
+        assert_eq!(mvcc.begin_read_only().get(b"a"), Some(b"a9".to_vec()));
+
+        // 重启后提交序号继续递增，被清理过的修改不能再补发
+        let mvcc = MVCC::new(mvcc.export());
+        assert_eq!(mvcc.subscribe_from(5).err(), Some(Error::HistoryUnavailable(5)));
+        assert_eq!(mvcc.subscribe_from(11).err(), Some(Error::HistoryUnavailable(11)));
+        let changes = mvcc.subscribe_from(12).unwrap();
+        set(&mvcc, b"c", Some(b"c1"));
+        assert_eq!(changes.recv().unwrap(), (13, vec![(b"c".to_vec(), Some(b"c1".to_vec()))]));
+    }
+
+    #[test]
+    fn test_vacuum_history() {
+        let mvcc = MVCC::new(KVEngine::new());
+        set(&mvcc, b"a", Some(b"a1"));
+        set(&mvcc, b"a", Some(b"a2"));
+        set(&mvcc, b"a", Some(b"a3"));
+
+        assert_eq!(mvcc.begin_as_of(1).unwrap().get(b"a"), Some(b"a1".to_vec()));
+        mvcc.vacuum();
+        assert_eq!(mvcc.begin_as_of(1).err(), Some(Error::HistoryUnavailable(1)));
+        assert_eq!(mvcc.begin_as_of(3).unwrap().get(b"a"), Some(b"a3".to_vec()));
+    }
+
+    #[test]
+    fn test_background_vacuum() {
+        let mvcc = MVCC::new(KVEngine::new());
+        let handle = mvcc.start_vacuum(Duration::from_millis(10));
+        for i in 0..10 {
+            set(&mvcc, b"a", Some(format!("a{}", i).as_bytes()));
+        }
+
+        // 等待后台线程完成清理，超过期限说明后台清理没有运行
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while versions(&mvcc) > 1 {
+            assert!(Instant::now() < deadline, "background vacuum did not run");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        handle.stop();
+
+        // 停止之后不会再清理
+        set(&mvcc, b"a", Some(b"a10"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(versions(&mvcc), 2);
+    }
+}